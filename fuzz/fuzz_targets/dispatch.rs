@@ -0,0 +1,32 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use badscan::{
+    neighbours,
+    packet::{self, LinkType, Transport},
+};
+
+// the whole receive path before the protocols, Ethernet -> IPv4 -> TCP/UDP
+fuzz_target!(|frame: &[u8]| {
+    if let Ok(Some(segment)) = packet::parse(frame) {
+        let payload = match segment.transport {
+            Transport::Udp { payload } => payload,
+            Transport::Tcp(tcp) => tcp.payload,
+        };
+        // make sure the payload is actually inside the frame
+        assert!(payload.len() <= frame.len());
+    }
+
+    if let Ok(frame) = packet::parse_ethernet(frame) {
+        if let Some(ipv4) = frame.ipv4 {
+            let _ = packet::parse_ipv4(ipv4, false);
+        }
+    }
+
+    // the capture threads look at every frame before anything validated it
+    packet::flow_hash(frame, LinkType::Ethernet);
+    packet::flow_hash(frame, LinkType::Ip);
+    // and so does the ARP resolution before the scan
+    neighbours::parse_reply(frame);
+});
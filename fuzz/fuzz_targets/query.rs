@@ -0,0 +1,18 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use badscan::protocols::query::{self, QueryPacket};
+
+// first byte picks full or partial stat, the next 4 are the cookie, the rest is the packet
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 5 {
+        return;
+    }
+    let fullstat = data[0] & 1 != 0;
+    let cookie = u32::from_be_bytes([data[1], data[2], data[3], data[4]]);
+
+    if let Ok(QueryPacket::Challenge(token)) = query::parse_packet(&data[5..], cookie, fullstat) {
+        let _ = query::challenge_response(cookie, token, fullstat);
+    }
+});
@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use badscan::protocols::raknet;
+
+// the first 4 bytes are the cookie, the rest is the packet
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 4 {
+        return;
+    }
+    let cookie = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+
+    let _ = raknet::parse_pong(&data[4..], cookie);
+});
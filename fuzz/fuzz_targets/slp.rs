@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use badscan::protocols::slp;
+
+// the data a server sent over the connection so far
+fuzz_target!(|data: &[u8]| {
+    if let Ok(Some((response, length))) = slp::parse_frame(data) {
+        assert!(length <= data.len());
+        let _ = response.description();
+    }
+});
@@ -0,0 +1,67 @@
+use std::{fmt::Debug, io, sync::mpsc::Receiver, time::Duration};
+
+use crate::{packet::LinkType, warn_limited};
+
+pub mod datalink;
+pub mod memory;
+pub mod ring;
+#[cfg(target_os = "linux")]
+mod sys;
+pub mod tun;
+pub mod xdp;
+
+// how often a blocked receive wakes up, so the receive thread gets to check if it should stop
+pub const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+// where the scanners send their frames and get the replies from
+// backends only move frames around, everything above the link layer is done by the scanners
+pub trait Backend: Debug + Send + Sync {
+    fn open(&self) -> io::Result<(Box<dyn FrameSender>, Box<dyn FrameReceiver>)>;
+
+    // whether the frames have an Ethernet header, or are bare IP packets
+    fn link_type(&self) -> LinkType {
+        LinkType::Ethernet
+    }
+
+    // how many receive threads the replies are spread over, every extra one gets its own receiver
+    fn receivers(&self) -> usize {
+        1
+    }
+
+    // a receiver for an extra receive thread, or to replace one that broke
+    fn open_receiver(&self) -> io::Result<Box<dyn FrameReceiver>> {
+        self.open().map(|(_, rx)| rx)
+    }
+
+    // a sender for an extra send thread
+    // backends whose channels receive as well should override this, the receive half would only fill up unread
+    fn open_sender(&self) -> io::Result<Box<dyn FrameSender>> {
+        self.open().map(|(tx, _)| tx)
+    }
+}
+
+pub trait FrameSender: Send {
+    // may only queue the frame, until enough of them are queued or flush is called
+    fn send(&mut self, frame: &[u8]) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub trait FrameReceiver: Send {
+    // blocks for at most READ_TIMEOUT, and returns a TimedOut error when nothing arrived by then
+    fn recv(&mut self) -> io::Result<&[u8]>;
+}
+
+// the next frame to send from the queue of a send thread
+// whatever the sender batched up gets flushed before waiting, so it's never stuck behind an empty queue
+pub fn next_queued<T>(queue: &Receiver<T>, sender: &mut dyn FrameSender) -> Option<T> {
+    if let Ok(item) = queue.try_recv() {
+        return Some(item);
+    }
+    if let Err(err) = sender.flush() {
+        warn_limited!(%err, "could not send packets");
+    }
+    queue.recv().ok()
+}
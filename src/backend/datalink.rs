@@ -0,0 +1,101 @@
+use std::io;
+
+use pnet::datalink::{self, Channel, DataLinkReceiver, DataLinkSender, NetworkInterface};
+
+use super::{Backend, FrameReceiver, FrameSender, READ_TIMEOUT};
+
+// a datalink channel from pnet, a raw socket on Linux and npcap on Windows
+#[derive(Debug, Clone)]
+pub struct PnetBackend {
+    interface: NetworkInterface,
+}
+
+impl PnetBackend {
+    pub fn new(interface: NetworkInterface) -> Self {
+        Self { interface }
+    }
+}
+
+impl Backend for PnetBackend {
+    fn open(&self) -> io::Result<(Box<dyn FrameSender>, Box<dyn FrameReceiver>)> {
+        let config = datalink::Config {
+            read_timeout: Some(READ_TIMEOUT),
+            ..Default::default()
+        };
+        match datalink::channel(&self.interface, config)? {
+            Channel::Ethernet(tx, rx) => Ok((Box::new(PnetSender(tx)), Box::new(PnetReceiver(rx)))),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "idk what weird type of connection you have mate",
+            )),
+        }
+    }
+
+    // a channel's socket gets a copy of everything arriving on the interface, which would pile up unread behind an
+    // extra send thread, so those get a socket that never receives anything instead
+    #[cfg(target_os = "linux")]
+    fn open_sender(&self) -> io::Result<Box<dyn FrameSender>> {
+        Ok(Box::new(sys::PacketSender::open(self.interface.index)?))
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::{
+        io,
+        os::fd::{AsRawFd, OwnedFd},
+    };
+
+    use libc::c_void;
+
+    use crate::backend::{
+        sys::{bind, socket},
+        FrameSender,
+    };
+
+    // an AF_PACKET socket bound to protocol 0, which only sends
+    pub struct PacketSender(OwnedFd);
+
+    impl PacketSender {
+        pub fn open(interface: u32) -> io::Result<Self> {
+            let socket = socket()?;
+            bind(&socket, interface, 0)?;
+            Ok(Self(socket))
+        }
+    }
+
+    impl FrameSender for PacketSender {
+        fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+            let sent = unsafe {
+                libc::send(
+                    self.0.as_raw_fd(),
+                    frame.as_ptr() as *const c_void,
+                    frame.len(),
+                    0,
+                )
+            };
+            if sent < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+}
+
+struct PnetSender(Box<dyn DataLinkSender>);
+
+impl FrameSender for PnetSender {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.0
+            .send_to(frame, None)
+            .unwrap_or_else(|| Err(io::Error::other("frame doesn't fit in the send buffer")))
+    }
+}
+
+struct PnetReceiver(Box<dyn DataLinkReceiver>);
+
+impl FrameReceiver for PnetReceiver {
+    fn recv(&mut self) -> io::Result<&[u8]> {
+        self.0.next()
+    }
+}
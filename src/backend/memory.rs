@@ -0,0 +1,113 @@
+use std::{
+    io,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use super::{Backend, FrameReceiver, FrameSender, READ_TIMEOUT};
+use crate::packet::LinkType;
+
+// a network in memory, for testing the scanners without root or a network card
+// whatever the scanner sends ends up at the Peer, and whatever the Peer sends the scanner receives
+#[derive(Debug, Clone)]
+pub struct MemoryBackend {
+    to_peer: Sender<Vec<u8>>,
+    // shared, so reopening the channel keeps the frames that are still queued
+    from_peer: Arc<Mutex<Receiver<Vec<u8>>>>,
+    link_type: LinkType,
+}
+
+// the other end of the wire
+#[derive(Debug)]
+pub struct Peer {
+    to_scanner: Sender<Vec<u8>>,
+    from_scanner: Receiver<Vec<u8>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> (Self, Peer) {
+        Self::with_link_type(LinkType::Ethernet)
+    }
+
+    // LinkType::Ip behaves like a TUN device
+    pub fn with_link_type(link_type: LinkType) -> (Self, Peer) {
+        let (to_peer, from_scanner) = mpsc::channel();
+        let (to_scanner, from_peer) = mpsc::channel();
+        (
+            Self {
+                to_peer,
+                from_peer: Arc::new(Mutex::new(from_peer)),
+                link_type,
+            },
+            Peer {
+                to_scanner,
+                from_scanner,
+            },
+        )
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn open(&self) -> io::Result<(Box<dyn FrameSender>, Box<dyn FrameReceiver>)> {
+        Ok((
+            Box::new(MemorySender(self.to_peer.clone())),
+            Box::new(MemoryReceiver {
+                from_peer: self.from_peer.clone(),
+                frame: vec![],
+            }),
+        ))
+    }
+
+    fn link_type(&self) -> LinkType {
+        self.link_type
+    }
+}
+
+impl Peer {
+    // false when the scanner is gone
+    pub fn send(&self, frame: Vec<u8>) -> bool {
+        self.to_scanner.send(frame).is_ok()
+    }
+
+    // None when nothing was sent within the timeout, or the scanner is gone
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Vec<u8>> {
+        self.from_scanner.recv_timeout(timeout).ok()
+    }
+}
+
+struct MemorySender(Sender<Vec<u8>>);
+
+impl FrameSender for MemorySender {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.0
+            .send(frame.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "the peer is gone"))
+    }
+}
+
+struct MemoryReceiver {
+    from_peer: Arc<Mutex<Receiver<Vec<u8>>>>,
+    frame: Vec<u8>,
+}
+
+impl FrameReceiver for MemoryReceiver {
+    fn recv(&mut self) -> io::Result<&[u8]> {
+        let result = self.from_peer.lock().unwrap().recv_timeout(READ_TIMEOUT);
+        match result {
+            Ok(frame) => {
+                self.frame = frame;
+                Ok(&self.frame)
+            }
+            Err(RecvTimeoutError::Timeout) => Err(io::ErrorKind::TimedOut.into()),
+            // like a cable that got pulled out, nothing will ever arrive anymore
+            Err(RecvTimeoutError::Disconnected) => {
+                thread::sleep(READ_TIMEOUT);
+                Err(io::ErrorKind::TimedOut.into())
+            }
+        }
+    }
+}
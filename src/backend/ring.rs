@@ -0,0 +1,660 @@
+use std::{
+    io, process,
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use pnet::datalink::NetworkInterface;
+use serde_derive::Deserialize;
+
+use super::{Backend, FrameReceiver, FrameSender};
+
+// PACKET_MMAP rings on an AF_PACKET socket, for when pnet's syscall (and copy) per frame becomes the bottleneck
+// frames are written to a ring we share with the kernel and sent a whole batch per syscall,
+// and replies arrive in blocks of many frames that we read straight from the ring
+// see https://docs.kernel.org/networking/packet_mmap.html
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RingConfig {
+    // use rings instead of pnet's channel (linux only)
+    #[serde(default)]
+    pub enabled: bool,
+    // size of a block of the receive ring in KiB, the kernel hands them over once full (or after 10ms)
+    #[serde(default = "default_block_size")]
+    pub block_size: u32,
+    // blocks in the receive ring of every receive thread
+    #[serde(default = "default_blocks")]
+    pub blocks: u32,
+    // frames in the send ring
+    #[serde(default = "default_tx_frames")]
+    pub tx_frames: u32,
+    // frames that get sent with a single syscall
+    #[serde(default = "default_batch")]
+    pub batch: u32,
+    // receive threads, the replies are spread over them by flow so a TCP connection always ends up in the same one
+    #[serde(default = "default_receivers")]
+    pub receivers: usize,
+}
+
+impl Default for RingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            block_size: default_block_size(),
+            blocks: default_blocks(),
+            tx_frames: default_tx_frames(),
+            batch: default_batch(),
+            receivers: default_receivers(),
+        }
+    }
+}
+
+fn default_block_size() -> u32 {
+    256
+}
+
+fn default_blocks() -> u32 {
+    64
+}
+
+fn default_tx_frames() -> u32 {
+    4096
+}
+
+fn default_batch() -> u32 {
+    64
+}
+
+fn default_receivers() -> usize {
+    1
+}
+
+// fanout groups are per network namespace, so they have to differ between processes (and backends)
+static NEXT_FANOUT_GROUP: AtomicU16 = AtomicU16::new(0);
+
+#[derive(Debug)]
+pub struct RingBackend {
+    interface: NetworkInterface,
+    config: RingConfig,
+    fanout_group: u16,
+}
+
+impl RingBackend {
+    pub fn new(interface: NetworkInterface, config: &RingConfig) -> io::Result<Self> {
+        if !cfg!(target_os = "linux") {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "packet rings are only supported on Linux",
+            ));
+        }
+        if config.blocks == 0 || config.tx_frames == 0 || config.batch == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the rings need at least one block, frame and frame per batch",
+            ));
+        }
+        let fanout_group =
+            (process::id() as u16).wrapping_add(NEXT_FANOUT_GROUP.fetch_add(1, Ordering::Relaxed));
+        Ok(Self {
+            interface,
+            config: config.clone(),
+            fanout_group,
+        })
+    }
+
+    fn fanout(&self) -> Option<u16> {
+        (self.config.receivers > 1).then_some(self.fanout_group)
+    }
+}
+
+impl Backend for RingBackend {
+    fn open(&self) -> io::Result<(Box<dyn FrameSender>, Box<dyn FrameReceiver>)> {
+        Ok((self.open_sender()?, self.open_receiver()?))
+    }
+
+    fn receivers(&self) -> usize {
+        self.config.receivers.max(1)
+    }
+
+    fn open_receiver(&self) -> io::Result<Box<dyn FrameReceiver>> {
+        let rx = sys::RingReceiver::open(
+            self.interface.index,
+            self.config.block_size * 1024,
+            self.config.blocks,
+            self.fanout(),
+        )?;
+        Ok(Box::new(rx))
+    }
+
+    // every send thread gets a ring of its own
+    fn open_sender(&self) -> io::Result<Box<dyn FrameSender>> {
+        let tx = sys::RingSender::open(
+            self.interface.index,
+            self.config.tx_frames,
+            self.config.batch,
+        )?;
+        Ok(Box::new(tx))
+    }
+}
+
+// every frame slot is this big, which fits a 1500 byte MTU with the ring's own headers in front of it
+const FRAME_SIZE: u32 = 2048;
+// frames in a block of the send ring
+const TX_FRAMES_PER_BLOCK: u32 = 16;
+
+// where the blocks and frames of a ring are
+// blocks have to be whole pages, and frames can't cross them, so a block can end in a gap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+struct Layout {
+    block_size: u32,
+    blocks: u32,
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+impl Layout {
+    // a send ring with room for at least `frames` frames
+    fn tx(frames: u32, page_size: u32) -> Self {
+        let block_size = (TX_FRAMES_PER_BLOCK * FRAME_SIZE).next_multiple_of(page_size);
+        Self {
+            block_size,
+            blocks: frames.div_ceil(block_size / FRAME_SIZE),
+        }
+    }
+
+    // a receive ring of `blocks` blocks of at least `block_size` bytes, and at least one frame
+    fn rx(block_size: u32, blocks: u32, page_size: u32) -> Self {
+        Self {
+            block_size: block_size.max(FRAME_SIZE).next_multiple_of(page_size),
+            blocks,
+        }
+    }
+
+    fn frames_per_block(&self) -> u32 {
+        self.block_size / FRAME_SIZE
+    }
+
+    fn frames(&self) -> u32 {
+        self.frames_per_block() * self.blocks
+    }
+
+    // how much of the socket to map
+    fn len(&self) -> usize {
+        self.block_size as usize * self.blocks as usize
+    }
+
+    fn block(&self, block: usize) -> usize {
+        block * self.block_size as usize
+    }
+
+    fn frame(&self, slot: usize) -> usize {
+        let frames_per_block = self.frames_per_block() as usize;
+        self.block(slot / frames_per_block) + slot % frames_per_block * FRAME_SIZE as usize
+    }
+
+    // where a received frame starts, from the offsets the kernel put in its block
+    // None when they point past the end of the block, which can't happen with a sane kernel
+    fn received(&self, block: usize, offset: usize, mac: usize, len: usize) -> Option<usize> {
+        let start = offset + mac;
+        (start + len <= self.block_size as usize).then(|| self.block(block) + start)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::{
+        io, mem,
+        os::fd::{AsRawFd, OwnedFd},
+        ptr::{self, NonNull},
+        slice,
+        sync::atomic::{fence, Ordering},
+    };
+
+    use libc::{c_int, c_void};
+
+    use super::{Layout, FRAME_SIZE};
+    use crate::backend::{
+        sys::{bind, socket},
+        FrameReceiver, FrameSender, READ_TIMEOUT,
+    };
+
+    // the kernel hands over a block of the receive ring that isn't full yet after this many milliseconds
+    const BLOCK_TIMEOUT: u32 = 10;
+    // where the data of a frame in the send ring starts (TPACKET2_HDRLEN - sizeof(struct sockaddr_ll))
+    const TX_DATA_OFFSET: usize = libc::TPACKET2_HDRLEN - mem::size_of::<libc::sockaddr_ll>();
+
+    // a ring mapped into our memory, shared with the kernel
+    struct Ring {
+        ptr: NonNull<u8>,
+        len: usize,
+    }
+
+    // only the thread owning the sender or receiver touches it
+    unsafe impl Send for Ring {}
+
+    impl Ring {
+        fn map(socket: &OwnedFd, len: usize) -> io::Result<Self> {
+            let ptr = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED,
+                    socket.as_raw_fd(),
+                    0,
+                )
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self {
+                ptr: NonNull::new(ptr as *mut u8).unwrap(),
+                len,
+            })
+        }
+
+        fn at(&self, offset: usize) -> *mut u8 {
+            debug_assert!(offset < self.len);
+            unsafe { self.ptr.as_ptr().add(offset) }
+        }
+    }
+
+    impl Drop for Ring {
+        fn drop(&mut self) {
+            unsafe { libc::munmap(self.ptr.as_ptr() as *mut c_void, self.len) };
+        }
+    }
+
+    fn set_option<T>(socket: &OwnedFd, option: c_int, value: &T) -> io::Result<()> {
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_PACKET,
+                option,
+                value as *const T as *const c_void,
+                mem::size_of::<T>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // true when the socket became ready, false on a timeout
+    fn poll(socket: &OwnedFd, events: i16) -> io::Result<bool> {
+        let mut poll = libc::pollfd {
+            fd: socket.as_raw_fd(),
+            events,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut poll, 1, READ_TIMEOUT.as_millis() as c_int) } {
+            ..0 => Err(io::Error::last_os_error()),
+            0 => Ok(false),
+            _ => Ok(true),
+        }
+    }
+
+    fn page_size() -> u32 {
+        unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u32 }
+    }
+
+    pub struct RingSender {
+        ring: Ring,
+        socket: OwnedFd,
+        layout: Layout,
+        // the slot the next frame goes in
+        next: usize,
+        // frames in the ring that haven't been sent yet
+        pending: u32,
+        batch: u32,
+    }
+
+    impl RingSender {
+        pub fn open(interface: u32, frames: u32, batch: u32) -> io::Result<Self> {
+            let socket = socket()?;
+            set_option(
+                &socket,
+                libc::PACKET_VERSION,
+                &(libc::tpacket_versions::TPACKET_V2 as c_int),
+            )?;
+
+            let layout = Layout::tx(frames, page_size());
+            let request = libc::tpacket_req {
+                tp_block_size: layout.block_size,
+                tp_block_nr: layout.blocks,
+                tp_frame_size: FRAME_SIZE,
+                tp_frame_nr: layout.frames(),
+            };
+            set_option(&socket, libc::PACKET_TX_RING, &request)?;
+            let ring = Ring::map(&socket, layout.len())?;
+            bind(&socket, interface, 0)?;
+
+            Ok(Self {
+                ring,
+                socket,
+                layout,
+                next: 0,
+                pending: 0,
+                batch,
+            })
+        }
+
+        fn header(&self, slot: usize) -> *mut libc::tpacket2_hdr {
+            self.ring.at(self.layout.frame(slot)) as *mut libc::tpacket2_hdr
+        }
+    }
+
+    impl FrameSender for RingSender {
+        fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+            if frame.len() > FRAME_SIZE as usize - TX_DATA_OFFSET {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "frame doesn't fit in the send ring",
+                ));
+            }
+
+            let header = self.header(self.next);
+            let status = unsafe { &raw mut (*header).tp_status };
+            // a frame the kernel couldn't send earlier, its slot is ours again
+            let mut rejected = false;
+            loop {
+                match unsafe { ptr::read_volatile(status) } {
+                    libc::TP_STATUS_AVAILABLE => break,
+                    libc::TP_STATUS_WRONG_FORMAT => {
+                        rejected = true;
+                        break;
+                    }
+                    // the ring is full, wait for the kernel to catch up
+                    _ => {
+                        self.flush()?;
+                        poll(&self.socket, libc::POLLOUT)?;
+                    }
+                }
+            }
+            fence(Ordering::Acquire);
+
+            unsafe {
+                let data = (header as *mut u8).add(TX_DATA_OFFSET);
+                ptr::copy_nonoverlapping(frame.as_ptr(), data, frame.len());
+                (*header).tp_len = frame.len() as u32;
+            }
+            // the kernel may only see the frame once it's all there
+            fence(Ordering::Release);
+            unsafe { ptr::write_volatile(status, libc::TP_STATUS_SEND_REQUEST) };
+
+            self.next = (self.next + 1) % self.layout.frames() as usize;
+            self.pending += 1;
+            if self.pending >= self.batch {
+                self.flush()?;
+            }
+            if rejected {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the kernel rejected a frame sent earlier",
+                ));
+            }
+            Ok(())
+        }
+
+        // sends everything in the ring, and blocks until the kernel is done with it
+        fn flush(&mut self) -> io::Result<()> {
+            if self.pending == 0 {
+                return Ok(());
+            }
+            self.pending = 0;
+            let result =
+                unsafe { libc::sendto(self.socket.as_raw_fd(), ptr::null(), 0, 0, ptr::null(), 0) };
+            if result < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for RingSender {
+        fn drop(&mut self) {
+            let _ = self.flush();
+        }
+    }
+
+    pub struct RingReceiver {
+        ring: Ring,
+        socket: OwnedFd,
+        layout: Layout,
+        // the block we're reading, if the kernel handed it to us
+        block: usize,
+        owned: bool,
+        // frames left in the block, and where the next one starts
+        remaining: u32,
+        offset: usize,
+    }
+
+    impl RingReceiver {
+        pub fn open(
+            interface: u32,
+            block_size: u32,
+            blocks: u32,
+            fanout: Option<u16>,
+        ) -> io::Result<Self> {
+            let socket = socket()?;
+            set_option(
+                &socket,
+                libc::PACKET_VERSION,
+                &(libc::tpacket_versions::TPACKET_V3 as c_int),
+            )?;
+
+            let layout = Layout::rx(block_size, blocks, page_size());
+            let request = libc::tpacket_req3 {
+                tp_block_size: layout.block_size,
+                tp_block_nr: layout.blocks,
+                // only checked for sanity, frames in a block are packed as tight as they fit
+                tp_frame_size: FRAME_SIZE,
+                tp_frame_nr: layout.frames(),
+                tp_retire_blk_tov: BLOCK_TIMEOUT,
+                tp_sizeof_priv: 0,
+                tp_feature_req_word: 0,
+            };
+            set_option(&socket, libc::PACKET_RX_RING, &request)?;
+            let ring = Ring::map(&socket, layout.len())?;
+            bind(&socket, interface, libc::ETH_P_ALL as u16)?;
+
+            if let Some(group) = fanout {
+                // hashing on the flow keeps every connection in the same receive thread
+                let mode = libc::PACKET_FANOUT_HASH | libc::PACKET_FANOUT_FLAG_DEFRAG;
+                set_option(
+                    &socket,
+                    libc::PACKET_FANOUT,
+                    &(group as c_int | (mode << 16) as c_int),
+                )?;
+            }
+
+            Ok(Self {
+                ring,
+                socket,
+                layout,
+                block: 0,
+                owned: false,
+                remaining: 0,
+                offset: 0,
+            })
+        }
+
+        fn descriptor(&self) -> *mut libc::tpacket_block_desc {
+            self.ring.at(self.layout.block(self.block)) as *mut libc::tpacket_block_desc
+        }
+
+        // replies the kernel dropped since the last time we asked, because the ring was full
+        fn drops(&self) -> io::Result<u32> {
+            let mut stats: libc::tpacket_stats_v3 = unsafe { mem::zeroed() };
+            let mut len = mem::size_of::<libc::tpacket_stats_v3>() as libc::socklen_t;
+            let result = unsafe {
+                libc::getsockopt(
+                    self.socket.as_raw_fd(),
+                    libc::SOL_PACKET,
+                    libc::PACKET_STATISTICS,
+                    &mut stats as *mut libc::tpacket_stats_v3 as *mut c_void,
+                    &mut len,
+                )
+            };
+            if result < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(stats.tp_drops)
+        }
+    }
+
+    impl FrameReceiver for RingReceiver {
+        fn recv(&mut self) -> io::Result<&[u8]> {
+            // give the block back once we're through it
+            if self.owned && self.remaining == 0 {
+                let descriptor = self.descriptor();
+                fence(Ordering::Release);
+                unsafe {
+                    ptr::write_volatile(
+                        &raw mut (*descriptor).hdr.bh1.block_status,
+                        libc::TP_STATUS_KERNEL,
+                    )
+                };
+                self.owned = false;
+                self.block = (self.block + 1) % self.layout.blocks as usize;
+            }
+
+            if !self.owned {
+                // the kernel fills the blocks in order, so the next one is always this one
+                let descriptor = self.descriptor();
+                let status = unsafe { &raw const (*descriptor).hdr.bh1.block_status };
+                if unsafe { ptr::read_volatile(status) } & libc::TP_STATUS_USER == 0
+                    && (!poll(&self.socket, libc::POLLIN | libc::POLLERR)?
+                        || unsafe { ptr::read_volatile(status) } & libc::TP_STATUS_USER == 0)
+                {
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+                fence(Ordering::Acquire);
+
+                let (status, frames, first) = unsafe {
+                    let header = &(*descriptor).hdr.bh1;
+                    (
+                        header.block_status,
+                        header.num_pkts,
+                        header.offset_to_first_pkt,
+                    )
+                };
+                self.owned = true;
+                self.remaining = frames;
+                self.offset = first as usize;
+                if status & libc::TP_STATUS_LOSING != 0 && self.drops()? > 0 {
+                    return Err(io::Error::from_raw_os_error(libc::ENOBUFS));
+                }
+                if frames == 0 {
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+            }
+
+            let (next, mac, len) = unsafe {
+                let header = self.ring.at(self.layout.block(self.block) + self.offset)
+                    as *const libc::tpacket3_hdr;
+                (
+                    (*header).tp_next_offset as usize,
+                    (*header).tp_mac as usize,
+                    (*header).tp_snaplen as usize,
+                )
+            };
+            let Some(start) = self.layout.received(self.block, self.offset, mac, len) else {
+                // don't read past the block
+                self.remaining = 0;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "frame extends past its block",
+                ));
+            };
+            self.remaining -= 1;
+            self.offset += next;
+            Ok(unsafe { slice::from_raw_parts(self.ring.at(start), len) })
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::io;
+
+    use crate::backend::{FrameReceiver, FrameSender};
+
+    pub struct RingSender;
+
+    impl RingSender {
+        pub fn open(_interface: u32, _frames: u32, _batch: u32) -> io::Result<Self> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+    }
+
+    impl FrameSender for RingSender {
+        fn send(&mut self, _frame: &[u8]) -> io::Result<()> {
+            unreachable!()
+        }
+    }
+
+    pub struct RingReceiver;
+
+    impl RingReceiver {
+        pub fn open(
+            _interface: u32,
+            _block_size: u32,
+            _blocks: u32,
+            _fanout: Option<u16>,
+        ) -> io::Result<Self> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+    }
+
+    impl FrameReceiver for RingReceiver {
+        fn recv(&mut self) -> io::Result<&[u8]> {
+            unreachable!()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ring_layouts() {
+        // 16 frames fill a block of 8 pages
+        let tx = Layout::tx(4096, 4096);
+        assert_eq!(tx.block_size, 32768);
+        assert_eq!((tx.blocks, tx.frames()), (256, 4096));
+        assert_eq!(tx.len(), 8 << 20);
+        assert_eq!(tx.frame(15), 15 * 2048);
+        assert_eq!(tx.frame(16), 32768);
+        // never less than asked for, and never more blocks than needed
+        assert_eq!(Layout::tx(10, 4096).frames(), 16);
+        assert_eq!(Layout::tx(17, 4096).blocks, 2);
+        // with 64 KiB pages a block gets 32 frames
+        let tx = Layout::tx(4096, 65536);
+        assert_eq!((tx.blocks, tx.frames_per_block()), (128, 32));
+        assert_eq!(tx.frame(33), 65536 + 2048);
+        // frames don't cross blocks, when a block doesn't divide into them the rest of it stays empty
+        let tx = Layout::tx(32, 3000);
+        assert_eq!((tx.block_size, tx.frames_per_block()), (33000, 16));
+        assert_eq!(tx.frame(16), 33000);
+        assert!(tx.frame(tx.frames() as usize - 1) + FRAME_SIZE as usize <= tx.len());
+
+        let rx = Layout::rx(256 * 1024, 64, 4096);
+        assert_eq!((rx.block_size, rx.blocks), (256 * 1024, 64));
+        assert_eq!(rx.len(), 16 << 20);
+        assert_eq!(rx.block(3), 3 * 256 * 1024);
+        // a block holds at least one frame, in whole pages
+        assert_eq!(Layout::rx(1000, 2, 4096).block_size, 4096);
+        assert_eq!(Layout::rx(1000, 2, 1024).block_size, 2048);
+    }
+
+    #[test]
+    fn received_frames_stay_in_their_block() {
+        let rx = Layout::rx(4096, 4, 4096);
+        assert_eq!(rx.received(2, 48, 66, 1000), Some(2 * 4096 + 48 + 66));
+        assert_eq!(rx.received(0, 48, 66, 4096 - 48 - 66), Some(48 + 66));
+        assert_eq!(rx.received(0, 48, 66, 4096 - 48 - 65), None);
+        assert_eq!(rx.received(3, 4000, 66, 100), None);
+    }
+}
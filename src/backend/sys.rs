@@ -0,0 +1,35 @@
+// raw AF_PACKET sockets, shared by the backends that open their own
+use std::{
+    io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use libc::c_int;
+
+pub fn socket() -> io::Result<OwnedFd> {
+    // protocol 0 receives nothing until we bind to one, so nothing from other interfaces ends up in it
+    let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+// binding to protocol 0 keeps a socket that only sends
+pub fn bind(socket: &OwnedFd, interface: u32, protocol: u16) -> io::Result<()> {
+    let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+    addr.sll_family = libc::AF_PACKET as u16;
+    addr.sll_protocol = protocol.to_be();
+    addr.sll_ifindex = interface as c_int;
+    let result = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
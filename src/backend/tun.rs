@@ -0,0 +1,182 @@
+use std::{fs::File, io, net::Ipv4Addr};
+
+use pnet::util::MacAddr;
+use serde_derive::Deserialize;
+
+use super::{Backend, FrameReceiver, FrameSender};
+use crate::packet::LinkType;
+
+// a TUN or TAP device, for scanning servers in local containers or VMs without touching the real network
+// we act as a host behind the device: the kernel routes our probes to the servers like it would for any other
+// network, and routes the replies for our address back into the device
+// since the kernel doesn't own that address it never RSTs our connections either
+
+// the MAC we use on a TAP device, the device itself has its own
+pub const TAP_MAC: MacAddr = MacAddr(0x02, 0x62, 0x61, 0x64, 0x00, 0x01);
+
+#[derive(Deserialize, Debug, Default)]
+pub struct TunConfig {
+    // scan through this device instead of the interface, uncomment to enable
+    pub device: Option<String>,
+    #[serde(default)]
+    pub mode: TunMode,
+    // the address we scan from, it has to be routed to the device but can't be one of the device's own addresses
+    pub address: Option<Ipv4Addr>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TunMode {
+    // bare IP packets
+    #[default]
+    #[serde(rename = "tun")]
+    Tun,
+    // Ethernet frames
+    #[serde(rename = "tap")]
+    Tap,
+}
+
+#[derive(Debug)]
+pub struct TunBackend {
+    device: File,
+    mode: TunMode,
+}
+
+impl TunBackend {
+    // the device is created when it doesn't exist yet, which needs CAP_NET_ADMIN
+    // a persistent device (`ip tuntap add ... user $USER`) can be attached to without any privileges
+    pub fn attach(name: &str, mode: TunMode) -> io::Result<Self> {
+        Ok(Self {
+            device: sys::attach(name, mode)?,
+            mode,
+        })
+    }
+}
+
+impl Backend for TunBackend {
+    // every channel shares the same queue of the device
+    fn open(&self) -> io::Result<(Box<dyn FrameSender>, Box<dyn FrameReceiver>)> {
+        Ok((
+            Box::new(TunSender(self.device.try_clone()?)),
+            Box::new(TunReceiver {
+                device: self.device.try_clone()?,
+                buf: vec![0; 65536],
+            }),
+        ))
+    }
+
+    fn link_type(&self) -> LinkType {
+        match self.mode {
+            TunMode::Tun => LinkType::Ip,
+            TunMode::Tap => LinkType::Ethernet,
+        }
+    }
+
+    fn open_sender(&self) -> io::Result<Box<dyn FrameSender>> {
+        Ok(Box::new(TunSender(self.device.try_clone()?)))
+    }
+}
+
+struct TunSender(File);
+
+impl FrameSender for TunSender {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        sys::send(&self.0, frame)
+    }
+}
+
+struct TunReceiver {
+    device: File,
+    buf: Vec<u8>,
+}
+
+impl FrameReceiver for TunReceiver {
+    fn recv(&mut self) -> io::Result<&[u8]> {
+        let len = sys::recv(&self.device, &mut self.buf)?;
+        Ok(&self.buf[..len])
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::{
+        fs::{File, OpenOptions},
+        io::{self, Read, Write},
+        os::fd::AsRawFd,
+    };
+
+    use super::TunMode;
+    use crate::backend::READ_TIMEOUT;
+
+    pub fn attach(name: &str, mode: TunMode) -> io::Result<File> {
+        if name.len() >= libc::IFNAMSIZ {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "device name is too long",
+            ));
+        }
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/net/tun")?;
+
+        // without IFF_NO_PI every packet would start with 4 bytes of flags and protocol
+        let kind = match mode {
+            TunMode::Tun => libc::IFF_TUN,
+            TunMode::Tap => libc::IFF_TAP,
+        };
+        let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+        for (to, from) in request.ifr_name.iter_mut().zip(name.bytes()) {
+            *to = from as libc::c_char;
+        }
+        request.ifr_ifru.ifru_flags = (kind | libc::IFF_NO_PI) as libc::c_short;
+        if unsafe { libc::ioctl(device.as_raw_fd(), libc::TUNSETIFF, &request) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(device)
+    }
+
+    // a write is exactly one packet
+    pub fn send(mut device: &File, frame: &[u8]) -> io::Result<()> {
+        let written = device.write(frame)?;
+        if written != frame.len() {
+            return Err(io::Error::other("frame was cut off"));
+        }
+        Ok(())
+    }
+
+    // and so is a read, as long as the buffer fits the MTU
+    pub fn recv(mut device: &File, buf: &mut [u8]) -> io::Result<usize> {
+        let mut poll = libc::pollfd {
+            fd: device.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut poll, 1, READ_TIMEOUT.as_millis() as libc::c_int) } {
+            ..0 => Err(io::Error::last_os_error()),
+            0 => Err(io::ErrorKind::TimedOut.into()),
+            _ => device.read(buf),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::{fs::File, io};
+
+    use super::TunMode;
+
+    pub fn attach(_name: &str, _mode: TunMode) -> io::Result<File> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "TUN/TAP devices are only supported on Linux",
+        ))
+    }
+
+    pub fn send(_device: &File, _frame: &[u8]) -> io::Result<()> {
+        unreachable!()
+    }
+
+    pub fn recv(_device: &File, _buf: &mut [u8]) -> io::Result<usize> {
+        unreachable!()
+    }
+}
@@ -0,0 +1,1158 @@
+use std::{io, sync::Arc};
+
+use pnet::datalink::NetworkInterface;
+use serde_derive::Deserialize;
+
+use super::{Backend, FrameReceiver, FrameSender};
+use crate::config::PortRange;
+
+// an AF_XDP socket, for when even packet rings can't keep up
+// frames go straight between the driver and memory we share with the kernel (the UMEM), skipping the network stack,
+// and when the driver supports it without being copied at all
+// a small XDP program on the interface hands us the replies to our source ports, everything else still goes to the kernel
+// see https://docs.kernel.org/networking/af_xdp.html
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct XdpConfig {
+    // use an AF_XDP socket instead of pnet's channel or packet rings (linux only, needs the `xdp` feature)
+    #[serde(default)]
+    pub enabled: bool,
+    // the queue of the interface the socket is bound to, replies that arrive on other queues go to the kernel instead
+    // so on NICs with several queues either steer them all to this one, or use just one (`ethtool -L <if> combined 1`)
+    #[serde(default)]
+    pub queue: u32,
+    // frames in the UMEM, half of them for receiving and half for sending, has to be a power of two
+    #[serde(default = "default_frames")]
+    pub frames: u32,
+    // frames that get sent with a single syscall
+    #[serde(default = "default_batch")]
+    pub batch: u32,
+    // let the driver use the UMEM directly, falls back to copying when it can't (like on veth)
+    #[serde(default = "default_zero_copy")]
+    pub zero_copy: bool,
+}
+
+impl Default for XdpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            queue: 0,
+            frames: default_frames(),
+            batch: default_batch(),
+            zero_copy: default_zero_copy(),
+        }
+    }
+}
+
+fn default_frames() -> u32 {
+    4096
+}
+
+fn default_batch() -> u32 {
+    64
+}
+
+fn default_zero_copy() -> bool {
+    true
+}
+
+#[derive(Debug)]
+pub struct XdpBackend {
+    socket: Arc<sys::Socket>,
+}
+
+impl XdpBackend {
+    // sets up the socket and attaches the program right away, which needs CAP_NET_ADMIN and CAP_BPF (or root)
+    // only replies to `source_ports` are taken from the kernel
+    pub fn new(
+        interface: &NetworkInterface,
+        config: &XdpConfig,
+        source_ports: PortRange,
+    ) -> io::Result<Self> {
+        if !config.frames.is_power_of_two() || config.frames < 2 || config.batch == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the UMEM needs a power of two of at least 2 frames, and batches at least one frame",
+            ));
+        }
+        let socket = sys::Socket::open(
+            interface.index,
+            config.queue,
+            config.frames,
+            config.batch,
+            config.zero_copy,
+            source_ports,
+        )?;
+        Ok(Self {
+            socket: Arc::new(socket),
+        })
+    }
+
+    // whether the driver took the zero-copy mode
+    pub fn zero_copy(&self) -> bool {
+        self.socket.zero_copy()
+    }
+}
+
+impl Backend for XdpBackend {
+    // there's only one socket per queue, every channel shares it
+    fn open(&self) -> io::Result<(Box<dyn FrameSender>, Box<dyn FrameReceiver>)> {
+        Ok((self.open_sender()?, self.open_receiver()?))
+    }
+
+    fn open_receiver(&self) -> io::Result<Box<dyn FrameReceiver>> {
+        Ok(Box::new(sys::XdpReceiver::new(self.socket.clone())))
+    }
+
+    // the send threads take turns on the socket's TX ring
+    fn open_sender(&self) -> io::Result<Box<dyn FrameSender>> {
+        Ok(Box::new(sys::XdpSender::new(self.socket.clone())))
+    }
+}
+
+#[cfg(all(feature = "xdp", target_os = "linux"))]
+mod sys {
+    use std::{
+        fmt, io, mem,
+        os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        ptr::{self, NonNull},
+        slice,
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use libc::{c_int, c_void};
+
+    use crate::{
+        backend::{FrameReceiver, FrameSender, READ_TIMEOUT},
+        config::PortRange,
+    };
+
+    // every frame in the UMEM is this big, the kernel puts 256 bytes of headroom in front of received frames
+    // which still leaves enough room for a 1500 byte MTU
+    const FRAME_SIZE: u32 = 2048;
+
+    // memory mapped into our address space
+    struct Mmap {
+        ptr: NonNull<u8>,
+        len: usize,
+    }
+
+    impl Mmap {
+        fn map(len: usize, fd: RawFd, offset: libc::off_t) -> io::Result<Self> {
+            let flags = if fd < 0 {
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS
+            } else {
+                libc::MAP_SHARED
+            };
+            let ptr = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    flags | libc::MAP_POPULATE,
+                    fd,
+                    offset,
+                )
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self {
+                ptr: NonNull::new(ptr as *mut u8).unwrap(),
+                len,
+            })
+        }
+
+        fn at(&self, offset: usize) -> *mut u8 {
+            debug_assert!(offset < self.len);
+            unsafe { self.ptr.as_ptr().add(offset) }
+        }
+    }
+
+    impl Drop for Mmap {
+        fn drop(&mut self) {
+            unsafe { libc::munmap(self.ptr.as_ptr() as *mut c_void, self.len) };
+        }
+    }
+
+    // one of the four rings of the socket, a single producer and a single consumer walk around it
+    // we keep our own side of it to ourselves until it's published, so a whole batch becomes visible at once
+    struct Ring<T> {
+        _map: Mmap,
+        producer: *const AtomicU32,
+        consumer: *const AtomicU32,
+        flags: *const AtomicU32,
+        descs: *mut T,
+        mask: u32,
+        // our index, the producer's when we fill the ring and the consumer's when we empty it
+        cached: u32,
+    }
+
+    impl<T: Copy> Ring<T> {
+        fn map(
+            socket: &OwnedFd,
+            offsets: &libc::xdp_ring_offset,
+            size: u32,
+            page: libc::off_t,
+        ) -> io::Result<Self> {
+            let len = offsets.desc as usize + size as usize * mem::size_of::<T>();
+            let map = Mmap::map(len, socket.as_raw_fd(), page)?;
+            let producer = map.at(offsets.producer as usize) as *const AtomicU32;
+            let consumer = map.at(offsets.consumer as usize) as *const AtomicU32;
+            Ok(Self {
+                producer,
+                consumer,
+                flags: map.at(offsets.flags as usize) as *const AtomicU32,
+                descs: map.at(offsets.desc as usize) as *mut T,
+                mask: size - 1,
+                cached: 0,
+                _map: map,
+            })
+        }
+
+        fn producer(&self) -> &AtomicU32 {
+            unsafe { &*self.producer }
+        }
+
+        fn consumer(&self) -> &AtomicU32 {
+            unsafe { &*self.consumer }
+        }
+
+        fn needs_wakeup(&self) -> bool {
+            unsafe { &*self.flags }.load(Ordering::Relaxed) & libc::XDP_RING_NEED_WAKEUP != 0
+        }
+
+        // for rings we fill, the caller makes sure there's room
+        fn push(&mut self, desc: T) {
+            unsafe {
+                self.descs
+                    .add((self.cached & self.mask) as usize)
+                    .write(desc)
+            };
+            self.cached = self.cached.wrapping_add(1);
+        }
+
+        // the kernel may only see the descriptors once they're all there
+        fn submit(&self) {
+            self.producer().store(self.cached, Ordering::Release);
+        }
+
+        // for rings we empty
+        fn pop(&mut self) -> Option<T> {
+            if self.cached == self.producer().load(Ordering::Acquire) {
+                return None;
+            }
+            let desc = unsafe { self.descs.add((self.cached & self.mask) as usize).read() };
+            self.cached = self.cached.wrapping_add(1);
+            Some(desc)
+        }
+
+        fn is_empty(&self) -> bool {
+            self.cached == self.producer().load(Ordering::Acquire)
+        }
+
+        // gives the slots we popped back to the kernel
+        fn release(&self) {
+            self.consumer().store(self.cached, Ordering::Release);
+        }
+    }
+
+    struct Tx {
+        ring: Ring<libc::xdp_desc>,
+        completion: Ring<u64>,
+        // frames of the UMEM that aren't in either ring
+        free: Vec<u64>,
+        pending: u32,
+    }
+
+    struct Rx {
+        ring: Ring<libc::xdp_desc>,
+        fill: Ring<u64>,
+        // what the kernel dropped by the last time we checked
+        drops: u64,
+    }
+
+    pub struct Socket {
+        // closing the link detaches the program, so it goes first
+        _program: Program,
+        socket: OwnedFd,
+        umem: Mmap,
+        zero_copy: bool,
+        batch: u32,
+        tx: Mutex<Tx>,
+        rx: Mutex<Rx>,
+    }
+
+    // the rings are behind the locks, and senders and receivers only touch their own frames of the UMEM
+    unsafe impl Send for Socket {}
+    unsafe impl Sync for Socket {}
+
+    impl fmt::Debug for Socket {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("Socket")
+                .field("socket", &self.socket)
+                .field("zero_copy", &self.zero_copy)
+                .finish_non_exhaustive()
+        }
+    }
+
+    fn set_option<T>(socket: &OwnedFd, option: c_int, value: &T) -> io::Result<()> {
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_XDP,
+                option,
+                value as *const T as *const c_void,
+                mem::size_of::<T>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn get_option<T>(socket: &OwnedFd, option: c_int) -> io::Result<T> {
+        let mut value: T = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<T>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_XDP,
+                option,
+                &mut value as *mut T as *mut c_void,
+                &mut len,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(value)
+    }
+
+    fn bind(socket: &OwnedFd, interface: u32, queue: u32, flags: u16) -> io::Result<()> {
+        let addr = libc::sockaddr_xdp {
+            sxdp_family: libc::AF_XDP as u16,
+            sxdp_flags: flags | libc::XDP_USE_NEED_WAKEUP,
+            sxdp_ifindex: interface,
+            sxdp_queue_id: queue,
+            sxdp_shared_umem_fd: 0,
+        };
+        let result = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                &addr as *const libc::sockaddr_xdp as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_xdp>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // true when the socket became ready, false on a timeout
+    fn poll(socket: &OwnedFd, events: i16) -> io::Result<bool> {
+        let mut poll = libc::pollfd {
+            fd: socket.as_raw_fd(),
+            events,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut poll, 1, READ_TIMEOUT.as_millis() as c_int) } {
+            ..0 => Err(io::Error::last_os_error()),
+            0 => Ok(false),
+            _ => Ok(true),
+        }
+    }
+
+    impl Socket {
+        pub fn open(
+            interface: u32,
+            queue: u32,
+            frames: u32,
+            batch: u32,
+            zero_copy: bool,
+            source_ports: PortRange,
+        ) -> io::Result<Self> {
+            let fd = unsafe { libc::socket(libc::AF_XDP, libc::SOCK_RAW, 0) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+            let umem = Mmap::map((frames * FRAME_SIZE) as usize, -1, 0)?;
+            set_option(
+                &socket,
+                libc::XDP_UMEM_REG,
+                &libc::xdp_umem_reg {
+                    addr: umem.ptr.as_ptr() as u64,
+                    len: umem.len as u64,
+                    chunk_size: FRAME_SIZE,
+                    headroom: 0,
+                    flags: 0,
+                    tx_metadata_len: 0,
+                },
+            )?;
+
+            // the first half of the frames is for receiving, the second for sending
+            // every ring fits all frames of its half, so none of them can ever overflow
+            let half = frames / 2;
+            set_option(&socket, libc::XDP_UMEM_FILL_RING, &half)?;
+            set_option(&socket, libc::XDP_UMEM_COMPLETION_RING, &half)?;
+            set_option(&socket, libc::XDP_RX_RING, &half)?;
+            set_option(&socket, libc::XDP_TX_RING, &half)?;
+            let offsets: libc::xdp_mmap_offsets = get_option(&socket, libc::XDP_MMAP_OFFSETS)?;
+
+            let mut fill = Ring::map(
+                &socket,
+                &offsets.fr,
+                half,
+                libc::XDP_UMEM_PGOFF_FILL_RING as libc::off_t,
+            )?;
+            let completion = Ring::map(
+                &socket,
+                &offsets.cr,
+                half,
+                libc::XDP_UMEM_PGOFF_COMPLETION_RING as libc::off_t,
+            )?;
+            let rx = Ring::map(&socket, &offsets.rx, half, libc::XDP_PGOFF_RX_RING)?;
+            let tx = Ring::map(&socket, &offsets.tx, half, libc::XDP_PGOFF_TX_RING)?;
+
+            // the kernel needs somewhere to put the replies before anything can arrive
+            for frame in 0..half {
+                fill.push((frame * FRAME_SIZE) as u64);
+            }
+            fill.submit();
+
+            // drivers without zero-copy support refuse the bind, copying works everywhere
+            let zero_copy = zero_copy
+                && match bind(&socket, interface, queue, libc::XDP_ZEROCOPY) {
+                    Ok(()) => true,
+                    Err(err) if err.raw_os_error() == Some(libc::EOPNOTSUPP) => false,
+                    Err(err) if err.raw_os_error() == Some(libc::EINVAL) => false,
+                    Err(err) => return Err(err),
+                };
+            if !zero_copy {
+                bind(&socket, interface, queue, libc::XDP_COPY)?;
+            }
+
+            let program = Program::attach(interface, queue, &socket, source_ports)?;
+
+            Ok(Self {
+                _program: program,
+                socket,
+                umem,
+                zero_copy,
+                batch,
+                tx: Mutex::new(Tx {
+                    ring: tx,
+                    completion,
+                    free: (half..frames)
+                        .map(|frame| (frame * FRAME_SIZE) as u64)
+                        .collect(),
+                    pending: 0,
+                }),
+                rx: Mutex::new(Rx {
+                    ring: rx,
+                    fill,
+                    drops: 0,
+                }),
+            })
+        }
+
+        pub fn zero_copy(&self) -> bool {
+            self.zero_copy
+        }
+
+        // the kernel only looks at the send ring when we ask it to, or in zero-copy mode when the driver is awake anyway
+        fn wake(&self, tx: &Tx) -> io::Result<()> {
+            if !tx.ring.needs_wakeup() {
+                return Ok(());
+            }
+            let result = unsafe {
+                libc::sendto(
+                    self.socket.as_raw_fd(),
+                    ptr::null(),
+                    0,
+                    libc::MSG_DONTWAIT,
+                    ptr::null(),
+                    0,
+                )
+            };
+            if result < 0 {
+                let err = io::Error::last_os_error();
+                // the driver is still busy with what we gave it earlier, it'll get to the rest
+                if !matches!(
+                    err.raw_os_error(),
+                    Some(libc::EAGAIN | libc::EBUSY | libc::ENOBUFS)
+                ) {
+                    return Err(err);
+                }
+            }
+            Ok(())
+        }
+
+        fn flush(&self, tx: &mut Tx) -> io::Result<()> {
+            if tx.pending == 0 {
+                return Ok(());
+            }
+            tx.pending = 0;
+            tx.ring.submit();
+            self.wake(tx)
+        }
+
+        // replies the kernel dropped since the last time we asked, because our rings were full
+        fn drops(&self, rx: &mut Rx) -> io::Result<u64> {
+            let stats: libc::xdp_statistics = get_option(&self.socket, libc::XDP_STATISTICS)?;
+            let total = stats.rx_dropped + stats.rx_ring_full;
+            let new = total - rx.drops;
+            rx.drops = total;
+            Ok(new)
+        }
+    }
+
+    pub struct XdpSender {
+        socket: Arc<Socket>,
+    }
+
+    impl XdpSender {
+        pub fn new(socket: Arc<Socket>) -> Self {
+            Self { socket }
+        }
+    }
+
+    impl FrameSender for XdpSender {
+        fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+            if frame.len() > FRAME_SIZE as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "frame doesn't fit in the UMEM",
+                ));
+            }
+
+            let socket = &*self.socket;
+            let mut tx = socket.tx.lock().unwrap();
+            let addr = loop {
+                // frames the kernel is done sending are ours again
+                while let Some(addr) = tx.completion.pop() {
+                    tx.free.push(addr);
+                }
+                tx.completion.release();
+                if let Some(addr) = tx.free.pop() {
+                    break addr;
+                }
+                // every frame is in flight, wait for the kernel to catch up
+                socket.flush(&mut tx)?;
+                socket.wake(&tx)?;
+                poll(&socket.socket, libc::POLLOUT)?;
+            };
+
+            unsafe {
+                ptr::copy_nonoverlapping(frame.as_ptr(), socket.umem.at(addr as usize), frame.len())
+            };
+            tx.ring.push(libc::xdp_desc {
+                addr,
+                len: frame.len() as u32,
+                options: 0,
+            });
+            tx.pending += 1;
+            if tx.pending >= socket.batch {
+                socket.flush(&mut tx)?;
+            }
+            Ok(())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            let mut tx = self.socket.tx.lock().unwrap();
+            self.socket.flush(&mut tx)
+        }
+    }
+
+    impl Drop for XdpSender {
+        fn drop(&mut self) {
+            let _ = self.flush();
+        }
+    }
+
+    pub struct XdpReceiver {
+        socket: Arc<Socket>,
+        // the frame we returned last, it goes back to the kernel on the next receive
+        held: Option<u64>,
+    }
+
+    impl XdpReceiver {
+        pub fn new(socket: Arc<Socket>) -> Self {
+            Self { socket, held: None }
+        }
+
+        fn recycle(&mut self, rx: &mut Rx) {
+            if let Some(addr) = self.held.take() {
+                // received frames start somewhere into their chunk
+                rx.fill.push(addr & !(FRAME_SIZE as u64 - 1));
+                rx.fill.submit();
+            }
+        }
+    }
+
+    impl FrameReceiver for XdpReceiver {
+        fn recv(&mut self) -> io::Result<&[u8]> {
+            let socket = self.socket.clone();
+            let mut rx = socket.rx.lock().unwrap();
+            self.recycle(&mut rx);
+
+            if rx.ring.is_empty() {
+                if socket.drops(&mut rx)? > 0 {
+                    return Err(io::Error::from_raw_os_error(libc::ENOBUFS));
+                }
+                // polling also wakes the kernel up to refill from the fill ring
+                if !poll(&socket.socket, libc::POLLIN)? || rx.ring.is_empty() {
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+            }
+            let desc = rx.ring.pop().unwrap();
+            rx.ring.release();
+            self.held = Some(desc.addr);
+
+            let (addr, len) = (desc.addr as usize, desc.len as usize);
+            if addr + len > self.socket.umem.len {
+                // can't happen with a sane kernel, but don't read past the UMEM if it does
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "frame extends past the UMEM",
+                ));
+            }
+            Ok(unsafe { slice::from_raw_parts(self.socket.umem.at(addr), len) })
+        }
+    }
+
+    impl Drop for XdpReceiver {
+        fn drop(&mut self) {
+            let socket = self.socket.clone();
+            let mut rx = socket.rx.lock().unwrap();
+            self.recycle(&mut rx);
+        }
+    }
+
+    // the XDP program that redirects our replies to the socket, and the map it finds the socket in
+    struct Program {
+        _link: OwnedFd,
+        _program: OwnedFd,
+        _map: OwnedFd,
+    }
+
+    // the few bpf_attr variants we need, see include/uapi/linux/bpf.h
+    #[repr(C)]
+    struct MapCreate {
+        map_type: u32,
+        key_size: u32,
+        value_size: u32,
+        max_entries: u32,
+        map_flags: u32,
+        inner_map_fd: u32,
+        numa_node: u32,
+        map_name: [u8; 16],
+    }
+
+    #[repr(C)]
+    struct MapUpdate {
+        map_fd: u32,
+        key: u64,
+        value: u64,
+        flags: u64,
+    }
+
+    #[repr(C)]
+    struct ProgLoad {
+        prog_type: u32,
+        insn_cnt: u32,
+        insns: u64,
+        license: u64,
+        log_level: u32,
+        log_size: u32,
+        log_buf: u64,
+        kern_version: u32,
+        prog_flags: u32,
+        prog_name: [u8; 16],
+        prog_ifindex: u32,
+        expected_attach_type: u32,
+    }
+
+    #[repr(C)]
+    struct LinkCreate {
+        prog_fd: u32,
+        target_ifindex: u32,
+        attach_type: u32,
+        flags: u32,
+    }
+
+    const BPF_MAP_CREATE: c_int = 0;
+    const BPF_MAP_UPDATE_ELEM: c_int = 2;
+    const BPF_PROG_LOAD: c_int = 5;
+    const BPF_LINK_CREATE: c_int = 28;
+    const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+    const BPF_PROG_TYPE_XDP: u32 = 6;
+    const BPF_XDP: u32 = 37;
+    const XDP_FLAGS_SKB_MODE: u32 = 1 << 1;
+
+    fn bpf<T>(command: c_int, attr: &T) -> io::Result<libc::c_long> {
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_bpf,
+                command,
+                attr as *const T,
+                mem::size_of::<T>() as u32,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(result)
+    }
+
+    // for the commands that create something
+    fn bpf_fd<T>(command: c_int, attr: &T) -> io::Result<OwnedFd> {
+        let fd = bpf(command, attr)?;
+        Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+    }
+
+    fn name(name: &str) -> [u8; 16] {
+        let mut buf = [0; 16];
+        buf[..name.len()].copy_from_slice(name.as_bytes());
+        buf
+    }
+
+    // a single eBPF instruction
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct Insn {
+        code: u8,
+        regs: u8,
+        off: i16,
+        imm: i32,
+    }
+
+    fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Insn {
+        // the registers are a bitfield, which is laid out the other way around on big endian
+        let regs = if cfg!(target_endian = "little") {
+            dst | src << 4
+        } else {
+            dst << 4 | src
+        };
+        Insn {
+            code,
+            regs,
+            off,
+            imm,
+        }
+    }
+
+    const LDX_W: u8 = 0x61;
+    const LDX_H: u8 = 0x69;
+    const LDX_B: u8 = 0x71;
+    const MOV_X: u8 = 0xbf;
+    const MOV_K: u8 = 0xb7;
+    const ADD_K: u8 = 0x07;
+    const AND_K: u8 = 0x57;
+    const TO_BE: u8 = 0xdc;
+    const JGT_X: u8 = 0x2d;
+    const JEQ_K: u8 = 0x15;
+    const JNE_K: u8 = 0x55;
+    const JGT_K: u8 = 0x25;
+    const JLT_K: u8 = 0xa5;
+    const LD_DW: u8 = 0x18;
+    const CALL: u8 = 0x85;
+    const EXIT: u8 = 0x95;
+    const PSEUDO_MAP_FD: u8 = 1;
+    const REDIRECT_MAP: i32 = 51;
+    const XDP_PASS: i32 = 2;
+
+    // a big endian u16 as it ends up in a register when it's loaded from the packet
+    fn wire(value: u16) -> i32 {
+        u16::from_ne_bytes(value.to_be_bytes()) as i32
+    }
+
+    // redirects unfragmented IPv4 TCP and UDP packets without options to one of our source ports to the socket,
+    // and passes everything else on to the kernel, same as when the socket isn't there (anymore)
+    fn program(map: RawFd, source_ports: PortRange) -> Vec<Insn> {
+        let mut insns = Vec::new();
+        // jumps to the end of the program, where the packet is passed
+        let mut pass = Vec::new();
+        let mut jump_to_pass = |insns: &mut Vec<Insn>, code, dst, src, imm| {
+            pass.push(insns.len());
+            insns.push(insn(code, dst, src, 0, imm));
+        };
+
+        // r6 = ctx, r2 = data, r3 = data_end
+        insns.push(insn(MOV_X, 6, 1, 0, 0));
+        insns.push(insn(LDX_W, 2, 1, 0, 0));
+        insns.push(insn(LDX_W, 3, 1, 4, 0));
+        // the Ethernet, IP and first 4 bytes of the TCP or UDP header have to be there
+        insns.push(insn(MOV_X, 4, 2, 0, 0));
+        insns.push(insn(ADD_K, 4, 0, 0, 14 + 20 + 4));
+        jump_to_pass(&mut insns, JGT_X, 4, 3, 0);
+        // EtherType
+        insns.push(insn(LDX_H, 5, 2, 12, 0));
+        jump_to_pass(&mut insns, JNE_K, 5, 0, wire(0x0800));
+        // version and header length
+        insns.push(insn(LDX_B, 5, 2, 14, 0));
+        jump_to_pass(&mut insns, JNE_K, 5, 0, 0x45);
+        // fragment offset, later fragments don't have the ports
+        insns.push(insn(LDX_H, 5, 2, 20, 0));
+        insns.push(insn(AND_K, 5, 0, 0, wire(0x1fff)));
+        jump_to_pass(&mut insns, JNE_K, 5, 0, 0);
+        // protocol
+        insns.push(insn(LDX_B, 5, 2, 23, 0));
+        insns.push(insn(JEQ_K, 5, 0, 1, 6));
+        jump_to_pass(&mut insns, JNE_K, 5, 0, 17);
+        // destination port
+        insns.push(insn(LDX_H, 5, 2, 36, 0));
+        insns.push(insn(TO_BE, 5, 0, 0, 16));
+        jump_to_pass(&mut insns, JLT_K, 5, 0, source_ports.start as i32);
+        jump_to_pass(&mut insns, JGT_K, 5, 0, source_ports.end as i32);
+        // return bpf_redirect_map(&xsks, ctx->rx_queue_index, XDP_PASS)
+        insns.push(insn(LDX_W, 2, 6, 16, 0));
+        insns.push(insn(LD_DW, 1, PSEUDO_MAP_FD, 0, map));
+        insns.push(insn(0, 0, 0, 0, 0));
+        insns.push(insn(MOV_K, 3, 0, 0, XDP_PASS));
+        insns.push(insn(CALL, 0, 0, 0, REDIRECT_MAP));
+        insns.push(insn(EXIT, 0, 0, 0, 0));
+
+        let end = insns.len();
+        for jump in pass {
+            insns[jump].off = (end - jump - 1) as i16;
+        }
+        insns.push(insn(MOV_K, 0, 0, 0, XDP_PASS));
+        insns.push(insn(EXIT, 0, 0, 0, 0));
+        insns
+    }
+
+    impl Program {
+        fn attach(
+            interface: u32,
+            queue: u32,
+            socket: &OwnedFd,
+            source_ports: PortRange,
+        ) -> io::Result<Self> {
+            let map = bpf_fd(
+                BPF_MAP_CREATE,
+                &MapCreate {
+                    map_type: BPF_MAP_TYPE_XSKMAP,
+                    key_size: 4,
+                    value_size: 4,
+                    max_entries: queue + 1,
+                    map_flags: 0,
+                    inner_map_fd: 0,
+                    numa_node: 0,
+                    map_name: name("badscan_xsks"),
+                },
+            )?;
+            let (key, value) = (queue, socket.as_raw_fd() as u32);
+            bpf(
+                BPF_MAP_UPDATE_ELEM,
+                &MapUpdate {
+                    map_fd: map.as_raw_fd() as u32,
+                    key: &key as *const u32 as u64,
+                    value: &value as *const u32 as u64,
+                    flags: 0,
+                },
+            )?;
+
+            let insns = program(map.as_raw_fd(), source_ports);
+            let license = c"GPL";
+            let program = bpf_fd(
+                BPF_PROG_LOAD,
+                &ProgLoad {
+                    prog_type: BPF_PROG_TYPE_XDP,
+                    insn_cnt: insns.len() as u32,
+                    insns: insns.as_ptr() as u64,
+                    license: license.as_ptr() as u64,
+                    log_level: 0,
+                    log_size: 0,
+                    log_buf: 0,
+                    kern_version: 0,
+                    prog_flags: 0,
+                    prog_name: name("badscan"),
+                    prog_ifindex: 0,
+                    expected_attach_type: BPF_XDP,
+                },
+            )?;
+
+            // the kernel picks the driver's own XDP support when there is any, which may still refuse the program
+            // (e.g. because of the MTU), the generic one works with every driver
+            let link = |flags| {
+                bpf_fd(
+                    BPF_LINK_CREATE,
+                    &LinkCreate {
+                        prog_fd: program.as_raw_fd() as u32,
+                        target_ifindex: interface,
+                        attach_type: BPF_XDP,
+                        flags,
+                    },
+                )
+            };
+            let link = match link(0) {
+                Ok(link) => link,
+                // another program is already attached
+                Err(err) if err.raw_os_error() == Some(libc::EBUSY) => return Err(err),
+                Err(_) => link(XDP_FLAGS_SKB_MODE)?,
+            };
+
+            Ok(Self {
+                _link: link,
+                _program: program,
+                _map: map,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+
+        const MAP: RawFd = 42;
+        const QUEUE: u64 = 3;
+        const PORTS: PortRange = PortRange {
+            start: 61000,
+            end: 61009,
+        };
+        const XDP_REDIRECT: u64 = 4;
+
+        fn regs(insn: &Insn) -> (usize, usize) {
+            let (dst, src) = if cfg!(target_endian = "little") {
+                (insn.regs & 0xf, insn.regs >> 4)
+            } else {
+                (insn.regs >> 4, insn.regs & 0xf)
+            };
+            (dst as usize, src as usize)
+        }
+
+        fn is_jump(insn: &Insn) -> bool {
+            insn.code & 0x07 == 0x05 && insn.code != CALL && insn.code != EXIT
+        }
+
+        // runs the program like the kernel would on `packet` arriving on QUEUE, with just enough of an interpreter
+        // for the instructions it uses, and returns the queue it was redirected to (None when it was passed)
+        // reading outside of the packet fails the test, the verifier wouldn't load the program
+        fn run(packet: &[u8]) -> Option<u64> {
+            const CTX: u64 = 0x10_0000;
+            const DATA: u64 = 0x1000;
+            let load = |addr: u64, size: usize| -> u64 {
+                if (CTX..CTX + 24).contains(&addr) {
+                    return match addr - CTX {
+                        0 => DATA,
+                        4 => DATA + packet.len() as u64,
+                        16 => QUEUE,
+                        offset => panic!("unexpected ctx field at {offset}"),
+                    };
+                }
+                let offset = (addr - DATA) as usize;
+                let bytes = packet
+                    .get(offset..offset + size)
+                    .unwrap_or_else(|| panic!("read past the end of the packet at {offset}"));
+                match size {
+                    1 => bytes[0] as u64,
+                    2 => u16::from_ne_bytes([bytes[0], bytes[1]]) as u64,
+                    _ => u32::from_ne_bytes(bytes.try_into().unwrap()) as u64,
+                }
+            };
+
+            let insns = program(MAP, PORTS);
+            let mut r = [0u64; 11];
+            r[1] = CTX;
+            let mut redirected = None;
+            let mut pc = 0;
+            loop {
+                let insn = insns[pc];
+                let (dst, src) = regs(&insn);
+                let imm = insn.imm as i64 as u64;
+                pc += 1;
+                let taken = match insn.code {
+                    LDX_W | LDX_H | LDX_B => {
+                        let size = match insn.code {
+                            LDX_W => 4,
+                            LDX_H => 2,
+                            _ => 1,
+                        };
+                        r[dst] = load(r[src].wrapping_add(insn.off as i64 as u64), size);
+                        false
+                    }
+                    MOV_X => {
+                        r[dst] = r[src];
+                        false
+                    }
+                    MOV_K => {
+                        r[dst] = imm;
+                        false
+                    }
+                    ADD_K => {
+                        r[dst] = r[dst].wrapping_add(imm);
+                        false
+                    }
+                    AND_K => {
+                        r[dst] &= imm;
+                        false
+                    }
+                    TO_BE => {
+                        assert_eq!(insn.imm, 16);
+                        r[dst] = (r[dst] as u16).to_be() as u64;
+                        false
+                    }
+                    LD_DW => {
+                        r[dst] = (insn.imm as u32 as u64) | (insns[pc].imm as u32 as u64) << 32;
+                        pc += 1;
+                        false
+                    }
+                    CALL => {
+                        assert_eq!(insn.imm, REDIRECT_MAP);
+                        assert_eq!(r[1], MAP as u64);
+                        assert_eq!(r[3], XDP_PASS as u64);
+                        redirected = Some(r[2]);
+                        r[0] = XDP_REDIRECT;
+                        false
+                    }
+                    EXIT => break,
+                    JGT_X => r[dst] > r[src],
+                    JEQ_K => r[dst] == imm,
+                    JNE_K => r[dst] != imm,
+                    JGT_K => r[dst] > imm,
+                    JLT_K => r[dst] < imm,
+                    code => panic!("unexpected instruction {code:#x}"),
+                };
+                if taken {
+                    pc = (pc as isize + insn.off as isize) as usize;
+                }
+            }
+            match r[0] {
+                XDP_REDIRECT => redirected,
+                action => {
+                    assert_eq!(action, XDP_PASS as u64);
+                    None
+                }
+            }
+        }
+
+        fn packet(protocol: u8, dest_port: u16) -> Vec<u8> {
+            let mut packet = vec![0; 12];
+            packet.extend_from_slice(&[0x08, 0x00]);
+            packet.extend_from_slice(&[0x45, 0, 0, 40, 0, 1, 0x40, 0, 64, protocol, 0, 0]);
+            packet.extend_from_slice(&[1, 2, 3, 4, 10, 0, 0, 2]);
+            packet.extend_from_slice(&25565u16.to_be_bytes());
+            packet.extend_from_slice(&dest_port.to_be_bytes());
+            packet.extend_from_slice(&[0; 16]);
+            packet
+        }
+
+        #[test]
+        fn program_layout() {
+            let insns = program(MAP, PORTS);
+            assert_eq!(insns.len(), 28);
+            assert_eq!(insns.last().unwrap().code, EXIT);
+
+            // the map is referenced by its fd, which the kernel swaps for the map itself
+            let load = insns.iter().position(|insn| insn.code == LD_DW).unwrap();
+            assert_eq!(regs(&insns[load]), (1, PSEUDO_MAP_FD as usize));
+            assert_eq!(insns[load].imm, MAP);
+            assert_eq!(insns[load + 1].code, 0);
+
+            // every jump lands inside the program, and never in the second half of the map load
+            for (i, insn) in insns.iter().enumerate().filter(|(_, insn)| is_jump(insn)) {
+                let target = i as isize + 1 + insn.off as isize;
+                assert!((0..insns.len() as isize).contains(&target), "jump at {i}");
+                assert_ne!(target as usize, load + 1, "jump at {i}");
+            }
+        }
+
+        #[test]
+        fn program_redirects_replies() {
+            assert_eq!(run(&packet(17, 61000)), Some(QUEUE));
+            assert_eq!(run(&packet(6, 61009)), Some(QUEUE));
+            assert_eq!(run(&packet(6, 61010)), None);
+            assert_eq!(run(&packet(17, 60999)), None);
+            // ICMP
+            assert_eq!(run(&packet(1, 61000)), None);
+
+            let mut ipv6 = packet(17, 61000);
+            ipv6[12..14].copy_from_slice(&[0x86, 0xdd]);
+            assert_eq!(run(&ipv6), None);
+            let mut options = packet(17, 61000);
+            options[14] = 0x46;
+            assert_eq!(run(&options), None);
+            // only the first fragment has the ports
+            let mut fragment = packet(17, 61000);
+            fragment[20..22].copy_from_slice(&[0x20, 0]);
+            assert_eq!(run(&fragment), Some(QUEUE));
+            fragment[20..22].copy_from_slice(&[0, 0x10]);
+            assert_eq!(run(&fragment), None);
+
+            // too short to have the ports, nothing past the end gets read
+            let short = packet(17, 61000);
+            assert_eq!(run(&short[..14 + 20 + 3]), None);
+            assert_eq!(run(&short[..14 + 20 + 4]), Some(QUEUE));
+            assert_eq!(run(&[]), None);
+        }
+    }
+}
+
+#[cfg(not(all(feature = "xdp", target_os = "linux")))]
+mod sys {
+    use std::{io, sync::Arc};
+
+    use crate::{
+        backend::{FrameReceiver, FrameSender},
+        config::PortRange,
+    };
+
+    #[derive(Debug)]
+    pub struct Socket;
+
+    impl Socket {
+        pub fn open(
+            _interface: u32,
+            _queue: u32,
+            _frames: u32,
+            _batch: u32,
+            _zero_copy: bool,
+            _source_ports: PortRange,
+        ) -> io::Result<Self> {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "AF_XDP sockets need Linux and a build with the `xdp` feature",
+            ))
+        }
+
+        pub fn zero_copy(&self) -> bool {
+            false
+        }
+    }
+
+    pub struct XdpSender;
+
+    impl XdpSender {
+        pub fn new(_socket: Arc<Socket>) -> Self {
+            Self
+        }
+    }
+
+    impl FrameSender for XdpSender {
+        fn send(&mut self, _frame: &[u8]) -> io::Result<()> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+    }
+
+    pub struct XdpReceiver;
+
+    impl XdpReceiver {
+        pub fn new(_socket: Arc<Socket>) -> Self {
+            Self
+        }
+    }
+
+    impl FrameReceiver for XdpReceiver {
+        fn recv(&mut self) -> io::Result<&[u8]> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+    }
+}
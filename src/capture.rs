@@ -0,0 +1,256 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_derive::Deserialize;
+use tracing::{error, info};
+
+use crate::{packet::LinkType, pcap, warn_limited};
+
+// writes the frames of a scan to pcapng files, for looking at them in Wireshark (or `badscan replay`)
+
+const SECTION_HEADER: u32 = 0x0A0D0D0A;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+const INTERFACE_DESCRIPTION: u32 = 1;
+const ENHANCED_PACKET: u32 = 6;
+
+const OPT_END: u16 = 0;
+const OPT_SHB_USERAPPL: u16 = 4;
+const OPT_IF_NAME: u16 = 2;
+const OPT_IF_TSRESOL: u16 = 9;
+
+#[derive(Deserialize, Debug)]
+pub struct CaptureConfig {
+    // write every frame we send and every reply we accept to this pcapng file, uncomment to enable
+    pub file: Option<String>,
+    // start a new file once the current one is this big (in MiB), 0 never does
+    #[serde(default = "default_max_size")]
+    pub max_size: u64,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            max_size: default_max_size(),
+        }
+    }
+}
+
+fn default_max_size() -> u64 {
+    100
+}
+
+// a pcapng section with a single interface, timestamps are in microseconds
+pub struct PcapngWriter<W: Write> {
+    writer: W,
+    // bytes written so far
+    written: u64,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    pub fn new(writer: W, interface: &str, link_type: LinkType) -> io::Result<Self> {
+        let mut pcap = Self { writer, written: 0 };
+
+        let mut section = vec![];
+        section.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        section.extend_from_slice(&1u16.to_le_bytes()); // major version
+        section.extend_from_slice(&0u16.to_le_bytes()); // minor version
+        section.extend_from_slice(&(-1i64).to_le_bytes()); // section length, unknown
+        let application = format!("badscan {}", env!("CARGO_PKG_VERSION"));
+        push_option(&mut section, OPT_SHB_USERAPPL, application.as_bytes());
+        push_option(&mut section, OPT_END, &[]);
+        pcap.write_block(SECTION_HEADER, &section)?;
+
+        let mut description = vec![];
+        description.extend_from_slice(&pcap::linktype(link_type).to_le_bytes());
+        description.extend_from_slice(&0u16.to_le_bytes()); // reserved
+        description.extend_from_slice(&0u32.to_le_bytes()); // snaplen, no limit
+        push_option(&mut description, OPT_IF_NAME, interface.as_bytes());
+        push_option(&mut description, OPT_IF_TSRESOL, &[6]);
+        push_option(&mut description, OPT_END, &[]);
+        pcap.write_block(INTERFACE_DESCRIPTION, &description)?;
+
+        Ok(pcap)
+    }
+
+    pub fn write_frame(&mut self, timestamp: SystemTime, frame: &[u8]) -> io::Result<()> {
+        let micros = timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        let mut packet = Vec::with_capacity(20 + frame.len() + 3);
+        packet.extend_from_slice(&0u32.to_le_bytes()); // interface
+        packet.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        packet.extend_from_slice(&(micros as u32).to_le_bytes());
+        packet.extend_from_slice(&(frame.len() as u32).to_le_bytes()); // captured length
+        packet.extend_from_slice(&(frame.len() as u32).to_le_bytes()); // original length
+        packet.extend_from_slice(frame);
+        pad(&mut packet);
+        self.write_block(ENHANCED_PACKET, &packet)
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    // the body has to be padded to 4 bytes already
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> io::Result<()> {
+        let len = (12 + body.len()) as u32;
+        self.writer.write_all(&block_type.to_le_bytes())?;
+        self.writer.write_all(&len.to_le_bytes())?;
+        self.writer.write_all(body)?;
+        self.writer.write_all(&len.to_le_bytes())?;
+        self.written += len as u64;
+        Ok(())
+    }
+}
+
+fn push_option(block: &mut Vec<u8>, code: u16, value: &[u8]) {
+    block.extend_from_slice(&code.to_le_bytes());
+    block.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    block.extend_from_slice(value);
+    pad(block);
+}
+
+fn pad(block: &mut Vec<u8>) {
+    block.resize(block.len().next_multiple_of(4), 0);
+}
+
+// the capture files of a scan, shared by the send and receive threads
+// once a file reaches the maximum size the next one is started, `scan.pcapng` is followed by `scan.1.pcapng`, `scan.2.pcapng`, ...
+#[derive(Clone)]
+pub struct Capture {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    path: PathBuf,
+    interface: String,
+    link_type: LinkType,
+    // in bytes, 0 for no limit
+    max_size: u64,
+    index: u32,
+    writer: PcapngWriter<BufWriter<File>>,
+}
+
+impl Capture {
+    pub fn create(
+        path: &Path,
+        interface: &str,
+        link_type: LinkType,
+        max_size: u64,
+    ) -> io::Result<Self> {
+        let writer = PcapngWriter::new(BufWriter::new(File::create(path)?), interface, link_type)?;
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Inner {
+                path: path.to_owned(),
+                interface: interface.to_string(),
+                link_type,
+                max_size: max_size * 1024 * 1024,
+                index: 0,
+                writer,
+            })),
+        })
+    }
+
+    // the link type has to be the one of the backend the frames come from
+    pub fn from_config(
+        config: &CaptureConfig,
+        interface: &str,
+        link_type: LinkType,
+    ) -> io::Result<Option<Self>> {
+        config
+            .file
+            .as_ref()
+            .map(|path| Self::create(Path::new(path), interface, link_type, config.max_size))
+            .transpose()
+    }
+
+    // a failing capture shouldn't break the scan, so errors only get logged
+    pub fn write(&self, frame: &[u8]) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Err(err) = inner.write(frame) {
+            warn_limited!(%err, "could not write to the capture");
+        }
+    }
+
+    pub fn flush(&self) {
+        if let Err(err) = self.inner.lock().unwrap().writer.flush() {
+            error!("Could not flush capture: {err}");
+        }
+    }
+}
+
+impl Inner {
+    fn write(&mut self, frame: &[u8]) -> io::Result<()> {
+        if self.max_size > 0 && self.writer.written() >= self.max_size {
+            self.rotate()?;
+        }
+        self.writer.write_frame(SystemTime::now(), frame)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.index += 1;
+        let path = rotated_path(&self.path, self.index);
+        self.writer = PcapngWriter::new(
+            BufWriter::new(File::create(&path)?),
+            &self.interface,
+            self.link_type,
+        )?;
+        info!("Capturing to {}", path.display());
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, index: u32) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{stem}.{index}.{}", extension.to_string_lossy()),
+        None => format!("{stem}.{index}"),
+    };
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::pcap::PcapReader;
+
+    #[test]
+    fn write_pcapng() {
+        let mut buf = vec![];
+        let mut pcap = PcapngWriter::new(&mut buf, "eth0", LinkType::Ethernet).unwrap();
+        pcap.write_frame(SystemTime::now(), b"first").unwrap();
+        pcap.write_frame(UNIX_EPOCH, b"second frame").unwrap();
+        let written = pcap.written();
+        assert_eq!(written, buf.len() as u64);
+
+        let mut pcap = PcapReader::new(&buf[..]).unwrap();
+        assert_eq!(
+            pcap.next_frame().unwrap(),
+            Some((LinkType::Ethernet, &b"first"[..]))
+        );
+        assert_eq!(
+            pcap.next_frame().unwrap(),
+            Some((LinkType::Ethernet, &b"second frame"[..]))
+        );
+        assert_eq!(pcap.next_frame().unwrap(), None);
+
+        assert_eq!(
+            rotated_path(Path::new("captures/scan.pcapng"), 2),
+            Path::new("captures/scan.2.pcapng")
+        );
+    }
+}
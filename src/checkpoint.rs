@@ -0,0 +1,229 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, TimeZone, Utc};
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    config::PortRange,
+    targets::{Shard, TargetSpace},
+    utils::stable_hash,
+};
+
+// bumped whenever the same seed would give another permutation or other cookies, so those checkpoints can't be resumed
+pub const CHECKPOINT_VERSION: u32 = 1;
+
+// everything needed to continue a scan after the process died
+// the seed and start time are stored as well, since we need them to validate cookies of probes sent by the original run
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    // how the permutation and cookies were computed, checkpoints from before it was stored are 0
+    #[serde(default)]
+    pub version: u32,
+    pub seed: i64,
+    // milliseconds since the unix epoch, since that's what the cookies use
+    pub start_time: i64,
+    // how many targets of the permutation we already sent probes to
+    pub position: u64,
+    // size of the target space, to detect if the targets were changed in between
+    pub total: u64,
+    // and stable hashes of its addresses, and of the ports together with the source ports, for changes that keep
+    // the size
+    #[serde(default)]
+    pub targets: u64,
+    #[serde(default)]
+    pub ports: u64,
+    pub shard: Option<Shard>,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Deserialize(#[from] toml::de::Error),
+    #[error(transparent)]
+    Serialize(#[from] toml::ser::Error),
+    #[error("invalid start time in checkpoint: {0}")]
+    InvalidStartTime(i64),
+    #[error("the checkpoint was made by an incompatible version of badscan (format {0}, expected {CHECKPOINT_VERSION})")]
+    Version(u32),
+    #[error("the {0} changed since the checkpoint was made")]
+    Changed(&'static str),
+}
+
+impl Checkpoint {
+    // for a scan of `space` that didn't send anything yet
+    pub fn new(
+        seed: i64,
+        start_time: i64,
+        space: &TargetSpace,
+        source_ports: PortRange,
+        shard: Option<Shard>,
+    ) -> Self {
+        let mut ports = space.port_hash().to_le_bytes().to_vec();
+        ports.extend_from_slice(&source_ports.start.to_le_bytes());
+        ports.extend_from_slice(&source_ports.end.to_le_bytes());
+        Self {
+            version: CHECKPOINT_VERSION,
+            seed,
+            start_time,
+            position: 0,
+            total: space.len(),
+            targets: space.address_hash(),
+            ports: stable_hash(&ports),
+            shard,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let contents = fs::read_to_string(path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+
+    // write to a temporary file first, so we never end up with half a checkpoint when we die while writing
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+
+        fs::write(&tmp, toml::to_string(self)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    // whether a scan set up like `scan` can continue from this checkpoint
+    // we can't validate cookies of the old run with another seed, and positions mean nothing in another target space
+    pub fn check(&self, scan: &Checkpoint) -> Result<(), Error> {
+        if self.version != CHECKPOINT_VERSION {
+            return Err(Error::Version(self.version));
+        }
+        if self.seed != scan.seed {
+            return Err(Error::Changed("seed"));
+        }
+        if self.total != scan.total || self.targets != scan.targets {
+            return Err(Error::Changed("targets"));
+        }
+        if self.ports != scan.ports {
+            return Err(Error::Changed("ports"));
+        }
+        if self.shard != scan.shard {
+            return Err(Error::Changed("shard"));
+        }
+        self.start_time()?;
+        Ok(())
+    }
+
+    pub fn start_time(&self) -> Result<DateTime<Utc>, Error> {
+        Utc.timestamp_millis_opt(self.start_time)
+            .single()
+            .ok_or(Error::InvalidStartTime(self.start_time))
+    }
+}
+
+// saves the checkpoint every `interval`
+pub struct Checkpointer {
+    path: PathBuf,
+    interval: Duration,
+    last_save: Instant,
+    checkpoint: Checkpoint,
+}
+
+impl Checkpointer {
+    pub fn new(path: PathBuf, interval: Duration, checkpoint: Checkpoint) -> Self {
+        Self {
+            path,
+            interval,
+            last_save: Instant::now(),
+            checkpoint,
+        }
+    }
+
+    pub fn update(&mut self, position: u64) -> Result<(), Error> {
+        self.checkpoint.position = position;
+        if self.last_save.elapsed() >= self.interval {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    pub fn save(&mut self) -> Result<(), Error> {
+        self.last_save = Instant::now();
+        self.checkpoint.save(&self.path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn incompatible_checkpoints() {
+        let space = |ranges: &[&str], ports: Vec<u16>| {
+            let ranges = ranges.iter().map(|range| range.parse().unwrap()).collect();
+            TargetSpace::new(ranges, ports).unwrap()
+        };
+        let source_ports = PortRange {
+            start: 61000,
+            end: 61999,
+        };
+        let checkpoint_for = |space: &TargetSpace, source_ports| {
+            Checkpoint::new(1, 1_700_000_000_000, space, source_ports, None)
+        };
+        let scan = checkpoint_for(&space(&["10.0.0.0/24"], vec![25565]), source_ports);
+        let checkpoint = Checkpoint {
+            position: 100,
+            ..scan.clone()
+        };
+        assert!(checkpoint.check(&scan).is_ok());
+
+        // written before the version was stored
+        let old: Checkpoint =
+            toml::from_str("seed = 1\nstart_time = 1700000000000\nposition = 100\ntotal = 256\n")
+                .unwrap();
+        assert!(matches!(old.check(&scan), Err(Error::Version(0))));
+        let moved = Checkpoint {
+            total: 512,
+            ..checkpoint.clone()
+        };
+        assert!(matches!(moved.check(&scan), Err(Error::Changed("targets"))));
+
+        // the same number of targets, but other ones
+        for (other, changed) in [
+            (
+                checkpoint_for(&space(&["10.0.1.0/24"], vec![25565]), source_ports),
+                "targets",
+            ),
+            (
+                checkpoint_for(
+                    &space(&["10.0.0.0/25", "10.0.2.0/25"], vec![25565]),
+                    source_ports,
+                ),
+                "targets",
+            ),
+            (
+                checkpoint_for(&space(&["10.0.0.0/24"], vec![25566]), source_ports),
+                "ports",
+            ),
+            (
+                checkpoint_for(
+                    &space(&["10.0.0.0/24"], vec![25565]),
+                    PortRange {
+                        start: 62000,
+                        end: 62999,
+                    },
+                ),
+                "ports",
+            ),
+        ] {
+            assert_eq!(other.total, scan.total);
+            assert!(
+                matches!(checkpoint.check(&other), Err(Error::Changed(field)) if field == changed)
+            );
+        }
+    }
+}
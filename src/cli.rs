@@ -0,0 +1,131 @@
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use crate::{
+    config::{self, Config},
+    targets::{Ipv4Range, Shard},
+};
+
+// values given on the command line are layered over the ones in the config file
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "badscan",
+    version,
+    about = "A simple little scanner for Minecraft servers"
+)]
+pub struct Cli {
+    /// The config file to use
+    #[arg(short, long, global = true, default_value = "badscan.toml")]
+    pub config: PathBuf,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Scan the configured targets (the default)
+    Scan(ScanArgs),
+    /// Scan the servers found by an earlier scan again
+    Rescan {
+        /// Results file (JSON lines) of the earlier scan
+        results: PathBuf,
+        #[command(flatten)]
+        args: ScanArgs,
+    },
+    /// Combine the result files of several shards into one
+    Merge {
+        output: PathBuf,
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+    },
+    /// Feed the replies in a pcap or pcapng capture through the protocol handlers, without sending anything
+    Replay {
+        /// Capture of a scan, with Ethernet frames or bare IP packets
+        capture: PathBuf,
+        /// Seed of the captured scan, defaults to the one in the checkpoint or the config
+        #[arg(long)]
+        seed: Option<i64>,
+        /// Start time of the captured scan in milliseconds since the unix epoch, defaults to the one in the checkpoint
+        #[arg(long, value_name = "MILLIS")]
+        start_time: Option<i64>,
+        #[arg(long, value_enum)]
+        protocol: Option<ProtocolArg>,
+    },
+    /// List the network interfaces we can scan from
+    ListInterfaces,
+    /// List the TCP fingerprints we can pretend to be
+    ListFingerprints,
+    /// Check if the config file is valid
+    ValidateConfig,
+}
+
+#[derive(Args, Debug, Default)]
+pub struct ScanArgs {
+    /// Continue from the checkpoint of an earlier run
+    #[arg(long)]
+    pub resume: bool,
+    /// Address or CIDR range to scan, can be given multiple times
+    #[arg(short, long = "target", value_name = "RANGE")]
+    pub targets: Vec<Ipv4Range>,
+    /// Port to scan, can be given multiple times
+    #[arg(short, long = "port", value_name = "PORT")]
+    pub ports: Vec<u16>,
+    #[arg(long, value_enum)]
+    pub protocol: Option<ProtocolArg>,
+    /// Maximum amount of probes per second
+    #[arg(long, value_name = "PPS")]
+    pub rate: Option<u64>,
+    /// Network interface to send packets over
+    #[arg(short, long)]
+    pub interface: Option<String>,
+    /// Only scan part of the targets, as `i/n`
+    #[arg(long)]
+    pub shard: Option<Shard>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum ProtocolArg {
+    /// Query, partial stat
+    Query,
+    /// Query, full stat
+    QueryFull,
+    Raknet,
+    Slp,
+}
+
+impl From<ProtocolArg> for config::Protocol {
+    fn from(protocol: ProtocolArg) -> Self {
+        match protocol {
+            ProtocolArg::Query => config::Protocol::Query { fullstat: false },
+            ProtocolArg::QueryFull => config::Protocol::Query { fullstat: true },
+            ProtocolArg::Raknet => config::Protocol::Raknet,
+            ProtocolArg::Slp => config::Protocol::SLP,
+        }
+    }
+}
+
+impl ScanArgs {
+    pub fn apply(&self, config: &mut Config) {
+        if !self.targets.is_empty() {
+            config.scan.targets = self.targets.clone();
+        }
+        if !self.ports.is_empty() {
+            config.scan.ports = self.ports.clone();
+        }
+        if let Some(protocol) = self.protocol {
+            config.protocol = protocol.into();
+        }
+        if let Some(rate) = self.rate {
+            config.scan.rate = Some(rate);
+        }
+        if let Some(interface) = &self.interface {
+            config.interface = Some(interface.clone());
+        }
+        if let Some(shard) = self.shard {
+            config.scan.shard = Some(shard);
+        }
+    }
+}
@@ -1,9 +1,20 @@
-use std::{fmt::Display, io};
+use std::{fmt::Display, io, path::Path, time::Duration};
 
-use once_cell::sync::Lazy;
 use serde_derive::Deserialize;
 use thiserror::Error;
 
+use crate::{
+    backend::{ring::RingConfig, tun::TunConfig, xdp::XdpConfig},
+    capture::CaptureConfig,
+    firewall::FirewallConfig,
+    logging::LoggingConfig,
+    metrics::MetricsConfig,
+    neighbours::ArpConfig,
+    output::OutputConfig,
+    scanner::Cooldown,
+    targets::{Ipv4Range, Shard},
+};
+
 #[derive(Deserialize, Default)]
 pub struct Config {
     pub interface: Option<String>,
@@ -11,15 +22,113 @@ pub struct Config {
     pub protocol: Protocol,
     #[serde(default)]
     pub fingerprint: Fingerprint,
+    #[serde(default)]
+    pub firewall: FirewallConfig,
+    #[serde(default)]
+    pub output: OutputConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub capture: CaptureConfig,
+    #[serde(default)]
+    pub tun: TunConfig,
+    #[serde(default)]
+    pub ring: RingConfig,
+    #[serde(default)]
+    pub xdp: XdpConfig,
+    #[serde(default)]
+    pub arp: ArpConfig,
 }
 
 #[derive(Deserialize, Default)]
 pub struct ScanConfig {
     pub seed: i64,
+    // the longest we wait for replies after the last probe (in seconds)
     pub wait_delay: u64,
+    // the shortest we wait for them
+    #[serde(default = "default_cooldown_min")]
+    pub cooldown_min: u64,
+    // after cooldown_min, stop waiting once no replies arrived for this long and no exchanges are outstanding
+    #[serde(default = "default_cooldown_idle")]
+    pub cooldown_idle: u64,
+    // how long a server gets to continue an exchange (query challenge, TCP connection) before we give up on it
+    #[serde(default = "default_exchange_timeout")]
+    pub exchange_timeout: u64,
+    #[serde(default)]
+    pub source_ports: PortRange,
+    // can also be given on the command line
+    #[serde(default)]
+    pub targets: Vec<Ipv4Range>,
+    // defaults to the default port of the protocol
+    #[serde(default)]
+    pub ports: Vec<u16>,
+    // where to periodically store the progress of the scan, so it can be resumed with --resume
+    pub checkpoint: Option<String>,
+    #[serde(default = "default_checkpoint_interval")]
+    pub checkpoint_interval: u64,
+    // only scan part of the targets, so several instances can split a scan
+    pub shard: Option<Shard>,
+    // how often to print a status line (in seconds), 0 disables it
+    #[serde(default = "default_status_interval")]
+    pub status_interval: u64,
+    // maximum amount of probes per second, unlimited when not set
+    pub rate: Option<u64>,
+    // how many threads send probes, each one to its own share of the targets
+    #[serde(default = "default_senders")]
+    pub senders: usize,
+    // pin the send threads to these CPUs, in turn
+    #[serde(default)]
+    pub send_cpus: Vec<usize>,
+    // how many answers to servers can wait to be sent, before the receive workers have to wait too
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+    // how many threads parse the replies, the frames of a server always go to the same one
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+}
+
+fn default_cooldown_min() -> u64 {
+    2
+}
+
+fn default_cooldown_idle() -> u64 {
+    2
 }
 
-#[derive(Deserialize, Debug)]
+fn default_exchange_timeout() -> u64 {
+    5
+}
+
+fn default_status_interval() -> u64 {
+    5
+}
+
+fn default_checkpoint_interval() -> u64 {
+    30
+}
+
+fn default_senders() -> usize {
+    1
+}
+
+fn default_queue_size() -> usize {
+    65536
+}
+
+fn default_workers() -> usize {
+    1
+}
+
+// inclusive range of local ports we send our probes from
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
 #[serde(tag = "t", content = "c")]
 pub enum Protocol {
     Query { fullstat: bool },
@@ -27,19 +136,81 @@ pub enum Protocol {
     SLP,
 }
 
-#[derive(Debug, Deserialize, Default)]
+#[derive(Debug, Deserialize, Default, Clone, Copy)]
 pub enum Fingerprint {
     #[default]
     #[serde(rename = "Nintendo 3DS")]
     Nintendo3DS, // funny :D
 }
 
+impl Fingerprint {
+    pub const ALL: &'static [Fingerprint] = &[Fingerprint::Nintendo3DS];
+}
+
+impl ScanConfig {
+    pub fn cooldown(&self) -> Cooldown {
+        Cooldown {
+            min: Duration::from_secs(self.cooldown_min),
+            idle: Duration::from_secs(self.cooldown_idle),
+            // the minimum wins when they conflict
+            max: Duration::from_secs(self.wait_delay.max(self.cooldown_min)),
+            exchange_timeout: Duration::from_secs(self.exchange_timeout),
+        }
+    }
+}
+
+impl Protocol {
+    // same as the default port of the protocol implementation, without having to build one
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Protocol::Query { .. } | Protocol::SLP => 25565,
+            Protocol::Raknet => 19132,
+        }
+    }
+}
+
 impl Default for Protocol {
     fn default() -> Self {
         Protocol::Query { fullstat: false }
     }
 }
 
+impl Default for PortRange {
+    fn default() -> Self {
+        Self {
+            start: 61000,
+            end: 61999,
+        }
+    }
+}
+
+impl PortRange {
+    pub fn len(&self) -> u32 {
+        (self.end as u32 + 1).saturating_sub(self.start as u32)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+
+    // the source port is derived from the cookie, so replies can still be validated without keeping state
+    // it's redundant with the cookie (the whole TCP sequence number, the query session ID masked with 0x0F0F0F0F),
+    // the receivers recompute it from the cookie and drop replies to any other port
+    pub fn from_cookie(&self, cookie: u32) -> u16 {
+        self.start + ((cookie >> 16) % self.len()) as u16
+    }
+}
+
+impl Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
 impl Display for Fingerprint {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -49,20 +220,33 @@ impl Display for Fingerprint {
 }
 
 #[derive(Error, Debug)]
-enum Error {
+pub enum Error {
     #[error(transparent)]
     Io(#[from] io::Error),
     #[error(transparent)]
     Toml(#[from] toml::de::Error),
+    #[error("source port range {0} is empty")]
+    EmptyPortRange(PortRange),
+    #[error("tun.address is needed to scan through TUN/TAP device `{0}`")]
+    TunWithoutAddress(String),
+    #[error("arp.rate can't be 0")]
+    ZeroArpRate,
 }
 
-pub static CONFIG: Lazy<Config> =
-    Lazy::new(|| Config::get("badscan.toml").expect("Could not read badscan.toml file!"));
-
 impl Config {
-    fn get(path: &str) -> Result<Self, Error> {
-        let contents = std::fs::read_to_string(path).map_err(|io| Error::Io(io))?;
-        let config = toml::from_str(&contents).map_err(|toml| Error::Toml(toml))?;
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path).map_err(Error::Io)?;
+        let config: Self = toml::from_str(&contents).map_err(Error::Toml)?;
+
+        if config.scan.source_ports.is_empty() {
+            return Err(Error::EmptyPortRange(config.scan.source_ports));
+        }
+        if let (Some(device), None) = (&config.tun.device, config.tun.address) {
+            return Err(Error::TunWithoutAddress(device.clone()));
+        }
+        if config.arp.rate == 0 {
+            return Err(Error::ZeroArpRate);
+        }
 
         Ok(config)
     }
@@ -74,7 +258,24 @@ mod test {
 
     #[test]
     fn validate_example() {
-        let res = Config::get("badscan.example.toml");
+        let res = Config::load(Path::new("badscan.example.toml"));
         assert!(res.is_ok());
     }
+
+    #[test]
+    fn source_port_in_range() {
+        let range = PortRange {
+            start: 40000,
+            end: 40009,
+        };
+        for cookie in [0, 1, 0xFFFF, 0x10000, 0xDEADBEEF, u32::MAX] {
+            assert!(range.contains(range.from_cookie(cookie)));
+        }
+
+        let single = PortRange {
+            start: 61000,
+            end: 61000,
+        };
+        assert_eq!(single.from_cookie(0xDEADBEEF), 61000);
+    }
 }
@@ -0,0 +1,52 @@
+use std::net::SocketAddrV4;
+
+use chrono::{DateTime, Utc};
+
+use crate::{config::PortRange, utils::stable_hash};
+
+// everything needed to generate (and so validate) the cookie of a target
+// a resumed scan has to use the exact same values as the original run
+#[derive(Debug, Clone, Copy)]
+pub struct Cookies {
+    pub seed: i64,
+    pub start_time: DateTime<Utc>,
+    pub source_ports: PortRange,
+}
+
+impl Cookies {
+    pub fn cookie(&self, addr: &SocketAddrV4) -> u32 {
+        let mut data = [0; 22];
+        data[0..4].copy_from_slice(&addr.ip().octets());
+        data[4..6].copy_from_slice(&addr.port().to_be_bytes());
+        data[6..14].copy_from_slice(&self.seed.to_le_bytes());
+        data[14..22].copy_from_slice(&self.start_time.timestamp_millis().to_le_bytes());
+        stable_hash(&data) as u32
+    }
+
+    pub fn source_port(&self, cookie: u32) -> u16 {
+        self.source_ports.from_cookie(cookie)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    // resumed scans (and replays of old captures) validate replies with the cookies of an earlier run
+    // if this changes, so does CHECKPOINT_VERSION
+    #[test]
+    fn cookies_are_stable() {
+        let cookies = Cookies {
+            seed: 1234,
+            start_time: Utc.timestamp_millis_opt(1_700_000_000_000).unwrap(),
+            source_ports: PortRange {
+                start: 61000,
+                end: 61999,
+            },
+        };
+        let cookie = cookies.cookie(&"10.0.0.1:25565".parse().unwrap());
+        assert_eq!(cookie, 2946038653);
+    }
+}
@@ -0,0 +1,133 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddrV4,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// multi-step exchanges that are still waiting on the server, like a query challenge we answered or a TCP connection waiting for data
+// this is only used to know when the cooldown can end, so being a bit rough is fine
+// every receive worker keeps its own table (a server always ends up at the same worker), this only adds them up
+#[derive(Debug)]
+pub struct Exchanges {
+    timeout: Duration,
+    tables: Mutex<Vec<Arc<Mutex<Table>>>>,
+}
+
+// the exchanges of one worker, the lock is only ever shared with `Exchanges::outstanding`
+// cloning gives a new, empty table for another worker, counted by the same Exchanges
+#[derive(Debug)]
+pub struct ExchangeTable {
+    exchanges: Arc<Exchanges>,
+    table: Arc<Mutex<Table>>,
+}
+
+#[derive(Debug)]
+struct Table {
+    // when we last heard from every server we're waiting on
+    last_activity: HashMap<SocketAddrV4, Instant>,
+    last_expire: Instant,
+}
+
+impl Exchanges {
+    pub fn new(timeout: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            timeout,
+            tables: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn table(self: &Arc<Self>) -> ExchangeTable {
+        let table = Arc::new(Mutex::new(Table {
+            last_activity: HashMap::new(),
+            last_expire: Instant::now(),
+        }));
+        self.tables.lock().unwrap().push(table.clone());
+        ExchangeTable {
+            exchanges: self.clone(),
+            table,
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    // forgets the exchanges that timed out, and returns how many are still going
+    pub fn outstanding(&self) -> usize {
+        let now = Instant::now();
+        self.tables
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|table| {
+                let mut table = table.lock().unwrap();
+                table.expire(now, self.timeout);
+                table.last_activity.len()
+            })
+            .sum()
+    }
+}
+
+impl ExchangeTable {
+    pub fn timeout(&self) -> Duration {
+        self.exchanges.timeout
+    }
+
+    // also used to refresh an exchange that made progress
+    pub fn start(&self, addr: SocketAddrV4) {
+        let mut table = self.table.lock().unwrap();
+        let now = Instant::now();
+        table.last_activity.insert(addr, now);
+
+        // servers that never finish their exchange would pile up over a long scan otherwise
+        if now - table.last_expire >= self.timeout() {
+            table.expire(now, self.timeout());
+        }
+    }
+
+    pub fn end(&self, addr: &SocketAddrV4) {
+        self.table.lock().unwrap().last_activity.remove(addr);
+    }
+}
+
+impl Clone for ExchangeTable {
+    fn clone(&self) -> Self {
+        self.exchanges.table()
+    }
+}
+
+impl Table {
+    fn expire(&mut self, now: Instant, timeout: Duration) {
+        self.last_activity
+            .retain(|_, last_activity| now - *last_activity < timeout);
+        self.last_expire = now;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn exchanges_end_or_time_out() {
+        let a = "10.0.0.1:25565".parse().unwrap();
+        let b = "10.0.0.2:25565".parse().unwrap();
+
+        let exchanges = Exchanges::new(Duration::from_secs(3600));
+        let table = exchanges.table();
+        table.start(a);
+        table.start(b);
+        table.start(a);
+        assert_eq!(exchanges.outstanding(), 2);
+        table.end(&a);
+        assert_eq!(exchanges.outstanding(), 1);
+        // another worker's table
+        table.clone().start(a);
+        assert_eq!(exchanges.outstanding(), 2);
+
+        let exchanges = Exchanges::new(Duration::ZERO);
+        exchanges.table().start(a);
+        assert_eq!(exchanges.outstanding(), 0);
+    }
+}
@@ -0,0 +1,216 @@
+use std::{
+    io::{self, Write},
+    process::{Command, Stdio},
+};
+
+use serde_derive::Deserialize;
+use thiserror::Error;
+use tracing::{error, info};
+
+use crate::config::PortRange;
+
+// the kernel doesn't know about our handcrafted TCP connections, so it will RST them unless we drop the packets before they reach it
+// this module can add (and remove) the rule that does that
+
+#[derive(Deserialize, Debug, Default)]
+pub struct FirewallConfig {
+    // install the rule on startup and remove it on shutdown
+    #[serde(default)]
+    pub manage: bool,
+    #[serde(default)]
+    pub backend: FirewallBackend,
+    // only print the commands instead of running them
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FirewallBackend {
+    #[default]
+    #[serde(rename = "iptables")]
+    Iptables,
+    #[serde(rename = "nftables")]
+    Nftables,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("could not run `{command}`: {source}")]
+    Io { command: String, source: io::Error },
+    #[error("`{command}` failed ({status}): {stderr}")]
+    Failed {
+        command: String,
+        status: std::process::ExitStatus,
+        stderr: String,
+    },
+}
+
+// name of the nftables table we put our rule in, so we can remove everything at once
+const NFT_TABLE: &str = "badscan";
+
+pub struct Firewall {
+    backend: FirewallBackend,
+    ports: PortRange,
+    dry_run: bool,
+    installed: bool,
+}
+
+impl Firewall {
+    pub fn new(config: &FirewallConfig, ports: PortRange) -> Self {
+        Self {
+            backend: config.backend,
+            ports,
+            dry_run: config.dry_run,
+            installed: false,
+        }
+    }
+
+    pub fn install(&mut self) -> Result<(), Error> {
+        match self.backend {
+            FirewallBackend::Iptables => self.run(&self.iptables_rule("-I"), None)?,
+            // nft applies a script as one transaction, so a rule that fails doesn't leave the table and chain behind
+            FirewallBackend::Nftables => {
+                self.run(&to_command(&["nft", "-f", "-"]), Some(&self.nft_script()))?
+            }
+        }
+        self.installed = true;
+        Ok(())
+    }
+
+    pub fn remove(&mut self) -> Result<(), Error> {
+        if !self.installed {
+            return Ok(());
+        }
+        self.installed = false;
+        self.run(&self.remove_command(), None)
+    }
+
+    // checks if a rule dropping our ports is present, this never runs in dry-run mode since it doesn't change anything
+    pub fn is_installed(&self) -> Result<bool, Error> {
+        let command = self.check_command();
+        let output = Command::new(&command[0])
+            .args(&command[1..])
+            .output()
+            .map_err(|source| Error::Io {
+                command: command.join(" "),
+                source,
+            })?;
+        Ok(output.status.success())
+    }
+
+    fn nft_script(&self) -> String {
+        format!(
+            "add table inet {NFT_TABLE}\n\
+             add chain inet {NFT_TABLE} input {{ type filter hook input priority 0 ; }}\n\
+             add rule inet {NFT_TABLE} input tcp dport {}-{} drop\n",
+            self.ports.start, self.ports.end
+        )
+    }
+
+    fn remove_command(&self) -> Vec<String> {
+        match self.backend {
+            FirewallBackend::Iptables => self.iptables_rule("-D"),
+            FirewallBackend::Nftables => to_command(&["nft", "delete", "table", "inet", NFT_TABLE]),
+        }
+    }
+
+    fn check_command(&self) -> Vec<String> {
+        match self.backend {
+            FirewallBackend::Iptables => self.iptables_rule("-C"),
+            FirewallBackend::Nftables => to_command(&["nft", "list", "table", "inet", NFT_TABLE]),
+        }
+    }
+
+    fn iptables_rule(&self, action: &str) -> Vec<String> {
+        to_command(&[
+            "iptables",
+            action,
+            "INPUT",
+            "-p",
+            "tcp",
+            "--dport",
+            &format!("{}:{}", self.ports.start, self.ports.end),
+            "-j",
+            "DROP",
+        ])
+    }
+
+    // `input` is written to the command's stdin
+    fn run(&self, command: &[String], input: Option<&str>) -> Result<(), Error> {
+        let printable = command.join(" ");
+        if self.dry_run {
+            info!("[dry-run] {printable}");
+            if let Some(input) = input {
+                info!("[dry-run] {}", input.trim_end());
+            }
+            return Ok(());
+        }
+
+        info!("Running `{printable}`");
+        let io_error = |source| Error::Io {
+            command: printable.clone(),
+            source,
+        };
+        let mut child = Command::new(&command[0])
+            .args(&command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(io_error)?;
+        // dropping stdin closes it, so the command sees the end of its input
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin
+                .write_all(input.unwrap_or_default().as_bytes())
+                .map_err(io_error)?;
+        }
+        let output = child.wait_with_output().map_err(io_error)?;
+        if !output.status.success() {
+            return Err(Error::Failed {
+                command: printable,
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Firewall {
+    fn drop(&mut self) {
+        if let Err(err) = self.remove() {
+            error!("Could not remove firewall rule: {err}");
+        }
+    }
+}
+
+fn to_command(parts: &[&str]) -> Vec<String> {
+    parts.iter().map(|part| part.to_string()).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn nft_script() {
+        let config = FirewallConfig {
+            backend: FirewallBackend::Nftables,
+            ..Default::default()
+        };
+        let firewall = Firewall::new(
+            &config,
+            PortRange {
+                start: 61000,
+                end: 61009,
+            },
+        );
+        assert_eq!(
+            firewall.nft_script(),
+            "add table inet badscan
+add chain inet badscan input { type filter hook input priority 0 ; }
+add rule inet badscan input tcp dport 61000-61009 drop
+"
+        );
+    }
+}
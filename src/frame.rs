@@ -0,0 +1,415 @@
+use std::net::{IpAddr, SocketAddrV4};
+
+use pnet::{
+    packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols},
+    util::MacAddr,
+};
+use thiserror::Error;
+
+use crate::{
+    interface::MyInterface, neighbours::Neighbours, packet::LinkType, tcp::template::TcpTemplate,
+};
+
+// the frames the send threads send, built without allocating
+// everything that's the same for every probe (the Ethernet header, most of the IP header and the probe itself) is
+// laid out once, and only the addresses, ports, cookie and checksums get patched into the same buffer for every target
+// the checksums start from the sum of everything that doesn't change, so only the patched words get added (RFC 1624)
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const IPV4_HEADER_LEN: usize = 20;
+const UDP_HEADER_LEN: usize = 8;
+
+// offsets in the IP header
+const IP_TOTAL_LENGTH: usize = 2;
+const IP_CHECKSUM: usize = 10;
+const IP_DESTINATION: usize = 16;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(
+        "no MAC to send frames to, the gateway's isn't known and no targets are on our own network"
+    )]
+    NoNextHop,
+}
+
+// what the send threads get queued
+#[derive(Debug)]
+pub enum Outgoing {
+    // a probe, built from the template
+    Probe {
+        dest: SocketAddrV4,
+        source_port: u16,
+        cookie: u32,
+    },
+    // a UDP datagram or TCP segment the receivers answer a server with, only the IP header gets added
+    Segment(SocketAddrV4, Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+pub struct FrameTemplate {
+    protocol: IpNextHeaderProtocol,
+    // where the IP header starts, after the link header if there is one
+    ip: usize,
+    // the destination MAC of frames to hosts on our own network, and to everything else
+    neighbours: Neighbours,
+    gateway: MacAddr,
+    // the frame of the last probe, the fields that differ between targets are overwritten every time
+    probe: Vec<u8>,
+    // where the cookie goes in the transport header and payload
+    cookie_offsets: Vec<usize>,
+    // one's complement sums of everything in the probe's IP header and transport checksum that never changes
+    probe_ip_sum: u32,
+    probe_transport_sum: u32,
+    // the same for the IP header of segments, which also differ in length
+    segment_ip_sum: u32,
+    // the frame of the last segment, its headers get reused
+    segment: Vec<u8>,
+}
+
+impl FrameTemplate {
+    // `payload` is the probe for every target, the cookie gets patched in at `cookie_offsets`
+    pub fn udp(
+        interface: &MyInterface,
+        link_type: LinkType,
+        ttl: u8,
+        payload: &[u8],
+        cookie_offsets: &[usize],
+    ) -> Result<Self, Error> {
+        let length = UDP_HEADER_LEN + payload.len();
+        let mut udp = vec![0; UDP_HEADER_LEN];
+        udp[4..6].copy_from_slice(&(length as u16).to_be_bytes());
+        udp.extend_from_slice(payload);
+        let cookie_offsets = cookie_offsets
+            .iter()
+            .map(|offset| UDP_HEADER_LEN + offset)
+            .collect();
+
+        Self::new(
+            interface,
+            link_type,
+            ttl,
+            IpNextHeaderProtocols::Udp,
+            udp,
+            cookie_offsets,
+        )
+    }
+
+    // a SYN with the cookie as its sequence number
+    pub fn tcp(
+        interface: &MyInterface,
+        link_type: LinkType,
+        ttl: u8,
+        syn: &TcpTemplate,
+    ) -> Result<Self, Error> {
+        Self::new(
+            interface,
+            link_type,
+            ttl,
+            IpNextHeaderProtocols::Tcp,
+            syn.header().to_vec(),
+            vec![4],
+        )
+    }
+
+    fn new(
+        interface: &MyInterface,
+        link_type: LinkType,
+        ttl: u8,
+        protocol: IpNextHeaderProtocol,
+        transport: Vec<u8>,
+        cookie_offsets: Vec<usize>,
+    ) -> Result<Self, Error> {
+        let IpAddr::V4(source_ip) = interface.get_source_ip() else {
+            panic!("No ipv4 source address!")
+        };
+
+        // frames to the gateway (the destination MAC gets patched per target), or bare IP packets when there's no
+        // link header
+        // without a gateway only the hosts on our own network can be reached, and without those nothing can
+        if link_type == LinkType::Ethernet
+            && interface.gateway_mac.is_none()
+            && interface.neighbours.is_empty()
+        {
+            return Err(Error::NoNextHop);
+        }
+        let gateway = interface.gateway_mac.unwrap_or(MacAddr::zero());
+        let mut headers = match link_type {
+            LinkType::Ethernet => {
+                let mut ethernet = Vec::with_capacity(ETHERNET_HEADER_LEN + IPV4_HEADER_LEN);
+                ethernet.extend_from_slice(&gateway.octets());
+                ethernet.extend_from_slice(&interface.mac().octets());
+                ethernet.extend_from_slice(&[0x08, 0x00]);
+                ethernet
+            }
+            LinkType::Ip => Vec::with_capacity(IPV4_HEADER_LEN),
+        };
+        let ip = headers.len();
+        // the header matscan sends: identification 1 (https://github.com/torvalds/linux/blob/master/net/ipv4/ip_output.c#L165)
+        // and DF, please, it would make it so much easier
+        headers.extend_from_slice(&[0x45, 0, 0, 0, 0, 1, 0x40, 0, ttl, protocol.0, 0, 0]);
+        headers.extend_from_slice(&source_ip.octets());
+        headers.extend_from_slice(&[0; 4]);
+        let segment_ip_sum = sum(&headers[ip..], 0);
+
+        let mut probe = headers.clone();
+        let total_length = (IPV4_HEADER_LEN + transport.len()) as u16;
+        probe[ip + IP_TOTAL_LENGTH..ip + IP_TOTAL_LENGTH + 2]
+            .copy_from_slice(&total_length.to_be_bytes());
+        let probe_ip_sum = segment_ip_sum + total_length as u32;
+
+        // the pseudo header without the destination, and the probe without the ports, cookie and checksum
+        let mut transport = transport;
+        let checksum = Self::checksum_offset(protocol);
+        transport[0..4].fill(0);
+        transport[checksum..checksum + 2].fill(0);
+        for &offset in &cookie_offsets {
+            transport[offset..offset + 4].fill(0);
+        }
+        let probe_transport_sum = sum(&source_ip.octets(), 0)
+            + protocol.0 as u32
+            + transport.len() as u32
+            + sum(&transport, 0);
+        probe.extend_from_slice(&transport);
+
+        Ok(Self {
+            protocol,
+            ip,
+            neighbours: interface.neighbours.clone(),
+            gateway,
+            probe,
+            cookie_offsets,
+            probe_ip_sum,
+            probe_transport_sum,
+            segment_ip_sum,
+            segment: headers,
+        })
+    }
+
+    fn checksum_offset(protocol: IpNextHeaderProtocol) -> usize {
+        if protocol == IpNextHeaderProtocols::Udp {
+            6
+        } else {
+            16
+        }
+    }
+
+    // hosts on our own network get their frames directly, everything else goes through the gateway
+    fn next_hop(&self, dest: &SocketAddrV4) -> MacAddr {
+        self.neighbours.get(dest.ip()).unwrap_or(self.gateway)
+    }
+
+    // the probe for `dest`
+    pub fn probe(&mut self, dest: &SocketAddrV4, source_port: u16, cookie: u32) -> &[u8] {
+        let ip = self.ip;
+        let transport = ip + IPV4_HEADER_LEN;
+        let dest_ip = dest.ip().octets();
+        if ip == ETHERNET_HEADER_LEN {
+            let mac = self.next_hop(dest);
+            self.probe[0..6].copy_from_slice(&mac.octets());
+        }
+        let dest_ip_sum = sum(&dest_ip, 0);
+
+        self.probe[ip + IP_DESTINATION..transport].copy_from_slice(&dest_ip);
+        let checksum = !fold(self.probe_ip_sum + dest_ip_sum);
+        self.probe[ip + IP_CHECKSUM..ip + IP_CHECKSUM + 2].copy_from_slice(&checksum.to_be_bytes());
+
+        let segment = &mut self.probe[transport..];
+        segment[0..2].copy_from_slice(&source_port.to_be_bytes());
+        segment[2..4].copy_from_slice(&dest.port().to_be_bytes());
+        let mut transport_sum =
+            self.probe_transport_sum + dest_ip_sum + source_port as u32 + dest.port() as u32;
+        for &offset in &self.cookie_offsets {
+            segment[offset..offset + 4].copy_from_slice(&cookie.to_be_bytes());
+            transport_sum += sum(&segment[offset..offset + 4], offset);
+        }
+        let mut checksum = !fold(transport_sum);
+        // a zero checksum means there is none in UDP
+        if checksum == 0 && self.protocol == IpNextHeaderProtocols::Udp {
+            checksum = 0xffff;
+        }
+        let offset = Self::checksum_offset(self.protocol);
+        segment[offset..offset + 2].copy_from_slice(&checksum.to_be_bytes());
+
+        &self.probe
+    }
+
+    // `segment` to `dest`, in an IP packet (and Ethernet frame) of the scanner's protocol
+    pub fn segment(&mut self, dest: &SocketAddrV4, segment: &[u8]) -> &[u8] {
+        let ip = self.ip;
+        let transport = ip + IPV4_HEADER_LEN;
+        let dest_ip = dest.ip().octets();
+        let total_length = (IPV4_HEADER_LEN + segment.len()) as u16;
+        if ip == ETHERNET_HEADER_LEN {
+            let mac = self.next_hop(dest);
+            self.segment[0..6].copy_from_slice(&mac.octets());
+        }
+
+        self.segment.truncate(transport);
+        self.segment[ip + IP_TOTAL_LENGTH..ip + IP_TOTAL_LENGTH + 2]
+            .copy_from_slice(&total_length.to_be_bytes());
+        self.segment[ip + IP_DESTINATION..transport].copy_from_slice(&dest_ip);
+        let checksum = !fold(self.segment_ip_sum + total_length as u32 + sum(&dest_ip, 0));
+        self.segment[ip + IP_CHECKSUM..ip + IP_CHECKSUM + 2]
+            .copy_from_slice(&checksum.to_be_bytes());
+        self.segment.extend_from_slice(segment);
+
+        &self.segment
+    }
+}
+
+// the one's complement sum of `data`, which starts `offset` bytes into what's being checksummed
+// the carries are only folded in at the end, which works as long as there are fewer than 65536 words
+fn sum(data: &[u8], offset: usize) -> u32 {
+    data.iter()
+        .enumerate()
+        .map(|(i, &byte)| {
+            if (offset + i).is_multiple_of(2) {
+                (byte as u32) << 8
+            } else {
+                byte as u32
+            }
+        })
+        .sum()
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use pnet::datalink::NetworkInterface;
+    use pnet::{ipnetwork::IpNetwork, util::MacAddr};
+
+    use super::*;
+    use crate::{
+        fingerprint::Fingerprint,
+        packet::{self, Transport},
+        protocols::raknet,
+    };
+
+    fn interface() -> MyInterface {
+        MyInterface {
+            network_interface: NetworkInterface {
+                name: "test".to_string(),
+                description: String::new(),
+                index: 1,
+                mac: Some(MacAddr(2, 0, 0, 0, 0, 1)),
+                ips: vec![IpNetwork::V4("10.0.0.2/24".parse().unwrap())],
+                flags: 0,
+            },
+            gateway_mac: Some(MacAddr(2, 0, 0, 0, 0, 2)),
+            gateway_ip: Some("10.0.0.1".parse().unwrap()),
+            neighbours: Neighbours::default(),
+        }
+    }
+
+    #[test]
+    fn udp_probes() {
+        let payload = raknet::initial_packet(&"0.0.0.0:0".parse().unwrap(), 0);
+        let mut template = FrameTemplate::udp(
+            &interface(),
+            LinkType::Ethernet,
+            64,
+            &payload,
+            raknet::COOKIE_OFFSETS,
+        )
+        .unwrap();
+
+        // every probe has to come out right, not just the first one patched into the template
+        for (dest, cookie) in [
+            ("1.2.3.4:19132", 0xdeadbeef),
+            ("255.255.255.254:1", 0xffffffff),
+            ("0.0.0.1:65535", 0),
+        ] {
+            let dest: SocketAddrV4 = dest.parse().unwrap();
+            let frame = template.probe(&dest, 61234, cookie);
+            let segment = packet::parse(frame).unwrap().unwrap();
+            assert_eq!(segment.source, "10.0.0.2:61234".parse().unwrap());
+            assert_eq!(segment.destination, dest);
+            let Transport::Udp { payload } = segment.transport else {
+                panic!("not UDP");
+            };
+            assert_eq!(payload, raknet::initial_packet(&dest, cookie));
+        }
+    }
+
+    #[test]
+    fn tcp_probes() {
+        let fingerprint = Fingerprint::nintendo_3ds();
+        let mut template =
+            FrameTemplate::tcp(&interface(), LinkType::Ip, 64, &fingerprint.get_syn()).unwrap();
+
+        for cookie in [1, 0x80000000, 0xfffffffe] {
+            let dest = "192.168.1.1:25565".parse().unwrap();
+            let frame = template.probe(&dest, 61000, cookie);
+            let segment = packet::parse_link(frame, LinkType::Ip).unwrap().unwrap();
+            assert_eq!(segment.identification, 1);
+            let Transport::Tcp(tcp) = segment.transport else {
+                panic!("not TCP");
+            };
+            assert_eq!(tcp.sequence, cookie);
+            assert_eq!(tcp.acknowledgement, 0);
+        }
+    }
+
+    #[test]
+    fn segments() {
+        let mut template =
+            FrameTemplate::udp(&interface(), LinkType::Ethernet, 64, &[], &[]).unwrap();
+        let source = "10.0.0.2:61000".parse().unwrap();
+        for (dest, data) in [("1.1.1.1:25565", &b"hello"[..]), ("8.8.4.4:53", &b""[..])] {
+            let dest = dest.parse().unwrap();
+            let udp = crate::utils::wrap_udp(data.to_vec(), &source, &dest);
+            let frame = template.segment(&dest, &udp);
+            let segment = packet::parse(frame).unwrap().unwrap();
+            assert_eq!(segment.destination, dest);
+            assert!(matches!(segment.transport, Transport::Udp { payload } if payload == data));
+        }
+    }
+
+    #[test]
+    fn on_link_targets() {
+        let mut interface = interface();
+        let neighbour = MacAddr(2, 0, 0, 0, 0, 5);
+        interface.neighbours = Neighbours::new(
+            "10.0.0.0/24".parse().unwrap(),
+            HashMap::from([("10.0.0.5".parse().unwrap(), neighbour)]),
+        );
+        let mut template =
+            FrameTemplate::udp(&interface, LinkType::Ethernet, 64, &[], &[]).unwrap();
+
+        // the frame to the neighbour goes straight to it, the next ones through the gateway again
+        let frame = template.probe(&"10.0.0.5:25565".parse().unwrap(), 61000, 0);
+        assert_eq!(frame[0..6], neighbour.octets());
+        let frame = template.probe(&"10.0.0.6:25565".parse().unwrap(), 61000, 0);
+        assert_eq!(frame[0..6], [2, 0, 0, 0, 0, 2]);
+        let frame = template.segment(&"10.0.0.5:25565".parse().unwrap(), &[0; 8]);
+        assert_eq!(frame[0..6], neighbour.octets());
+        let frame = template.segment(&"1.1.1.1:25565".parse().unwrap(), &[0; 8]);
+        assert_eq!(frame[0..6], [2, 0, 0, 0, 0, 2]);
+    }
+
+    #[test]
+    fn no_next_hop() {
+        let mut interface = interface();
+        interface.gateway_mac = None;
+        assert!(matches!(
+            FrameTemplate::udp(&interface, LinkType::Ethernet, 64, &[], &[]),
+            Err(Error::NoNextHop)
+        ));
+        // bare IP packets don't need one
+        assert!(FrameTemplate::udp(&interface, LinkType::Ip, 64, &[], &[]).is_ok());
+
+        interface.neighbours = Neighbours::new(
+            "10.0.0.0/24".parse().unwrap(),
+            HashMap::from([("10.0.0.5".parse().unwrap(), MacAddr(2, 0, 0, 0, 0, 5))]),
+        );
+        assert!(FrameTemplate::udp(&interface, LinkType::Ethernet, 64, &[], &[]).is_ok());
+    }
+}
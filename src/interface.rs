@@ -1,59 +1,166 @@
-use std::net::IpAddr;
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr},
+};
 
 use pnet::{
-    datalink::{self, DataLinkSender, NetworkInterface},
-    packet::{
-        ethernet::{EtherType, MutableEthernetPacket},
-        Packet,
-    },
+    datalink::{self, NetworkInterface},
+    ipnetwork::{IpNetwork, Ipv4Network},
     util::MacAddr,
 };
 
+use crate::{
+    backend::{
+        tun::{TunMode, TAP_MAC},
+        Backend,
+    },
+    neighbours::{self, ArpConfig, Neighbours},
+};
+
 #[derive(Debug, Clone)]
 pub struct MyInterface {
     pub network_interface: NetworkInterface,
+    // where frames to everything outside our own network go, None when it's not resolved (yet)
     pub gateway_mac: Option<MacAddr>,
+    pub gateway_ip: Option<Ipv4Addr>,
+    // the hosts on our own network, which get their frames directly
+    pub neighbours: Neighbours,
 }
 
 impl MyInterface {
-    pub fn get_default() -> Self {
-        let default_interface =
-            default_net::get_default_interface().expect("Could not get default interface");
+    pub fn get_default() -> Result<Self, String> {
+        let default_interface = default_net::get_default_interface()?;
         Self::from_default(&default_interface)
     }
 
-    pub fn from_name(name: &str) -> Self {
+    pub fn from_name(name: &str) -> Result<Self, String> {
         let interfaces = default_net::get_interfaces();
         let default_interface = interfaces
             .iter()
             .find(|interface| interface.name == name)
-            .expect("Could not find interface");
+            .ok_or_else(|| format!("no interface called `{name}`"))?;
         Self::from_default(default_interface)
     }
 
-    fn from_default(default_interface: &default_net::Interface) -> Self {
+    fn from_default(default_interface: &default_net::Interface) -> Result<Self, String> {
         let Some(mac) = default_interface.mac_addr else {
-            panic!("Interface doesn't have a MAC address!")
+            return Err(format!(
+                "`{}` doesn't have a MAC address",
+                default_interface.name
+            ));
         };
         let mac = convert_mac(mac);
+        // default_net leaves out the gateway entirely when the kernel doesn't have its MAC cached
         let gateway_mac = default_interface
-            .clone()
             .gateway
-            .map(|gateway| convert_mac(gateway.mac_addr));
+            .as_ref()
+            .map(|gateway| convert_mac(gateway.mac_addr))
+            .filter(|mac| !mac.is_zero());
+        let gateway_ip = match default_interface
+            .gateway
+            .as_ref()
+            .map(|gateway| gateway.ip_addr)
+        {
+            Some(IpAddr::V4(ip)) => Some(ip),
+            _ => neighbours::default_gateway(&default_interface.name),
+        };
 
         // turn into pnet's NetworkInterface
         let network_interface = datalink::interfaces()
-            .iter()
+            .into_iter()
             .find(|interface| interface.mac == Some(mac))
-            .expect("Could not get interface")
-            .to_owned();
+            .ok_or_else(|| format!("`{}` has no datalink interface", default_interface.name))?;
+
+        Ok(MyInterface {
+            network_interface,
+            gateway_mac,
+            gateway_ip,
+            neighbours: Neighbours::default(),
+        })
+    }
+
+    // we're a host behind the device, not the device itself
+    // a TUN device has no MACs at all, on a TAP device the device is our gateway
+    pub fn from_tun(device: &str, mode: TunMode, address: Ipv4Addr) -> Self {
+        let mut network_interface = datalink::interfaces()
+            .into_iter()
+            .find(|interface| interface.name == device)
+            .expect("Could not find TUN/TAP device");
+        network_interface.ips = vec![IpNetwork::V4(Ipv4Network::from(address))];
+        let gateway_mac = match mode {
+            TunMode::Tun => None,
+            TunMode::Tap => network_interface.mac,
+        };
+        network_interface.mac = match mode {
+            TunMode::Tun => None,
+            TunMode::Tap => Some(TAP_MAC),
+        };
 
         MyInterface {
             network_interface,
             gateway_mac,
+            gateway_ip: None,
+            neighbours: Neighbours::default(),
         }
     }
 
+    // fills in the MACs we send frames to with ARP: the gateway's when it's not configured and default_net didn't
+    // know it, and those of the targets in `on_link` (which are on our own network, so not behind the gateway)
+    // whatever the kernel already has cached isn't asked for again
+    pub fn resolve_neighbours(
+        &mut self,
+        backend: &dyn Backend,
+        config: &ArpConfig,
+        on_link: &[Ipv4Addr],
+    ) -> io::Result<()> {
+        let mut known = neighbours::kernel_table(&self.network_interface.name);
+        if let Some(next_hop) = config.next_hop {
+            self.gateway_mac = Some(next_hop.0);
+        }
+        let gateway = self
+            .gateway_ip
+            .filter(|_| self.gateway_mac.is_none())
+            .filter(|ip| !known.contains_key(ip));
+
+        let unknown: Vec<_> = on_link
+            .iter()
+            .chain(&gateway)
+            .filter(|ip| !known.contains_key(ip))
+            .copied()
+            .collect();
+        let IpAddr::V4(source_ip) = self.get_source_ip() else {
+            unreachable!()
+        };
+        known.extend(neighbours::resolve(
+            backend,
+            self.mac(),
+            source_ip,
+            &unknown,
+            config,
+        )?);
+
+        if self.gateway_mac.is_none() {
+            self.gateway_mac = self.gateway_ip.and_then(|ip| known.get(&ip).copied());
+        }
+        if let Some(network) = self.network() {
+            let on_link: HashMap<_, _> = on_link
+                .iter()
+                .filter_map(|ip| Some((*ip, *known.get(ip)?)))
+                .collect();
+            self.neighbours = Neighbours::new(network, on_link);
+        }
+        Ok(())
+    }
+
+    // the network we're on, everything in it can be reached without the gateway
+    pub fn network(&self) -> Option<Ipv4Network> {
+        self.network_interface.ips.iter().find_map(|ip| match ip {
+            IpNetwork::V4(network) => Some(*network),
+            IpNetwork::V6(_) => None,
+        })
+    }
+
     pub fn get_source_ip(&self) -> IpAddr {
         self.network_interface
             .ips
@@ -66,31 +173,6 @@ impl MyInterface {
     pub fn mac(&self) -> MacAddr {
         self.network_interface.mac.unwrap()
     }
-
-    pub fn send_packet(
-        &self,
-        tx: &mut Box<dyn DataLinkSender>,
-        packet: &[u8],
-        ethertype: EtherType,
-    ) {
-        // wrap into ethernet
-        let packet = match self.gateway_mac {
-            Some(dest) => {
-                let mut ethernet_buf = vec![0u8; packet.len() + 20]; // ethernet header should be 20 bytes
-
-                let mut ethernet_packet = MutableEthernetPacket::new(&mut ethernet_buf).unwrap();
-                ethernet_packet.set_destination(dest);
-                ethernet_packet.set_source(self.network_interface.mac.unwrap());
-                ethernet_packet.set_ethertype(ethertype);
-                ethernet_packet.set_payload(packet);
-
-                ethernet_packet.packet().to_vec()
-            }
-            None => packet.to_vec(),
-        };
-
-        tx.send_to(&packet, None);
-    }
 }
 
 // helper function
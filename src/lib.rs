@@ -1,10 +1,30 @@
 // using this lib.rs file makes it so the entire project (excluding main.rs) becomes a library that main.rs depends on
 // this avoids having to write these mod statements in the main.rs file
 // (yes it's purely asthetic)
+pub mod backend;
+pub mod capture;
+pub mod checkpoint;
+pub mod cli;
 pub mod config;
+pub mod cookie;
+pub mod exchanges;
 pub mod fingerprint;
+pub mod firewall;
+pub mod frame;
 pub mod interface;
+pub mod logging;
+pub mod metrics;
+pub mod neighbours;
+pub mod output;
+pub mod packet;
+pub mod pcap;
 pub mod protocols;
+pub mod receiver;
+pub mod replay;
+pub mod scanner;
+pub mod sender;
+pub mod stats;
+pub mod targets;
 pub mod tcp;
 pub mod tcpscanner;
 pub mod udpscanner;
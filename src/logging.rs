@@ -0,0 +1,119 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_derive::Deserialize;
+use tracing_subscriber::{fmt, EnvFilter};
+
+#[derive(Deserialize, Debug)]
+pub struct LoggingConfig {
+    // filter directives, either a plain level or per-module ones like "badscan=info,badscan::tcpscanner=debug"
+    // the RUST_LOG environment variable takes priority over this
+    #[serde(default = "default_filter")]
+    pub filter: String,
+    #[serde(default)]
+    pub format: LogFormat,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    #[serde(rename = "text")]
+    Text,
+    #[serde(rename = "json")]
+    Json,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            filter: default_filter(),
+            format: LogFormat::default(),
+        }
+    }
+}
+
+fn default_filter() -> String {
+    "info".to_string()
+}
+
+pub fn init(config: &LoggingConfig) {
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(&config.filter))
+        .unwrap_or_else(|err| {
+            eprintln!("Invalid log filter `{}`: {err}", config.filter);
+            EnvFilter::new(default_filter())
+        });
+
+    let builder = fmt().with_env_filter(filter);
+    let result = match config.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    };
+    // this fails when a subscriber was already set, which is fine
+    let _ = result;
+}
+
+// lets at most one message through every `interval`, counting the ones it swallowed
+// meant for things the internet can trigger at will, like malformed or spoofed packets
+pub struct RateLimiter {
+    interval_ms: u64,
+    last_ms: AtomicU64,
+    suppressed: AtomicU64,
+}
+
+impl RateLimiter {
+    pub const fn new(interval: Duration) -> Self {
+        Self {
+            interval_ms: interval.as_millis() as u64,
+            last_ms: AtomicU64::new(0),
+            suppressed: AtomicU64::new(0),
+        }
+    }
+
+    // returns how many messages were suppressed since the last one if this one may be logged
+    pub fn check(&self) -> Option<u64> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let last = self.last_ms.load(Ordering::Relaxed);
+        if now.saturating_sub(last) < self.interval_ms
+            || self
+                .last_ms
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(self.suppressed.swap(0, Ordering::Relaxed))
+    }
+}
+
+// like tracing::warn!, but at most once per second per call site
+#[macro_export]
+macro_rules! warn_limited {
+    ($($arg:tt)+) => {{
+        static LIMITER: $crate::logging::RateLimiter =
+            $crate::logging::RateLimiter::new(::std::time::Duration::from_secs(1));
+        if let Some(suppressed) = LIMITER.check() {
+            ::tracing::warn!(suppressed, $($arg)+);
+        }
+    }};
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_suppresses() {
+        let limiter = RateLimiter::new(Duration::from_secs(3600));
+        assert_eq!(limiter.check(), Some(0));
+        assert_eq!(limiter.check(), None);
+        assert_eq!(limiter.check(), None);
+        assert_eq!(limiter.suppressed.load(Ordering::Relaxed), 2);
+    }
+}
@@ -1,35 +1,249 @@
 use std::{
+    collections::BTreeSet,
     net::SocketAddrV4,
-    sync::{Arc, RwLock},
+    path::Path,
+    process,
+    sync::{atomic::Ordering, Arc, Mutex, MutexGuard, PoisonError},
     thread,
     time::Duration,
 };
 
+use chrono::{TimeZone, Utc};
+use clap::Parser;
+use pnet::datalink;
+use tracing::{error, info, warn};
+
 use badscan::{
-    config::{self, CONFIG},
+    backend::{
+        datalink::PnetBackend, ring::RingBackend, tun::TunBackend, xdp::XdpBackend, Backend,
+    },
+    capture::Capture,
+    checkpoint::{Checkpoint, Checkpointer},
+    cli::{Cli, Command, ScanArgs},
+    config::{self, Config},
+    cookie::Cookies,
     fingerprint,
+    firewall::Firewall,
     interface::MyInterface,
+    logging,
+    metrics::MetricsServer,
+    output::{self, Output, Response, ScanResult},
+    pcap::PcapReader,
     protocols::{
         self,
-        query::QueryResponse,
-        raknet::RaknetReponse,
-        slp::MinecraftSlpProtocol,
+        slp::{MinecraftSlpProtocol, SlpState},
     },
-    tcpscanner::TcpScanner,
-    udpscanner::UdpScanner,
+    replay,
+    scanner::ScannerBuilder,
+    sender::Senders,
+    stats::{Stats, StatusReporter},
+    targets::{TargetIter, TargetSpace},
 };
 
 fn main() {
-    println!("Starting BadScan");
+    let cli = Cli::parse();
+    let command = cli
+        .command
+        .unwrap_or_else(|| Command::Scan(ScanArgs::default()));
+
+    match command {
+        Command::Scan(args) => {
+            let config = load_config(&cli.config, &args);
+            scan(config, args.resume, None);
+        }
+        Command::Rescan { results, args } => {
+            let config = load_config(&cli.config, &args);
+            let results = output::read_results(&results).unwrap_or_else(|err| {
+                eprintln!("Could not read results: {err}");
+                process::exit(1);
+            });
+            // only the addresses that answered before, and every one of them once
+            let addrs: BTreeSet<SocketAddrV4> = results.iter().map(|result| result.addr).collect();
+            scan(config, args.resume, Some(addrs.into_iter().collect()));
+        }
+        Command::Merge { output, inputs } => match output::merge(&inputs, &output) {
+            Ok(count) => println!("Merged {count} results into {}", output.display()),
+            Err(err) => {
+                eprintln!("Could not merge results: {err}");
+                process::exit(1);
+            }
+        },
+        Command::Replay {
+            capture,
+            seed,
+            start_time,
+            protocol,
+        } => {
+            let args = ScanArgs {
+                protocol,
+                ..Default::default()
+            };
+            let config = load_config(&cli.config, &args);
+            replay(config, &capture, seed, start_time);
+        }
+        Command::ListInterfaces => list_interfaces(),
+        Command::ListFingerprints => {
+            for fingerprint in config::Fingerprint::ALL {
+                println!("{fingerprint}");
+            }
+        }
+        Command::ValidateConfig => validate_config(&cli.config),
+    }
+}
+
+fn load_config(path: &Path, args: &ScanArgs) -> Config {
+    let mut config = Config::load(path).unwrap_or_else(|err| {
+        eprintln!("Could not load config `{}`: {err}", path.display());
+        process::exit(1);
+    });
+    args.apply(&mut config);
+    config
+}
+
+fn list_interfaces() {
+    let default = default_net::get_default_interface().ok();
+    for interface in datalink::interfaces() {
+        let is_default = default
+            .as_ref()
+            .is_some_and(|default| default.name == interface.name);
+        println!(
+            "{}{}: mac {}, ips [{}]",
+            interface.name,
+            if is_default { " (default)" } else { "" },
+            interface
+                .mac
+                .map(|mac| mac.to_string())
+                .unwrap_or_else(|| "none".to_string()),
+            interface
+                .ips
+                .iter()
+                .map(|ip| ip.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+}
+
+fn validate_config(path: &Path) {
+    let config = load_config(path, &ScanArgs::default());
+    let ports = if config.scan.ports.is_empty() {
+        vec![config.protocol.default_port()]
+    } else {
+        config.scan.ports.clone()
+    };
+    match TargetSpace::new(config.scan.targets.clone(), ports) {
+        Ok(space) => {
+            println!("Config is valid");
+            println!("Protocol: {:?}", config.protocol);
+            println!("Targets: {}", space.len());
+            println!("Source ports: {}", config.scan.source_ports);
+        }
+        Err(err) => {
+            eprintln!("Invalid scan targets: {err}");
+            process::exit(1);
+        }
+    }
+}
+
+// errors are returned instead of exiting here, the firewall rule is already installed
+fn resolve_neighbours(
+    interface: &mut MyInterface,
+    config: &Config,
+    space: &TargetSpace,
+) -> Result<(), String> {
+    info!("Resolving gateway...");
+    let on_link = interface
+        .network()
+        .and_then(|network| space.addresses_within(network, config.arp.max_neighbours))
+        .unwrap_or_else(|| {
+            warn!("Too many targets on our own network to resolve, sending them to the gateway");
+            Vec::new()
+        });
+    // AF_XDP hands ARP replies to the kernel, so we always ask through a plain packet socket
+    let backend = PnetBackend::new(interface.network_interface.clone());
+    interface
+        .resolve_neighbours(&backend, &config.arp, &on_link)
+        .map_err(|err| format!("Could not resolve neighbours: {err}"))?;
+
+    match (interface.gateway_ip, interface.gateway_mac) {
+        (_, Some(mac)) => info!(
+            "Using gateway {} ({mac})",
+            interface
+                .gateway_ip
+                .map_or_else(|| "from config".to_string(), |ip| ip.to_string())
+        ),
+        (Some(ip), None) => {
+            return Err(format!(
+                "Gateway {ip} did not answer ARP requests, set `next_hop` in [arp] to its MAC"
+            ))
+        }
+        (None, None) => warn!("No gateway found, only targets on our own network can be reached"),
+    }
+    if !on_link.is_empty() {
+        info!(
+            "Resolved {}/{} targets on our own network",
+            interface.neighbours.len(),
+            on_link.len()
+        );
+    }
+    Ok(())
+}
+
+fn scan(config: Config, resume: bool, list: Option<Vec<SocketAddrV4>>) {
+    logging::init(&config.logging);
+    info!("Starting BadScan");
 
     // get interface to use
-    println!("Getting interface...");
-    let interface = match &CONFIG.interface {
-        Some(interface) => MyInterface::from_name(&interface),
-        None => MyInterface::get_default(),
+    info!("Getting interface...");
+    let (mut interface, backend): (_, Arc<dyn Backend>) = match &config.tun.device {
+        Some(device) => {
+            let backend =
+                TunBackend::attach(device, config.tun.mode).expect("Could not open TUN/TAP device");
+            // checked when loading the config
+            let address = config.tun.address.unwrap();
+            (
+                MyInterface::from_tun(device, config.tun.mode, address),
+                Arc::new(backend),
+            )
+        }
+        None => {
+            let interface = match &config.interface {
+                Some(interface) => MyInterface::from_name(interface),
+                None => MyInterface::get_default(),
+            }
+            .unwrap_or_else(|err| {
+                error!("Could not get interface: {err}");
+                process::exit(1);
+            });
+            let backend: Arc<dyn Backend> = if config.xdp.enabled {
+                let backend = XdpBackend::new(
+                    &interface.network_interface,
+                    &config.xdp,
+                    config.scan.source_ports,
+                )
+                .expect("Could not set up AF_XDP socket");
+                info!(
+                    "Using AF_XDP socket in {} mode",
+                    if backend.zero_copy() {
+                        "zero-copy"
+                    } else {
+                        "copy"
+                    }
+                );
+                Arc::new(backend)
+            } else if config.ring.enabled {
+                Arc::new(
+                    RingBackend::new(interface.network_interface.clone(), &config.ring)
+                        .expect("Could not set up packet rings"),
+                )
+            } else {
+                Arc::new(PnetBackend::new(interface.network_interface.clone()))
+            };
+            (interface, backend)
+        }
     };
 
-    println!(
+    info!(
         "Using interface `{}`: {}",
         interface.network_interface.name,
         interface
@@ -42,127 +256,337 @@ fn main() {
     );
 
     // select protocol
-    println!("Selecting protocol...");
-    let protocol: Arc<RwLock<protocols::Protocol<T>>> = Default::default();
-    set_protocol(protocol.clone(), &CONFIG.protocol);
+    info!("Selecting protocol...");
+    let stats = Arc::new(Stats::default());
+    let output = Output::from_config(&config.output).expect("Could not open output");
+    let protocol = make_protocol(&config.protocol, output.clone(), stats.clone());
     // select fingerprint
-    let fingerprint: Arc<RwLock<fingerprint::Fingerprint>> = Default::default();
-    set_fingerprint(fingerprint.clone(), &CONFIG.fingerprint);
+    let fingerprint = make_fingerprint(&config.fingerprint);
 
-    println!(
+    info!(
         "Using protocol: {} with fingerprint {:?}",
-        protocol.read().unwrap(),
-        CONFIG.fingerprint
+        protocol, config.fingerprint
     );
 
-    let ranges = SocketAddrV4::new(
-        "192.168.2.120".parse().unwrap(),
-        protocol.read().unwrap().default_port(),
+    // make sure the kernel doesn't RST the connections we make
+    // this only checks that a rule exists, whether it works shows once the scan runs: the receivers count every RST
+    // the kernel sends from our ports (kernel_rsts), and complain as soon as they see one
+    // behind a TUN/TAP device the kernel doesn't own our address, so it has nothing to RST there
+    // and with AF_XDP it never even sees the replies
+    // shared with the Ctrl-C handler, which has to remove the rule before quitting too
+    let firewall = Arc::new(Mutex::new(Firewall::new(
+        &config.firewall,
+        config.scan.source_ports,
+    )));
+    if config.firewall.manage {
+        info!("Installing firewall rule...");
+        lock(&firewall)
+            .install()
+            .expect("Could not install firewall rule");
+    } else if matches!(protocol, protocols::Protocol::Tcp(_))
+        && config.tun.device.is_none()
+        && !config.xdp.enabled
+    {
+        match lock(&firewall).is_installed() {
+            Ok(true) => {}
+            Ok(false) => warn!(
+                "No firewall rule drops TCP ports {}, the kernel will RST our connections! (see README or set firewall.manage)",
+                config.scan.source_ports
+            ),
+            Err(err) => error!("Could not check firewall rule: {err}"),
+        }
+    }
+
+    // select targets
+    let space = match list {
+        Some(addrs) => TargetSpace::from_list(addrs),
+        None => {
+            let ports = if config.scan.ports.is_empty() {
+                vec![protocol.default_port()]
+            } else {
+                config.scan.ports.clone()
+            };
+            TargetSpace::new(config.scan.targets.clone(), ports)
+        }
+    }
+    .expect("Invalid scan targets");
+
+    // find out where to send our frames, behind a TUN/TAP device that's always the device
+    if config.tun.device.is_none() {
+        if let Err(err) = resolve_neighbours(&mut interface, &config, &space) {
+            error!("{err}");
+            exit(&firewall, 1);
+        }
+    }
+
+    // resume from the checkpoint if requested
+    let new_checkpoint = Checkpoint::new(
+        config.scan.seed,
+        Utc::now().timestamp_millis(),
+        &space,
+        config.scan.source_ports,
+        config.scan.shard,
     );
+    let checkpoint = if resume {
+        let Some(path) = &config.scan.checkpoint else {
+            error!("--resume needs a checkpoint file to be configured");
+            exit(&firewall, 1);
+        };
+        let checkpoint = Checkpoint::load(Path::new(path))
+            .and_then(|checkpoint| checkpoint.check(&new_checkpoint).map(|_| checkpoint))
+            .unwrap_or_else(|err| {
+                error!("Could not resume from checkpoint `{path}`: {err}");
+                exit(&firewall, 1);
+            });
+        info!(
+            "Resuming scan at target {}/{}",
+            checkpoint.position, checkpoint.total
+        );
+        checkpoint
+    } else {
+        new_checkpoint
+    };
+    // checked above for resumed scans, and we just made the others
+    let start_time = checkpoint.start_time().unwrap();
+    let mut targets = TargetIter::new(space, config.scan.seed);
+    if let Some(shard) = config.scan.shard {
+        targets = targets.shard(shard);
+        info!("Scanning shard {shard} ({} targets)", targets.total());
+    }
+    let targets = targets.resume(checkpoint.position);
+    stats
+        .targets_total
+        .store(targets.total(), Ordering::Relaxed);
+    stats.targets_done.store(targets.done(), Ordering::Relaxed);
+
+    if config.metrics.enabled {
+        MetricsServer::bind(config.metrics.listen, stats.clone())
+            .expect("Could not start metrics server")
+            .spawn();
+        info!(
+            "Serving metrics on http://{}/metrics",
+            config.metrics.listen
+        );
+    }
+
+    if config.scan.status_interval > 0 {
+        StatusReporter::new(
+            stats.clone(),
+            Duration::from_secs(config.scan.status_interval),
+        )
+        .spawn();
+    }
+    let mut checkpointer = config.scan.checkpoint.as_ref().map(|path| {
+        Checkpointer::new(
+            path.into(),
+            Duration::from_secs(config.scan.checkpoint_interval),
+            checkpoint,
+        )
+    });
 
     // create scanner
-    let lock = protocol.read().unwrap();
-    match &*lock {
-        protocols::Protocol::Udp(proto) => {
-            let mut scanner =
-                UdpScanner::new(&interface, proto.clone(), &fingerprint.read().unwrap());
-            println!(
-                "Scanning started at {}",
-                scanner.start_time.format("%H:%M %d-%m-%Y UTC")
-            );
+    let interface_name = interface.network_interface.name.clone();
+    let link_type = backend.link_type();
+    let mut builder = ScannerBuilder::new(protocol)
+        .interface(interface)
+        .backend(backend)
+        .fingerprint(fingerprint)
+        .seed(config.scan.seed)
+        .start_time(start_time)
+        .source_ports(config.scan.source_ports)
+        .senders(config.scan.senders)
+        .send_cpus(config.scan.send_cpus.clone())
+        .queue_size(config.scan.queue_size)
+        .workers(config.scan.workers)
+        .cooldown(config.scan.cooldown())
+        .stats(stats.clone())
+        .output(output);
+    if let Some(rate) = config.scan.rate {
+        builder = builder.rate(rate);
+    }
+    if let Some(capture) = Capture::from_config(&config.capture, &interface_name, link_type)
+        .expect("Could not create capture file")
+    {
+        info!(
+            "Capturing to {}",
+            config.capture.file.as_deref().unwrap_or_default()
+        );
+        builder = builder.capture(capture);
+    }
+    let scanner = builder.build().unwrap_or_else(|err| {
+        error!("Could not create scanner: {err}");
+        exit(&firewall, 1);
+    });
+    info!(
+        "Scanning started at {}",
+        scanner.start_time().format("%H:%M %d-%m-%Y UTC")
+    );
 
-            scanner.scan(ranges);
-        }
-        protocols::Protocol::Tcp(proto) => {
-            let mut scanner =
-                TcpScanner::new(&interface, proto.clone(), &*fingerprint.read().unwrap());
-            println!(
-                "TCP Scanning started at {}",
-                scanner.start_time.format("%H:%M %d-%m-%Y UTC")
-            );
+    // the first Ctrl-C stops sending but still waits for replies, like a normal end of the scan
+    let shutdown = scanner.shutdown();
+    {
+        let shutdown = shutdown.clone();
+        let firewall = firewall.clone();
+        ctrlc::set_handler(move || match shutdown.stop() {
+            1 => warn!("Stopping, press Ctrl-C again to skip waiting for replies"),
+            2 => warn!("Skipping the cooldown, press Ctrl-C again to quit immediately"),
+            _ => exit(&firewall, 130),
+        })
+        .expect("Could not set Ctrl-C handler");
+    }
 
-            scanner.scan(ranges);
-        }
+    // the Ctrl-C handler keeps the firewall alive from here on, so a panic wouldn't remove the rule anymore
+    let senders = scanner.scan_all(targets).unwrap_or_else(|err| {
+        error!("Could not start send threads: {err}");
+        exit(&firewall, 1);
+    });
+    scan_targets(senders, &mut checkpointer);
+
+    if shutdown.is_stopped() {
+        info!("Scan interrupted");
+    } else {
+        info!("All probes sent");
+    }
+    scanner.finish();
+    let kernel_rsts = Stats::get(&stats.kernel_rsts);
+    if kernel_rsts > 0 {
+        error!("The kernel sent {kernel_rsts} RSTs from our scanning ports, the firewall rule isn't working (see README)");
+    }
+    if let Err(err) = lock(&firewall).remove() {
+        error!("Could not remove firewall rule: {err}");
+    }
+    if let Some(err) = shutdown.error() {
+        error!("Scan failed: {err}");
+        process::exit(1);
     }
+    info!("Done");
+}
 
-    println!("Scanner done, waiting for the last packets...");
-    thread::sleep(Duration::from_secs(CONFIG.scan.wait_delay));
-    println!("Done");
+// process::exit skips the destructors, so the firewall rule has to go first
+fn exit(firewall: &Mutex<Firewall>, code: i32) -> ! {
+    if let Err(err) = lock(firewall).remove() {
+        error!("Could not remove firewall rule: {err}");
+    }
+    process::exit(code);
 }
 
-fn set_protocol<T>(lock: Arc<RwLock<protocols::Protocol<T>>>, protocol: &config::Protocol) {
-    let mut lock = lock.write().unwrap();
-    *lock = match protocol {
-        &config::Protocol::Raknet => {
-            protocols::Protocol::Udp(Arc::new(protocols::UdpProtocol::Raknet {
-                callback: Box::new(handle_raknet),
-            }))
-        }
-        &config::Protocol::Query { fullstat } => {
-            protocols::Protocol::Udp(Arc::new(protocols::UdpProtocol::McQuery {
-                callback: Box::new(handle_query),
-                fullstat,
-            }))
-        }
-        &config::Protocol::SLP => protocols::Protocol::Tcp(Arc::new(MinecraftSlpProtocol::new())),
-    };
+// a panic while holding it doesn't change whether the rule is installed
+fn lock(firewall: &Mutex<Firewall>) -> MutexGuard<'_, Firewall> {
+    firewall.lock().unwrap_or_else(PoisonError::into_inner)
 }
 
-fn set_fingerprint(lock: Arc<RwLock<fingerprint::Fingerprint>>, fingerprint: &config::Fingerprint) {
-    let mut lock = lock.write().unwrap();
-    *lock = match fingerprint {
-        &config::Fingerprint::Nintendo3DS => fingerprint::Fingerprint::nintendo_3ds(),
+fn replay(config: Config, capture: &Path, seed: Option<i64>, start_time: Option<i64>) {
+    logging::init(&config.logging);
+
+    // the cookies only match with the exact seed and start time of the captured scan
+    let checkpoint = config
+        .scan
+        .checkpoint
+        .as_ref()
+        .and_then(|path| Checkpoint::load(Path::new(path)).ok());
+    let seed = seed
+        .or(checkpoint.as_ref().map(|checkpoint| checkpoint.seed))
+        .unwrap_or(config.scan.seed);
+    let Some(start_time) = start_time.or(checkpoint.map(|checkpoint| checkpoint.start_time)) else {
+        eprintln!("Need the start time of the captured scan, pass --start-time or configure its checkpoint");
+        process::exit(1);
+    };
+    let Some(start_time) = Utc.timestamp_millis_opt(start_time).single() else {
+        eprintln!("Invalid start time {start_time}");
+        process::exit(1);
+    };
+    let cookies = Cookies {
+        seed,
+        start_time,
+        source_ports: config.scan.source_ports,
     };
-}
 
-fn handle_query(addr: &SocketAddrV4, response: QueryResponse) {
-    match response {
-        QueryResponse::Partial {
-            motd,
-            gametype,
-            map,
-            numplayers,
-            maxplayers,
-            host,
-        } => {
-            println!("Got partial stat from {addr}: \n\tMOTD = {motd}\n\tgametype = {gametype}\n\tmap = {map}\n\tnumplayers = {numplayers}\n\tmaxplayers = {maxplayers}\n\thost = {host}");
+    let mut capture = PcapReader::open(capture).unwrap_or_else(|err| {
+        eprintln!("Could not open capture `{}`: {err}", capture.display());
+        process::exit(1);
+    });
+    let stats = Arc::new(Stats::default());
+    let output = Output::from_config(&config.output).expect("Could not open output");
+    let protocol = make_protocol(&config.protocol, output.clone(), stats.clone());
+    let fingerprint = make_fingerprint(&config.fingerprint);
+    info!(seed, %start_time, "Replaying {protocol} replies");
+
+    let summary = replay::replay(&mut capture, protocol, &fingerprint, cookies, stats.clone());
+    output.flush();
+    match summary {
+        Ok(summary) => info!(
+            frames = summary.frames,
+            answers = summary.answers,
+            replies = stats.total_replies(),
+            results = Stats::get(&stats.results),
+            cookie_failures = Stats::get(&stats.cookie_failures),
+            parse_failures = Stats::get(&stats.parse_failures),
+            "Replay done"
+        ),
+        Err(err) => {
+            error!("Could not read capture: {err}");
+            process::exit(1);
         }
-        QueryResponse::Full {
-            kv_section,
-            players,
-        } => {
-            let mut output = format!("Got full stat from {addr}:\n");
-            output += "=================== K,V section ===================\n";
-            for (k, v) in kv_section {
-                output += &format!("\t{k} = {v}\n");
-            }
-            output += "===================== Players =====================\n";
-            for player in players {
-                output += &format!("\t{player}\n");
+    }
+}
+
+// waits for the send threads, and keeps the checkpoint up to date in the meantime
+fn scan_targets(senders: Senders, checkpointer: &mut Option<Checkpointer>) {
+    while !senders.is_finished() {
+        thread::sleep(Duration::from_millis(100));
+        if let Some(checkpointer) = checkpointer {
+            if let Err(err) = checkpointer.update(senders.position()) {
+                error!("Could not save checkpoint: {err}");
             }
+        }
+    }
 
-            println!("{output}");
+    // make sure the final position ends up on disk
+    let position = senders.join();
+    if let Some(checkpointer) = checkpointer {
+        if let Err(err) = checkpointer
+            .update(position)
+            .and_then(|()| checkpointer.save())
+        {
+            error!("Could not save checkpoint: {err}");
         }
     }
 }
 
-fn handle_raknet(addr: &SocketAddrV4, response: RaknetReponse) {
-    let mut msg = format!(
-        "{addr}: GUID = {}, MOTD = `{}`,`{}`, PLAYERS = {}/{}, VERSION = {} {} (protocol v{}), GAMEMODE = {}",
-        response.guid,
-        response.motd,
-        response.sub_motd,
-        response.playercount,
-        response.maxplayers,
-        response.edition,
-        response.version,
-        response.protocol,
-        response.gamemode,
-    );
-
-    if let Some(extra) = response.extra {
-        msg += &format!(", GARBAGE = `{extra}`");
+fn make_protocol(
+    protocol: &config::Protocol,
+    output: Output,
+    stats: Arc<Stats>,
+) -> protocols::Protocol<SlpState> {
+    match *protocol {
+        config::Protocol::Raknet => {
+            protocols::Protocol::Udp(Arc::new(protocols::UdpProtocol::Raknet {
+                callback: Box::new(move |addr, response| {
+                    Stats::inc(&stats.results);
+                    output.write(ScanResult::new(*addr, Response::Raknet(response)))
+                }),
+            }))
+        }
+        config::Protocol::Query { fullstat } => {
+            protocols::Protocol::Udp(Arc::new(protocols::UdpProtocol::McQuery {
+                callback: Box::new(move |addr, response| {
+                    Stats::inc(&stats.results);
+                    output.write(ScanResult::new(*addr, Response::Query(response)))
+                }),
+                fullstat,
+            }))
+        }
+        config::Protocol::SLP => protocols::Protocol::Tcp(Arc::new(MinecraftSlpProtocol::new(
+            Box::new(move |addr, response| {
+                Stats::inc(&stats.results);
+                output.write(ScanResult::new(*addr, Response::Slp(response)))
+            }),
+        ))),
     }
+}
 
-    println!("{msg}");
+fn make_fingerprint(fingerprint: &config::Fingerprint) -> fingerprint::Fingerprint {
+    match *fingerprint {
+        config::Fingerprint::Nintendo3DS => fingerprint::Fingerprint::nintendo_3ds(),
+    }
 }
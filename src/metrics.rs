@@ -0,0 +1,239 @@
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use serde_derive::Deserialize;
+use tracing::warn;
+
+use crate::stats::Stats;
+
+#[derive(Deserialize, Debug)]
+pub struct MetricsConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_listen")]
+    pub listen: SocketAddr,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            listen: default_listen(),
+        }
+    }
+}
+
+fn default_listen() -> SocketAddr {
+    "127.0.0.1:9184".parse().unwrap()
+}
+
+// a tiny HTTP server exposing the scanner counters in the prometheus text format
+// we only ever get scraped once every few seconds, so one connection at a time is fine
+pub struct MetricsServer {
+    listener: TcpListener,
+    stats: Arc<Stats>,
+}
+
+impl MetricsServer {
+    pub fn bind(addr: SocketAddr, stats: Arc<Stats>) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr)?,
+            stats,
+        })
+    }
+
+    pub fn spawn(self) -> JoinHandle<()> {
+        thread::spawn(move || {
+            for stream in self.listener.incoming() {
+                let result = stream.and_then(|stream| handle_connection(stream, &self.stats));
+                if let Err(err) = result {
+                    warn!("Metrics connection failed: {err}");
+                }
+            }
+        })
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, stats: &Stats) -> io::Result<()> {
+    // don't let a stuck client block the scrapes of everyone else
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // skip the headers, we don't care about them
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", "text/plain; version=0.0.4", render(stats)),
+        _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+pub fn render(stats: &Stats) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, u64)]| {
+        let _ = writeln!(out, "# HELP badscan_{name} {help}");
+        let _ = writeln!(out, "# TYPE badscan_{name} {kind}");
+        for (labels, value) in samples {
+            let _ = writeln!(out, "badscan_{name}{labels} {value}");
+        }
+    };
+
+    metric(
+        "probes_sent_total",
+        "counter",
+        "Probes sent to new targets.",
+        &[("", Stats::get(&stats.probes_sent))],
+    );
+    metric(
+        "packets_sent_total",
+        "counter",
+        "Packets sent, including ACKs, RSTs and follow-up packets.",
+        &[("", Stats::get(&stats.packets_sent))],
+    );
+    metric(
+        "send_errors_total",
+        "counter",
+        "Packets the backend failed to send.",
+        &[("", Stats::get(&stats.send_errors))],
+    );
+    let replies: Vec<(String, u64)> = stats
+        .replies()
+        .into_iter()
+        .map(|(protocol, count)| (format!("{{protocol=\"{protocol}\"}}"), count))
+        .collect();
+    metric(
+        "responses_total",
+        "counter",
+        "Packets that passed cookie validation, by protocol.",
+        &replies
+            .iter()
+            .map(|(labels, count)| (labels.as_str(), *count))
+            .collect::<Vec<_>>(),
+    );
+    metric(
+        "results_total",
+        "counter",
+        "Servers found.",
+        &[("", Stats::get(&stats.results))],
+    );
+    metric(
+        "validation_failures_total",
+        "counter",
+        "Packets dropped because they failed validation.",
+        &[
+            ("{reason=\"cookie\"}", Stats::get(&stats.cookie_failures)),
+            ("{reason=\"parse\"}", Stats::get(&stats.parse_failures)),
+        ],
+    );
+    metric(
+        "rsts_total",
+        "counter",
+        "TCP RSTs received.",
+        &[("", Stats::get(&stats.rsts))],
+    );
+    metric(
+        "kernel_rsts_total",
+        "counter",
+        "TCP RSTs the kernel sent from our source ports, the firewall rule is missing when this grows.",
+        &[("", Stats::get(&stats.kernel_rsts))],
+    );
+    metric(
+        "dropped_packets_total",
+        "counter",
+        "Packets dropped before they got to the protocol, because they were invalid.",
+        &[
+            ("{reason=\"truncated\"}", Stats::get(&stats.drops_truncated)),
+            (
+                "{reason=\"bad_header\"}",
+                Stats::get(&stats.drops_bad_header),
+            ),
+            ("{reason=\"fragment\"}", Stats::get(&stats.drops_fragment)),
+            ("{reason=\"checksum\"}", Stats::get(&stats.drops_checksum)),
+        ],
+    );
+    metric(
+        "receive_errors_total",
+        "counter",
+        "Errors while reading from the datalink channel.",
+        &[
+            ("{kind=\"overrun\"}", Stats::get(&stats.recv_overruns)),
+            (
+                "{kind=\"interface_down\"}",
+                Stats::get(&stats.recv_interface_down),
+            ),
+            ("{kind=\"other\"}", Stats::get(&stats.recv_other_errors)),
+        ],
+    );
+    metric(
+        "channel_reopens_total",
+        "counter",
+        "Times the datalink channel was reopened after an error.",
+        &[("", Stats::get(&stats.recv_reopens))],
+    );
+    metric(
+        "tcp_connections",
+        "gauge",
+        "Entries in the TCP connection table.",
+        &[("", Stats::get(&stats.tcp_connections))],
+    );
+    metric(
+        "send_queue_depth",
+        "gauge",
+        "Packets waiting to be sent.",
+        &[("", Stats::get(&stats.queue_depth))],
+    );
+    metric(
+        "targets_done",
+        "gauge",
+        "Targets probed so far.",
+        &[("", Stats::get(&stats.targets_done))],
+    );
+    metric(
+        "targets_total",
+        "gauge",
+        "Targets in this scan.",
+        &[("", Stats::get(&stats.targets_total))],
+    );
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocols::ProtocolKind;
+
+    #[test]
+    fn render_metrics() {
+        let stats = Stats::default();
+        Stats::inc(&stats.probes_sent);
+        stats.reply(ProtocolKind::Raknet);
+        stats.reply(ProtocolKind::Raknet);
+
+        let text = render(&stats);
+        assert!(text
+            .contains("# TYPE badscan_probes_sent_total counter\nbadscan_probes_sent_total 1\n"));
+        assert!(text.contains("badscan_responses_total{protocol=\"Raknet\"} 2\n"));
+        assert!(text.contains("badscan_validation_failures_total{reason=\"cookie\"} 0\n"));
+    }
+}
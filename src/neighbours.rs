@@ -0,0 +1,346 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    io,
+    net::Ipv4Addr,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use pnet::{
+    ipnetwork::Ipv4Network,
+    util::{MacAddr, ParseMacAddrErr},
+};
+use serde_derive::Deserialize;
+
+use crate::{backend::Backend, receiver::RecvErrorKind, sender::Pacer, warn_limited};
+
+// the MACs of the hosts we send frames to directly: the gateway, and the targets on our own network
+// default_net only knows the gateway's when the kernel happened to have it cached, so we ask for it with ARP ourselves
+
+const ARP_FRAME_LEN: usize = 42;
+const ETHERTYPE_ARP: [u8; 2] = [0x08, 0x06];
+// hardware type Ethernet, protocol type IPv4 and the lengths of their addresses
+const ETHERNET_IPV4: [u8; 6] = [0, 1, 0x08, 0x00, 6, 4];
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ArpConfig {
+    // send everything that isn't on our own network to this MAC instead of asking the gateway, uncomment to enable
+    pub next_hop: Option<ConfiguredMac>,
+    // how long to wait for replies after sending the requests (in milliseconds)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    // how many more times to ask the hosts that didn't reply
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    // maximum amount of requests per second, every one of them is a broadcast
+    #[serde(default = "default_rate")]
+    pub rate: u64,
+    // the most targets on our own network we ask for their MACs, the rest of them go to the gateway (which hopefully
+    // forwards them)
+    #[serde(default = "default_max_neighbours")]
+    pub max_neighbours: usize,
+}
+
+impl Default for ArpConfig {
+    fn default() -> Self {
+        Self {
+            next_hop: None,
+            timeout: default_timeout(),
+            retries: default_retries(),
+            rate: default_rate(),
+            max_neighbours: default_max_neighbours(),
+        }
+    }
+}
+
+fn default_timeout() -> u64 {
+    1000
+}
+
+fn default_retries() -> u32 {
+    2
+}
+
+fn default_rate() -> u64 {
+    1000
+}
+
+fn default_max_neighbours() -> usize {
+    4096
+}
+
+// a MAC address in the config, written like "02:00:00:00:00:01"
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct ConfiguredMac(pub MacAddr);
+
+impl TryFrom<String> for ConfiguredMac {
+    type Error = ParseMacAddrErr;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        MacAddr::from_str(&value).map(Self)
+    }
+}
+
+// the MACs of the hosts on our own network, frames to them don't go through the gateway
+// cheap to clone, every send thread has one
+#[derive(Debug, Clone, Default)]
+pub struct Neighbours {
+    network: Option<Ipv4Network>,
+    macs: Arc<HashMap<Ipv4Addr, MacAddr>>,
+}
+
+impl Neighbours {
+    // only the addresses in `network` are kept
+    pub fn new(network: Ipv4Network, mut macs: HashMap<Ipv4Addr, MacAddr>) -> Self {
+        macs.retain(|ip, _| network.contains(*ip));
+        Self {
+            network: Some(network),
+            macs: Arc::new(macs),
+        }
+    }
+
+    // None for addresses that aren't on our network, or didn't answer
+    pub fn get(&self, ip: &Ipv4Addr) -> Option<MacAddr> {
+        if self.macs.is_empty() || !self.network?.contains(*ip) {
+            return None;
+        }
+        self.macs.get(ip).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.macs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.macs.is_empty()
+    }
+}
+
+// asks for the MACs of `targets`, and returns those of the ones that answered
+pub fn resolve(
+    backend: &dyn Backend,
+    mac: MacAddr,
+    ip: Ipv4Addr,
+    targets: &[Ipv4Addr],
+    config: &ArpConfig,
+) -> io::Result<HashMap<Ipv4Addr, MacAddr>> {
+    let mut resolved = HashMap::new();
+    let mut pending: BTreeSet<Ipv4Addr> = targets.iter().copied().collect();
+    if pending.is_empty() {
+        return Ok(resolved);
+    }
+    let (mut tx, mut rx) = backend.open()?;
+    let mut pacer = Pacer::new(config.rate as f64);
+
+    for _ in 0..=config.retries {
+        for target in &pending {
+            pacer.wait();
+            if let Err(err) = tx.send(&request(mac, ip, *target)) {
+                warn_limited!(%err, "could not send ARP request");
+            }
+        }
+        if let Err(err) = tx.flush() {
+            warn_limited!(%err, "could not send ARP requests");
+        }
+
+        let deadline = Instant::now() + Duration::from_millis(config.timeout);
+        while !pending.is_empty() && Instant::now() < deadline {
+            match rx.recv() {
+                Ok(frame) => {
+                    if let Some((ip, mac)) = parse_reply(frame) {
+                        if pending.remove(&ip) {
+                            resolved.insert(ip, mac);
+                        }
+                    }
+                }
+                Err(err) if RecvErrorKind::classify(&err) == RecvErrorKind::Timeout => {}
+                Err(err) => warn_limited!(%err, "could not receive ARP reply"),
+            }
+        }
+        if pending.is_empty() {
+            break;
+        }
+    }
+    Ok(resolved)
+}
+
+// who has `target`? tell `ip`
+pub fn request(mac: MacAddr, ip: Ipv4Addr, target: Ipv4Addr) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(ARP_FRAME_LEN);
+    frame.extend_from_slice(&MacAddr::broadcast().octets());
+    frame.extend_from_slice(&mac.octets());
+    frame.extend_from_slice(&ETHERTYPE_ARP);
+    frame.extend_from_slice(&ETHERNET_IPV4);
+    frame.extend_from_slice(&ARP_REQUEST.to_be_bytes());
+    frame.extend_from_slice(&mac.octets());
+    frame.extend_from_slice(&ip.octets());
+    frame.extend_from_slice(&[0; 6]);
+    frame.extend_from_slice(&target.octets());
+    frame
+}
+
+// the address and MAC of whoever sent an ARP reply, None for every other frame
+pub fn parse_reply(frame: &[u8]) -> Option<(Ipv4Addr, MacAddr)> {
+    if frame.len() < ARP_FRAME_LEN || frame[12..14] != ETHERTYPE_ARP {
+        return None;
+    }
+    let arp = &frame[14..ARP_FRAME_LEN];
+    if arp[0..6] != ETHERNET_IPV4 || u16::from_be_bytes([arp[6], arp[7]]) != ARP_REPLY {
+        return None;
+    }
+    let mac = MacAddr::new(arp[8], arp[9], arp[10], arp[11], arp[12], arp[13]);
+    let ip = Ipv4Addr::new(arp[14], arp[15], arp[16], arp[17]);
+    Some((ip, mac))
+}
+
+// the complete entries the kernel has for `interface`, so we don't have to ask for those
+pub fn kernel_table(interface: &str) -> HashMap<Ipv4Addr, MacAddr> {
+    std::fs::read_to_string("/proc/net/arp")
+        .map(|table| parse_kernel_table(&table, interface))
+        .unwrap_or_default()
+}
+
+// IP address, HW type, flags, HW address, mask, device
+fn parse_kernel_table(table: &str, interface: &str) -> HashMap<Ipv4Addr, MacAddr> {
+    // ATF_COM, the entry has a MAC
+    const COMPLETE: u32 = 0x2;
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let [ip, _, flags, mac, _, device] = fields[..] else {
+                return None;
+            };
+            let flags = u32::from_str_radix(flags.trim_start_matches("0x"), 16).ok()?;
+            if device != interface || flags & COMPLETE == 0 {
+                return None;
+            }
+            Some((ip.parse().ok()?, mac.parse().ok()?))
+        })
+        .collect()
+}
+
+// the gateway of the default route through `interface`, for when default_net didn't know its MAC (and so dropped it)
+pub fn default_gateway(interface: &str) -> Option<Ipv4Addr> {
+    let table = std::fs::read_to_string("/proc/net/route").ok()?;
+    parse_route_table(&table, interface)
+}
+
+// interface, destination, gateway, ..., the addresses are in network order but printed as numbers in host order
+fn parse_route_table(table: &str, interface: &str) -> Option<Ipv4Addr> {
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        let [device, "00000000", gateway, ..] = fields[..] else {
+            return None;
+        };
+        let gateway = u32::from_str_radix(gateway, 16).ok()?;
+        (device == interface && gateway != 0).then(|| Ipv4Addr::from(gateway.to_ne_bytes()))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+
+    use super::*;
+    use crate::backend::memory::MemoryBackend;
+
+    // what a host with `mac` answers to a request for its address
+    fn reply(request: &[u8], mac: MacAddr) -> Vec<u8> {
+        let mut reply = request.to_vec();
+        reply[0..6].copy_from_slice(&request[6..12]);
+        reply[6..12].copy_from_slice(&mac.octets());
+        reply[20..22].copy_from_slice(&ARP_REPLY.to_be_bytes());
+        reply[22..28].copy_from_slice(&mac.octets());
+        reply[28..32].copy_from_slice(&request[38..42]);
+        reply[32..42].copy_from_slice(&request[22..32]);
+        reply
+    }
+
+    #[test]
+    fn resolve_hosts() {
+        let (backend, peer) = MemoryBackend::new();
+        let hosts = HashMap::from([
+            (Ipv4Addr::new(10, 0, 0, 1), MacAddr(2, 0, 0, 0, 0, 1)),
+            (Ipv4Addr::new(10, 0, 0, 5), MacAddr(2, 0, 0, 0, 0, 5)),
+        ]);
+        let network = {
+            let hosts = hosts.clone();
+            thread::spawn(move || {
+                let mut requests = 0;
+                while let Some(request) = peer.recv_timeout(Duration::from_millis(500)) {
+                    requests += 1;
+                    let target = Ipv4Addr::new(request[38], request[39], request[40], request[41]);
+                    if let Some(mac) = hosts.get(&target) {
+                        peer.send(reply(&request, *mac));
+                    }
+                }
+                requests
+            })
+        };
+
+        let config = ArpConfig {
+            timeout: 100,
+            retries: 2,
+            ..Default::default()
+        };
+        let resolved = resolve(
+            &backend,
+            MacAddr(2, 0, 0, 0, 0, 2),
+            Ipv4Addr::new(10, 0, 0, 2),
+            &[
+                Ipv4Addr::new(10, 0, 0, 1),
+                Ipv4Addr::new(10, 0, 0, 5),
+                Ipv4Addr::new(10, 0, 0, 9),
+            ],
+            &config,
+        )
+        .unwrap();
+        assert_eq!(resolved, hosts);
+        drop(backend);
+        // the host that's not there gets asked again, the others only once
+        assert_eq!(network.join().unwrap(), 3 + 2);
+
+        let neighbours = Neighbours::new("10.0.0.0/24".parse().unwrap(), resolved);
+        assert_eq!(
+            neighbours.get(&Ipv4Addr::new(10, 0, 0, 5)),
+            Some(MacAddr(2, 0, 0, 0, 0, 5))
+        );
+        assert_eq!(neighbours.get(&Ipv4Addr::new(10, 0, 1, 5)), None);
+    }
+
+    #[test]
+    fn kernel_tables() {
+        let arp = "IP address       HW type     Flags       HW address            Mask     Device
+10.0.0.1         0x1         0x2         02:00:00:00:00:01     *        eth0
+10.0.0.7         0x1         0x0         00:00:00:00:00:00     *        eth0
+192.168.1.1      0x1         0x2         02:00:00:00:00:03     *        wlan0
+";
+        assert_eq!(
+            parse_kernel_table(arp, "eth0"),
+            HashMap::from([(Ipv4Addr::new(10, 0, 0, 1), MacAddr(2, 0, 0, 0, 0, 1))])
+        );
+
+        // the kernel prints the addresses as numbers in its own byte order
+        let hex = |ip: [u8; 4]| format!("{:08X}", u32::from_ne_bytes(ip));
+        let route = format!(
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t{}\t00000000\t0001\t0\t0\t0\t{}\t0\t0\t0
+eth0\t00000000\t{}\t0003\t0\t0\t0\t00000000\t0\t0\t0
+",
+            hex([10, 0, 0, 0]),
+            hex([255, 255, 255, 0]),
+            hex([10, 0, 0, 1])
+        );
+        assert_eq!(
+            parse_route_table(&route, "eth0"),
+            Some(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        assert_eq!(parse_route_table(&route, "wlan0"), None);
+    }
+}
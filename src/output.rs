@@ -0,0 +1,271 @@
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    net::SocketAddrV4,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::error;
+
+use crate::protocols::{query::QueryResponse, raknet::RaknetReponse, slp::SlpResponse};
+
+#[derive(Deserialize, Debug)]
+pub struct OutputConfig {
+    // print the results to the terminal
+    #[serde(default = "default_stdout")]
+    pub stdout: bool,
+    // append the results to this file, as JSON lines
+    pub file: Option<String>,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        Self {
+            stdout: default_stdout(),
+            file: None,
+        }
+    }
+}
+
+fn default_stdout() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScanResult {
+    pub addr: SocketAddrV4,
+    pub time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub response: Response,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "protocol", content = "response")]
+pub enum Response {
+    Query(QueryResponse),
+    Raknet(RaknetReponse),
+    Slp(SlpResponse),
+}
+
+impl Response {
+    pub fn protocol(&self) -> &'static str {
+        match self {
+            Response::Query(_) => "Query",
+            Response::Raknet(_) => "Raknet",
+            Response::Slp(_) => "SLP",
+        }
+    }
+}
+
+impl ScanResult {
+    pub fn new(addr: SocketAddrV4, response: Response) -> Self {
+        Self {
+            addr,
+            time: Utc::now(),
+            response,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("{path}:{line}: {source}")]
+    Json {
+        path: PathBuf,
+        line: usize,
+        source: serde_json::Error,
+    },
+}
+
+// somewhere results end up
+pub trait Sink: Send {
+    fn write(&mut self, result: &ScanResult) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// prints the results in a human readable way
+pub struct StdoutSink;
+
+impl Sink for StdoutSink {
+    fn write(&mut self, result: &ScanResult) -> io::Result<()> {
+        let addr = result.addr;
+        match &result.response {
+            Response::Query(QueryResponse::Partial {
+                motd,
+                gametype,
+                map,
+                numplayers,
+                maxplayers,
+                host,
+            }) => {
+                println!("Got partial stat from {addr}: \n\tMOTD = {motd}\n\tgametype = {gametype}\n\tmap = {map}\n\tnumplayers = {numplayers}\n\tmaxplayers = {maxplayers}\n\thost = {host}");
+            }
+            Response::Query(QueryResponse::Full {
+                kv_section,
+                players,
+            }) => {
+                let mut output = format!("Got full stat from {addr}:\n");
+                output += "=================== K,V section ===================\n";
+                for (k, v) in kv_section {
+                    output += &format!("\t{k} = {v}\n");
+                }
+                output += "===================== Players =====================\n";
+                for player in players {
+                    output += &format!("\t{player}\n");
+                }
+
+                println!("{output}");
+            }
+            Response::Raknet(response) => {
+                let mut msg = format!(
+                    "{addr}: GUID = {}, MOTD = `{}`,`{}`, PLAYERS = {}/{}, VERSION = {} {} (protocol v{}), GAMEMODE = {}",
+                    response.guid,
+                    response.motd,
+                    response.sub_motd,
+                    response.playercount,
+                    response.maxplayers,
+                    response.edition,
+                    response.version,
+                    response.protocol,
+                    response.gamemode,
+                );
+
+                if let Some(extra) = &response.extra {
+                    msg += &format!(", GARBAGE = `{extra}`");
+                }
+
+                println!("{msg}");
+            }
+            Response::Slp(response) => {
+                let (online, max) = response.players();
+                println!(
+                    "{addr}: VERSION = {} (protocol v{}), PLAYERS = {}/{}, MOTD = `{}`",
+                    response.version().unwrap_or("?"),
+                    response.protocol().unwrap_or_default(),
+                    online.unwrap_or_default(),
+                    max.unwrap_or_default(),
+                    response.description(),
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+// one JSON object per line, easy to append to and to process with other tools
+pub struct JsonLinesSink {
+    writer: BufWriter<File>,
+}
+
+impl JsonLinesSink {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+        })
+    }
+}
+
+impl Sink for JsonLinesSink {
+    fn write(&mut self, result: &ScanResult) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, result)?;
+        self.writer.write_all(b"\n")
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// all configured sinks, cheap to clone so every callback can have one
+#[derive(Clone, Default)]
+pub struct Output {
+    sinks: Arc<Mutex<Vec<Box<dyn Sink>>>>,
+}
+
+impl Output {
+    pub fn from_config(config: &OutputConfig) -> io::Result<Self> {
+        let output = Self::default();
+        if config.stdout {
+            output.add(Box::new(StdoutSink));
+        }
+        if let Some(path) = &config.file {
+            output.add(Box::new(JsonLinesSink::open(Path::new(path))?));
+        }
+        Ok(output)
+    }
+
+    pub fn add(&self, sink: Box<dyn Sink>) {
+        self.sinks.lock().unwrap().push(sink);
+    }
+
+    pub fn write(&self, result: ScanResult) {
+        for sink in self.sinks.lock().unwrap().iter_mut() {
+            if let Err(err) = sink.write(&result) {
+                error!("Could not write result: {err}");
+            }
+        }
+    }
+
+    pub fn flush(&self) {
+        for sink in self.sinks.lock().unwrap().iter_mut() {
+            if let Err(err) = sink.flush() {
+                error!("Could not flush results: {err}");
+            }
+        }
+    }
+}
+
+pub fn read_results(path: &Path) -> Result<Vec<ScanResult>, Error> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut results = vec![];
+    for (line, contents) in reader.lines().enumerate() {
+        let contents = contents?;
+        if contents.trim().is_empty() {
+            continue;
+        }
+        let result = serde_json::from_str(&contents).map_err(|source| Error::Json {
+            path: path.to_owned(),
+            line: line + 1,
+            source,
+        })?;
+        results.push(result);
+    }
+    Ok(results)
+}
+
+// combines the outputs of several shards (or runs) into one file
+// when a server shows up more than once, the newest response wins
+pub fn merge(inputs: &[PathBuf], output: &Path) -> Result<usize, Error> {
+    let mut merged: BTreeMap<(SocketAddrV4, &'static str), ScanResult> = BTreeMap::new();
+    for input in inputs {
+        for result in read_results(input)? {
+            let key = (result.addr, result.response.protocol());
+            match merged.get(&key) {
+                Some(existing) if existing.time >= result.time => {}
+                _ => {
+                    merged.insert(key, result);
+                }
+            }
+        }
+    }
+
+    let mut writer = BufWriter::new(File::create(output)?);
+    for result in merged.values() {
+        serde_json::to_writer(&mut writer, result).map_err(io::Error::from)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+
+    Ok(merged.len())
+}
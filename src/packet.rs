@@ -0,0 +1,355 @@
+use std::net::SocketAddrV4;
+
+use pnet::{
+    packet::{
+        ethernet::{EtherTypes, EthernetPacket},
+        ip::IpNextHeaderProtocols,
+        ipv4::{self, Ipv4Flags, Ipv4Packet},
+        tcp::{self, TcpPacket},
+        udp::{self, UdpPacket},
+    },
+    util::MacAddr,
+};
+use thiserror::Error;
+
+// everything in here handles packets straight from the internet, so nothing may panic on bad input
+// pnet's packet types don't check much more than the minimum size, so we validate the rest ourselves
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const IPV4_MIN_HEADER_LEN: usize = 20;
+const UDP_HEADER_LEN: usize = 8;
+const TCP_MIN_HEADER_LEN: usize = 20;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    #[error("packet is shorter than its headers say")]
+    Truncated,
+    #[error("invalid header")]
+    BadHeader,
+    #[error("IP fragment")]
+    Fragment,
+    #[error("bad checksum")]
+    BadChecksum,
+}
+
+// what the frames of a backend or capture start with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkType {
+    Ethernet,
+    // bare IP packets, like on a TUN device
+    Ip,
+}
+
+pub struct Frame<'a> {
+    pub source: MacAddr,
+    pub destination: MacAddr,
+    // the IPv4 packet, None for other ethertypes
+    pub ipv4: Option<&'a [u8]>,
+}
+
+pub struct Ipv4Segment<'a> {
+    pub identification: u16,
+    pub source: SocketAddrV4,
+    pub destination: SocketAddrV4,
+    pub transport: Transport<'a>,
+}
+
+pub enum Transport<'a> {
+    Udp { payload: &'a [u8] },
+    Tcp(TcpSegment<'a>),
+}
+
+pub struct TcpSegment<'a> {
+    pub flags: u8,
+    pub sequence: u32,
+    pub acknowledgement: u32,
+    pub payload: &'a [u8],
+}
+
+pub fn parse_ethernet(frame: &[u8]) -> Result<Frame<'_>, DropReason> {
+    if frame.len() < ETHERNET_HEADER_LEN {
+        return Err(DropReason::Truncated);
+    }
+    let ethernet = EthernetPacket::new(frame).ok_or(DropReason::Truncated)?;
+    let ipv4 =
+        (ethernet.get_ethertype() == EtherTypes::Ipv4).then(|| &frame[ETHERNET_HEADER_LEN..]);
+
+    Ok(Frame {
+        source: ethernet.get_source(),
+        destination: ethernet.get_destination(),
+        ipv4,
+    })
+}
+
+// a frame without a link layer has to be told apart by its version, None for IPv6
+pub fn raw_ip(frame: &[u8]) -> Result<Option<&[u8]>, DropReason> {
+    match frame.first().map(|byte| byte >> 4) {
+        None => Err(DropReason::Truncated),
+        Some(4) => Ok(Some(frame)),
+        Some(6) => Ok(None),
+        Some(_) => Err(DropReason::BadHeader),
+    }
+}
+
+// returns None for protocols other than TCP and UDP
+// checksums can be skipped for our own outgoing packets, those are often only filled in by the network card
+pub fn parse_ipv4(
+    packet: &[u8],
+    verify_checksums: bool,
+) -> Result<Option<Ipv4Segment<'_>>, DropReason> {
+    if packet.len() < IPV4_MIN_HEADER_LEN {
+        return Err(DropReason::Truncated);
+    }
+    let ip = Ipv4Packet::new(packet).ok_or(DropReason::Truncated)?;
+    if ip.get_version() != 4 {
+        return Err(DropReason::BadHeader);
+    }
+    let header_len = ip.get_header_length() as usize * 4;
+    let total_len = ip.get_total_length() as usize;
+    if header_len < IPV4_MIN_HEADER_LEN || total_len < header_len {
+        return Err(DropReason::BadHeader);
+    }
+    // anything after total_len is ethernet padding
+    if total_len > packet.len() {
+        return Err(DropReason::Truncated);
+    }
+    // we set DF on everything we send, and the replies we care about are tiny
+    if ip.get_flags() & Ipv4Flags::MoreFragments != 0 || ip.get_fragment_offset() != 0 {
+        return Err(DropReason::Fragment);
+    }
+    if verify_checksums && ipv4::checksum(&ip) != ip.get_checksum() {
+        return Err(DropReason::BadChecksum);
+    }
+
+    let source_ip = ip.get_source();
+    let destination_ip = ip.get_destination();
+    let payload = &packet[header_len..total_len];
+    let protocol = ip.get_next_level_protocol();
+
+    let (source_port, destination_port, transport) = if protocol == IpNextHeaderProtocols::Udp {
+        if payload.len() < UDP_HEADER_LEN {
+            return Err(DropReason::Truncated);
+        }
+        let udp = UdpPacket::new(payload).ok_or(DropReason::Truncated)?;
+        let udp_len = udp.get_length() as usize;
+        if udp_len < UDP_HEADER_LEN {
+            return Err(DropReason::BadHeader);
+        }
+        if udp_len > payload.len() {
+            return Err(DropReason::Truncated);
+        }
+        let datagram = &payload[..udp_len];
+        // a checksum of 0 means the sender didn't calculate one
+        if verify_checksums && udp.get_checksum() != 0 {
+            let udp = UdpPacket::new(datagram).ok_or(DropReason::Truncated)?;
+            if udp::ipv4_checksum(&udp, &source_ip, &destination_ip) != udp.get_checksum() {
+                return Err(DropReason::BadChecksum);
+            }
+        }
+        (
+            udp.get_source(),
+            udp.get_destination(),
+            Transport::Udp {
+                payload: &datagram[UDP_HEADER_LEN..],
+            },
+        )
+    } else if protocol == IpNextHeaderProtocols::Tcp {
+        if payload.len() < TCP_MIN_HEADER_LEN {
+            return Err(DropReason::Truncated);
+        }
+        let tcp = TcpPacket::new(payload).ok_or(DropReason::Truncated)?;
+        let data_offset = tcp.get_data_offset() as usize * 4;
+        if data_offset < TCP_MIN_HEADER_LEN {
+            return Err(DropReason::BadHeader);
+        }
+        if data_offset > payload.len() {
+            return Err(DropReason::Truncated);
+        }
+        if verify_checksums
+            && tcp::ipv4_checksum(&tcp, &source_ip, &destination_ip) != tcp.get_checksum()
+        {
+            return Err(DropReason::BadChecksum);
+        }
+        (
+            tcp.get_source(),
+            tcp.get_destination(),
+            Transport::Tcp(TcpSegment {
+                flags: tcp.get_flags(),
+                sequence: tcp.get_sequence(),
+                acknowledgement: tcp.get_acknowledgement(),
+                payload: &payload[data_offset..],
+            }),
+        )
+    } else {
+        return Ok(None);
+    };
+
+    Ok(Some(Ipv4Segment {
+        identification: ip.get_identification(),
+        source: SocketAddrV4::new(source_ip, source_port),
+        destination: SocketAddrV4::new(destination_ip, destination_port),
+        transport,
+    }))
+}
+
+// the whole receive path up to the protocol handlers, Ethernet -> IPv4 -> TCP/UDP
+pub fn parse(frame: &[u8]) -> Result<Option<Ipv4Segment<'_>>, DropReason> {
+    parse_link(frame, LinkType::Ethernet)
+}
+
+pub fn parse_link(
+    frame: &[u8],
+    link_type: LinkType,
+) -> Result<Option<Ipv4Segment<'_>>, DropReason> {
+    let packet = match link_type {
+        LinkType::Ethernet => parse_ethernet(frame)?.ipv4,
+        LinkType::Ip => raw_ip(frame)?,
+    };
+    match packet {
+        Some(packet) => parse_ipv4(packet, true),
+        None => Ok(None),
+    }
+}
+
+// which flow a frame belongs to, without validating anything so it's cheap enough for the capture threads
+// it's the same for every frame between two addresses (in both directions), and 0 for anything but IPv4 TCP and UDP
+pub fn flow_hash(frame: &[u8], link_type: LinkType) -> u64 {
+    let packet = match link_type {
+        LinkType::Ethernet => match frame.get(12..14) {
+            Some([0x08, 0x00]) => &frame[ETHERNET_HEADER_LEN..],
+            _ => return 0,
+        },
+        LinkType::Ip => frame,
+    };
+    let Some(&version_and_length) = packet.first() else {
+        return 0;
+    };
+    let header_len = (version_and_length & 0x0f) as usize * 4;
+    if version_and_length >> 4 != 4 || header_len < IPV4_MIN_HEADER_LEN {
+        return 0;
+    }
+    // the header being complete also means everything in it can be indexed
+    let Some(ports) = packet.get(header_len..header_len + 4) else {
+        return 0;
+    };
+    if packet[9] != IpNextHeaderProtocols::Tcp.0 && packet[9] != IpNextHeaderProtocols::Udp.0 {
+        return 0;
+    }
+
+    let ip = u32::from_be_bytes([packet[12], packet[13], packet[14], packet[15]])
+        ^ u32::from_be_bytes([packet[16], packet[17], packet[18], packet[19]]);
+    let port = u16::from_be_bytes([ports[0], ports[1]]) ^ u16::from_be_bytes([ports[2], ports[3]]);
+    (((ip as u64) << 16) | port as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::utils;
+
+    // an IPv4 header around `payload`, with a valid checksum
+    fn ipv4(payload: &[u8], protocol: u8) -> Vec<u8> {
+        let mut packet = vec![
+            0x45, 0, 0, 0, 0, 1, 0x40, 0, 64, protocol, 0, 0, 10, 0, 0, 2, 10, 0, 0, 1,
+        ];
+        let total_len = (packet.len() + payload.len()) as u16;
+        packet[2..4].copy_from_slice(&total_len.to_be_bytes());
+        packet.extend_from_slice(payload);
+        let checksum = ipv4::checksum(&Ipv4Packet::new(&packet).unwrap());
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn parse_udp() {
+        let source = "10.0.0.2:19132".parse().unwrap();
+        let destination = "10.0.0.1:61000".parse().unwrap();
+        let udp = utils::wrap_udp(b"hello".to_vec(), &source, &destination);
+        let mut packet = ipv4(&udp, 17);
+        // ethernet padding
+        packet.extend_from_slice(&[0; 6]);
+
+        let segment = parse_ipv4(&packet, true).unwrap().unwrap();
+        assert_eq!(segment.source, source);
+        assert_eq!(segment.destination, destination);
+        assert!(matches!(
+            segment.transport,
+            Transport::Udp { payload: b"hello" }
+        ));
+
+        let mut corrupted = packet.clone();
+        corrupted[30] ^= 0xFF;
+        assert_eq!(
+            parse_ipv4(&corrupted, true).err(),
+            Some(DropReason::BadChecksum)
+        );
+        assert!(parse_ipv4(&corrupted, false).is_ok());
+    }
+
+    #[test]
+    fn reject_bad_headers() {
+        let packet = ipv4(&[0; 8], 17);
+
+        assert_eq!(
+            parse_ipv4(&packet[..packet.len() - 1], true).err(),
+            Some(DropReason::Truncated)
+        );
+
+        let mut short_header = packet.clone();
+        short_header[0] = 0x44;
+        assert_eq!(
+            parse_ipv4(&short_header, false).err(),
+            Some(DropReason::BadHeader)
+        );
+
+        let mut fragment = packet.clone();
+        fragment[6] = 0x20;
+        assert_eq!(
+            parse_ipv4(&fragment, false).err(),
+            Some(DropReason::Fragment)
+        );
+
+        let tcp = ipv4(&[0; 20], 6);
+        assert_eq!(parse_ipv4(&tcp, false).err(), Some(DropReason::BadHeader));
+
+        assert!(parse_ipv4(&ipv4(&[], 1), true).unwrap().is_none());
+        assert_eq!(parse(&[0; 10]).err(), Some(DropReason::Truncated));
+
+        // without a link layer
+        let source = "10.0.0.2:19132".parse().unwrap();
+        let destination = "10.0.0.1:61000".parse().unwrap();
+        let udp = ipv4(&utils::wrap_udp(vec![], &source, &destination), 17);
+        assert!(parse_link(&udp, LinkType::Ip).unwrap().is_some());
+        assert!(parse_link(&[0x60; 40], LinkType::Ip).unwrap().is_none());
+        assert_eq!(
+            parse_link(&[], LinkType::Ip).err(),
+            Some(DropReason::Truncated)
+        );
+    }
+
+    #[test]
+    fn flows() {
+        let server = "10.0.0.2:19132".parse().unwrap();
+        let scanner = "10.0.0.1:61000".parse().unwrap();
+        let reply = ipv4(&utils::wrap_udp(vec![], &server, &scanner), 17);
+        let mut probe = ipv4(&utils::wrap_udp(vec![1], &scanner, &server), 17);
+        // the helper always sends from 10.0.0.2 to 10.0.0.1, turn it around
+        probe[12..20].rotate_left(4);
+        assert_eq!(
+            flow_hash(&reply, LinkType::Ip),
+            flow_hash(&probe, LinkType::Ip)
+        );
+
+        // the checksum doesn't matter here
+        let mut other = reply.clone();
+        other[15] = 3;
+        assert_ne!(
+            flow_hash(&reply, LinkType::Ip),
+            flow_hash(&other, LinkType::Ip)
+        );
+        assert_eq!(flow_hash(&reply[..22], LinkType::Ip), 0);
+        assert_eq!(flow_hash(&reply, LinkType::Ethernet), 0);
+        assert_eq!(flow_hash(&[0x45; 10], LinkType::Ip), 0);
+    }
+}
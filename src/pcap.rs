@@ -0,0 +1,377 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, Read},
+    path::Path,
+};
+
+use thiserror::Error;
+
+use crate::packet::LinkType;
+
+// just enough of pcap and pcapng to get the Ethernet frames (or IP packets) out of a capture, see
+// https://wiki.wireshark.org/Development/LibpcapFileFormat and https://www.ietf.org/archive/id/draft-ietf-opsawg-pcapng-01.html
+
+const PCAP_MAGIC_MICROS: u32 = 0xA1B2C3D4;
+const PCAP_MAGIC_NANOS: u32 = 0xA1B23C4D;
+const PCAPNG_SECTION_HEADER: u32 = 0x0A0D0D0A;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_SIMPLE_PACKET: u32 = 3;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+pub const LINKTYPE_ETHERNET: u16 = 1;
+// bare IP packets, the version is in the packet itself
+pub const LINKTYPE_RAW: u16 = 101;
+pub const LINKTYPE_IPV4: u16 = 228;
+
+// nothing we'd ever capture comes close, this only protects us from allocating gigabytes for a corrupt file
+const MAX_BLOCK_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("not a pcap or pcapng file")]
+    NotPcap,
+    #[error("unsupported link type {0}, only Ethernet and raw IP captures can be replayed")]
+    UnsupportedLinkType(u32),
+    #[error("the capture ends in the middle of a packet")]
+    Truncated,
+    #[error("malformed capture: {0}")]
+    Malformed(&'static str),
+}
+
+enum Format {
+    Pcap { link_type: LinkType },
+    // the link types of the interfaces of the current section, packets refer to them by index
+    Pcapng { interfaces: Vec<u16> },
+}
+
+pub struct PcapReader<R> {
+    reader: R,
+    format: Format,
+    big_endian: bool,
+    buf: Vec<u8>,
+}
+
+impl PcapReader<BufReader<File>> {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> PcapReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, Error> {
+        let mut magic = [0; 4];
+        reader
+            .read_exact(&mut magic)
+            .map_err(eof_as(Error::NotPcap))?;
+
+        if u32::from_be_bytes(magic) == PCAPNG_SECTION_HEADER {
+            let mut pcap = Self {
+                reader,
+                format: Format::Pcapng { interfaces: vec![] },
+                big_endian: false,
+                buf: vec![],
+            };
+            pcap.read_section_header()?;
+            return Ok(pcap);
+        }
+
+        let big_endian = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+            (PCAP_MAGIC_MICROS | PCAP_MAGIC_NANOS, _) => false,
+            (_, PCAP_MAGIC_MICROS | PCAP_MAGIC_NANOS) => true,
+            _ => return Err(Error::NotPcap),
+        };
+        let mut pcap = Self {
+            reader,
+            format: Format::Pcap {
+                link_type: LinkType::Ethernet,
+            },
+            big_endian,
+            buf: vec![],
+        };
+
+        // version, timezone, timestamp accuracy, snaplen and the link type
+        let mut header = [0; 20];
+        pcap.reader
+            .read_exact(&mut header)
+            .map_err(eof_as(Error::Truncated))?;
+        // the upper bits can hold the FCS length
+        let linktype = pcap.u32(&header[16..20]) & 0xFFFF;
+        pcap.format = Format::Pcap {
+            link_type: link_type(linktype as u16)?,
+        };
+        Ok(pcap)
+    }
+
+    // the next frame and what it starts with, None at the end of the capture
+    pub fn next_frame(&mut self) -> Result<Option<(LinkType, &[u8])>, Error> {
+        match self.format {
+            Format::Pcap { link_type } => Ok(self.next_pcap()?.map(|frame| (link_type, frame))),
+            Format::Pcapng { .. } => self.next_pcapng(),
+        }
+    }
+
+    fn next_pcap(&mut self) -> Result<Option<&[u8]>, Error> {
+        // seconds, fraction of a second, captured length and original length
+        let mut header = [0; 16];
+        if !self.read_or_eof(&mut header)? {
+            return Ok(None);
+        }
+        let len = self.u32(&header[8..12]) as usize;
+        if len > MAX_BLOCK_LEN {
+            return Err(Error::Malformed("packet is too long"));
+        }
+        self.read_buf(len)?;
+        Ok(Some(&self.buf))
+    }
+
+    fn next_pcapng(&mut self) -> Result<Option<(LinkType, &[u8])>, Error> {
+        loop {
+            let mut header = [0; 8];
+            if !self.read_or_eof(&mut header)? {
+                return Ok(None);
+            }
+            let block_type = self.u32(&header[0..4]);
+            if block_type == PCAPNG_SECTION_HEADER {
+                self.read_section_header()?;
+                continue;
+            }
+
+            let total_len = self.u32(&header[4..8]) as usize;
+            // the header, and the length again at the end
+            if total_len < 12 || !total_len.is_multiple_of(4) || total_len > MAX_BLOCK_LEN {
+                return Err(Error::Malformed("invalid block length"));
+            }
+            self.read_buf(total_len - 8)?;
+            let body = &self.buf[..total_len - 12];
+
+            // which interface the packet was captured on, and where its data is in the block
+            let (interface, start, len) = match block_type {
+                PCAPNG_INTERFACE_DESCRIPTION => {
+                    if body.len() < 8 {
+                        return Err(Error::Malformed("interface description is too short"));
+                    }
+                    let linktype = self.u16(&body[0..2]);
+                    if let Format::Pcapng { interfaces } = &mut self.format {
+                        interfaces.push(linktype);
+                    }
+                    continue;
+                }
+                PCAPNG_ENHANCED_PACKET => {
+                    // interface, timestamp (2x), captured length and original length
+                    if body.len() < 20 {
+                        return Err(Error::Malformed("enhanced packet is too short"));
+                    }
+                    let len = self.u32(&body[12..16]) as usize;
+                    if len > body.len() - 20 {
+                        return Err(Error::Malformed("packet is longer than its block"));
+                    }
+                    (self.u32(&body[0..4]) as usize, 20, len)
+                }
+                PCAPNG_SIMPLE_PACKET => {
+                    if body.len() < 4 {
+                        return Err(Error::Malformed("simple packet is too short"));
+                    }
+                    // the data is padded, the original length is the only length there is (unless it was cut off by the snaplen)
+                    let len = (self.u32(&body[0..4]) as usize).min(body.len() - 4);
+                    (0, 4, len)
+                }
+                // statistics, name resolution, comments...
+                _ => continue,
+            };
+
+            let Format::Pcapng { interfaces } = &self.format else {
+                unreachable!()
+            };
+            let Some(&linktype) = interfaces.get(interface) else {
+                return Err(Error::Malformed("packet of an unknown interface"));
+            };
+            return Ok(Some((link_type(linktype)?, &self.buf[start..start + len])));
+        }
+    }
+
+    // the block type has already been read, every section starts over with its own byte order and interfaces
+    fn read_section_header(&mut self) -> Result<(), Error> {
+        let mut header = [0; 8];
+        self.reader
+            .read_exact(&mut header)
+            .map_err(eof_as(Error::Truncated))?;
+        self.big_endian = match u32::from_le_bytes(header[4..8].try_into().unwrap()) {
+            PCAPNG_BYTE_ORDER_MAGIC => false,
+            magic if magic.swap_bytes() == PCAPNG_BYTE_ORDER_MAGIC => true,
+            _ => return Err(Error::NotPcap),
+        };
+        let total_len = self.u32(&header[0..4]) as usize;
+        // block type, length, byte order magic, version (2x) and the length at the end
+        if total_len < 28 || !total_len.is_multiple_of(4) || total_len > MAX_BLOCK_LEN {
+            return Err(Error::Malformed("invalid section header length"));
+        }
+        // the version, section length and options don't matter to us
+        self.read_buf(total_len - 12)?;
+        self.format = Format::Pcapng { interfaces: vec![] };
+        Ok(())
+    }
+
+    fn read_buf(&mut self, len: usize) -> Result<(), Error> {
+        self.buf.resize(len, 0);
+        self.reader
+            .read_exact(&mut self.buf)
+            .map_err(eof_as(Error::Truncated))
+    }
+
+    // false when the capture ended cleanly, in between two records
+    fn read_or_eof(&mut self, buf: &mut [u8]) -> Result<bool, Error> {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.reader.read(&mut buf[read..]) {
+                Ok(0) if read == 0 => return Ok(false),
+                Ok(0) => return Err(Error::Truncated),
+                Ok(n) => read += n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(true)
+    }
+
+    fn u32(&self, bytes: &[u8]) -> u32 {
+        let bytes = bytes.try_into().unwrap();
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+
+    fn u16(&self, bytes: &[u8]) -> u16 {
+        let bytes = bytes.try_into().unwrap();
+        if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        }
+    }
+}
+
+fn link_type(linktype: u16) -> Result<LinkType, Error> {
+    match linktype {
+        LINKTYPE_ETHERNET => Ok(LinkType::Ethernet),
+        LINKTYPE_RAW | LINKTYPE_IPV4 => Ok(LinkType::Ip),
+        _ => Err(Error::UnsupportedLinkType(linktype as u32)),
+    }
+}
+
+// what a capture of frames of this link type is written as
+pub fn linktype(link_type: LinkType) -> u16 {
+    match link_type {
+        LinkType::Ethernet => LINKTYPE_ETHERNET,
+        LinkType::Ip => LINKTYPE_RAW,
+    }
+}
+
+fn eof_as(error: Error) -> impl FnOnce(io::Error) -> Error {
+    move |err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => error,
+        _ => err.into(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frames<R: Read>(mut pcap: PcapReader<R>) -> Vec<(LinkType, Vec<u8>)> {
+        let mut frames = vec![];
+        while let Some((link_type, frame)) = pcap.next_frame().unwrap() {
+            frames.push((link_type, frame.to_vec()));
+        }
+        frames
+    }
+
+    #[test]
+    fn read_pcap() {
+        let mut file = vec![];
+        file.extend_from_slice(&PCAP_MAGIC_MICROS.to_be_bytes());
+        file.extend_from_slice(&[0, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF]);
+        file.extend_from_slice(&1u32.to_be_bytes());
+        for frame in [&b"first"[..], b"second"] {
+            file.extend_from_slice(&[0; 8]);
+            file.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            file.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            file.extend_from_slice(frame);
+        }
+
+        let pcap = PcapReader::new(&file[..]).unwrap();
+        assert_eq!(
+            frames(pcap),
+            [
+                (LinkType::Ethernet, b"first".to_vec()),
+                (LinkType::Ethernet, b"second".to_vec())
+            ]
+        );
+
+        let mut pcap = PcapReader::new(&file[..file.len() - 1]).unwrap();
+        pcap.next_frame().unwrap();
+        assert!(matches!(pcap.next_frame(), Err(Error::Truncated)));
+    }
+
+    #[test]
+    fn read_pcapng() {
+        fn block(file: &mut Vec<u8>, block_type: u32, body: &[u8]) {
+            let len = 12 + body.len().div_ceil(4) * 4;
+            file.extend_from_slice(&block_type.to_le_bytes());
+            file.extend_from_slice(&(len as u32).to_le_bytes());
+            file.extend_from_slice(body);
+            file.resize(file.len() + len - 12 - body.len(), 0);
+            file.extend_from_slice(&(len as u32).to_le_bytes());
+        }
+
+        let mut file = vec![];
+        let mut section = PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes().to_vec();
+        section.extend_from_slice(&[1, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        block(&mut file, PCAPNG_SECTION_HEADER, &section);
+        block(
+            &mut file,
+            PCAPNG_INTERFACE_DESCRIPTION,
+            &[1, 0, 0, 0, 0, 0, 0, 0],
+        );
+        let mut packet = vec![0; 12];
+        packet.extend_from_slice(&5u32.to_le_bytes());
+        packet.extend_from_slice(&5u32.to_le_bytes());
+        packet.extend_from_slice(b"first");
+        block(&mut file, PCAPNG_ENHANCED_PACKET, &packet);
+        // a comment block, which gets skipped
+        block(&mut file, 0x0BAD, b"comment");
+        let mut packet = 6u32.to_le_bytes().to_vec();
+        packet.extend_from_slice(b"second");
+        block(&mut file, PCAPNG_SIMPLE_PACKET, &packet);
+        // and one from a TUN device
+        block(
+            &mut file,
+            PCAPNG_INTERFACE_DESCRIPTION,
+            &[101, 0, 0, 0, 0, 0, 0, 0],
+        );
+        let mut packet = 1u32.to_le_bytes().to_vec();
+        packet.extend_from_slice(&[0; 8]);
+        packet.extend_from_slice(&5u32.to_le_bytes());
+        packet.extend_from_slice(&5u32.to_le_bytes());
+        packet.extend_from_slice(b"third");
+        block(&mut file, PCAPNG_ENHANCED_PACKET, &packet);
+
+        let pcap = PcapReader::new(&file[..]).unwrap();
+        assert_eq!(
+            frames(pcap),
+            [
+                (LinkType::Ethernet, b"first".to_vec()),
+                (LinkType::Ethernet, b"second".to_vec()),
+                (LinkType::Ip, b"third".to_vec())
+            ]
+        );
+
+        assert!(matches!(
+            PcapReader::new(&b"GIF89a"[..]),
+            Err(Error::NotPcap)
+        ));
+    }
+}
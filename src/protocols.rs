@@ -4,12 +4,35 @@ use thiserror::Error;
 
 use crate::tcpscanner::TcpState;
 
-use self::{query::QueryResponse, raknet::RaknetReponse};
+use self::{query::QueryResponse, raknet::RaknetReponse, slp::SlpResponse};
 
 pub mod query;
 pub mod raknet;
 pub mod slp;
 
+// which protocol a reply was for, the statistics keep a counter for each
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolKind {
+    Query,
+    Raknet,
+    Slp,
+    // TCP protocols from outside this crate
+    Other,
+}
+
+impl ProtocolKind {
+    pub const ALL: [ProtocolKind; 4] = [Self::Query, Self::Raknet, Self::Slp, Self::Other];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Query => "Query",
+            Self::Raknet => "Raknet",
+            Self::Slp => "SLP",
+            Self::Other => "Other",
+        }
+    }
+}
+
 pub enum Protocol<T> {
     Udp(Arc<UdpProtocol>),
     Tcp(Arc<dyn TcpProtocol<T>>),
@@ -51,13 +74,17 @@ where
     }
 }
 
+pub type QueryCallback = Box<dyn Fn(&SocketAddrV4, QueryResponse) + Send + Sync>;
+pub type RaknetCallback = Box<dyn Fn(&SocketAddrV4, RaknetReponse) + Send + Sync>;
+pub type SlpCallback = Box<dyn Fn(&SocketAddrV4, SlpResponse) + Send + Sync>;
+
 pub enum UdpProtocol {
     McQuery {
-        callback: Box<dyn Fn(&SocketAddrV4, QueryResponse) + Send + Sync>,
+        callback: QueryCallback,
         fullstat: bool,
     },
     Raknet {
-        callback: Box<dyn Fn(&SocketAddrV4, RaknetReponse) + Send + Sync>,
+        callback: RaknetCallback,
     },
 }
 
@@ -72,6 +99,13 @@ impl UdpProtocol {
         }
     }
 
+    pub fn kind(&self) -> ProtocolKind {
+        match self {
+            UdpProtocol::McQuery { .. } => ProtocolKind::Query,
+            UdpProtocol::Raknet { .. } => ProtocolKind::Raknet,
+        }
+    }
+
     pub fn default_port(&self) -> u16 {
         match self {
             UdpProtocol::McQuery {
@@ -92,13 +126,24 @@ impl UdpProtocol {
         }
     }
 
+    // where the cookie goes in the initial packet, so it can be sent from a template
+    pub fn cookie_offsets(&self) -> &'static [usize] {
+        match self {
+            UdpProtocol::McQuery {
+                callback: _,
+                fullstat: _,
+            } => query::COOKIE_OFFSETS,
+            UdpProtocol::Raknet { callback: _ } => raknet::COOKIE_OFFSETS,
+        }
+    }
+
     pub fn handle_packet(
         &self,
         send_back: &dyn Fn(Vec<u8>),
         source: &SocketAddrV4,
         cookie: u32,
         packet: &[u8],
-    ) {
+    ) -> Result<(), UdpError> {
         match self {
             UdpProtocol::McQuery { callback, fullstat } => {
                 query::handle_packet(send_back, source, cookie, packet, *fullstat, callback)
@@ -110,10 +155,22 @@ impl UdpProtocol {
     }
 }
 
+#[derive(Error, Debug)]
+pub enum UdpError {
+    #[error("The cookie didn't match")]
+    Cookie,
+    #[error("The packet was malformed")]
+    Malformed,
+    #[error("Unknown packet ID {0:#X}")]
+    UnknownPacket(u8),
+}
+
 #[derive(Error, Debug)]
 pub enum TcpError {
     #[error("The stream wasn't yet complete")]
     Incomplete,
+    #[error("The data was malformed")]
+    Malformed,
 }
 
 pub trait TcpProtocol<T>: Sync + Send
@@ -125,6 +182,11 @@ where
 
     fn name(&self) -> String;
 
+    // what the replies are counted as
+    fn kind(&self) -> ProtocolKind {
+        ProtocolKind::Other
+    }
+
     fn default_port(&self) -> u16;
 
     fn handle_data(
@@ -5,7 +5,13 @@ use std::{
 };
 
 use byteorder::{LittleEndian, ReadBytesExt};
+use serde_derive::{Deserialize, Serialize};
+use tracing::debug;
 
+use super::UdpError;
+use crate::warn_limited;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum QueryResponse {
     Partial {
         motd: String,
@@ -38,18 +44,11 @@ impl QueryResponse {
             // read K,V section
             let mut stream = Cursor::new(response);
             let mut buf = [0; 16];
-            stream.read(&mut buf).unwrap(); // here we can use .unwrap, because we already checked if we ahve the space
+            stream.read_exact(&mut buf).unwrap(); // here we can use .unwrap, because we already checked if we ahve the space
 
             // make sure the marker is correct
             if buf[5..16] != KV_MARKER {
-                println!(
-                    "{:?} != {KV_MARKER:?}",
-                    buf[5..16]
-                        .iter()
-                        .map(|c| format!("0x{c:X}"))
-                        .collect::<Vec<_>>()
-                        .join(", ")
-                );
+                debug!(got = ?&buf[5..16], expected = ?KV_MARKER, "unexpected K,V marker");
             }
 
             let mut kv_section = HashMap::new();
@@ -58,7 +57,7 @@ impl QueryResponse {
                 let Ok(key) = read_string(&mut stream) else {
                     return Err(());
                 };
-                if key.len() == 0 {
+                if key.is_empty() {
                     break;
                 }
                 //value
@@ -70,9 +69,9 @@ impl QueryResponse {
 
             // second marker
             let mut buf = [0; PLAYER_MARKER.len()];
-            stream.read(&mut buf).map_err(|_| ())?;
+            stream.read_exact(&mut buf).map_err(|_| ())?;
             if buf != PLAYER_MARKER {
-                println!("{buf:?} !+ {PLAYER_MARKER:?}");
+                debug!(got = ?buf, expected = ?PLAYER_MARKER, "unexpected player marker");
             }
 
             // players
@@ -82,7 +81,7 @@ impl QueryResponse {
                 let Ok(player) = read_string(&mut stream) else {
                     return Err(());
                 };
-                if player.len() == 0 {
+                if player.is_empty() {
                     break;
                 }
                 players.push(player);
@@ -96,7 +95,7 @@ impl QueryResponse {
             // partial stat
             let mut stream = Cursor::new(response);
             let mut buf = [0; 5];
-            if let Err(_) = stream.read(&mut buf) {
+            if stream.read_exact(&mut buf).is_err() {
                 return Err(());
             }
 
@@ -141,6 +140,9 @@ impl QueryResponse {
     }
 }
 
+// the initial packet is the same for every target except for the session ID, which is the cookie
+pub const COOKIE_OFFSETS: &[usize] = &[3];
+
 pub fn initial_packet(_addr: &SocketAddrV4, cookie: u32) -> Vec<u8> {
     let mut packet = vec![];
     packet.extend_from_slice(&[0xFE, 0xFD]); // magic
@@ -150,83 +152,107 @@ pub fn initial_packet(_addr: &SocketAddrV4, cookie: u32) -> Vec<u8> {
     packet
 }
 
-pub fn handle_packet(
-    send_back: &dyn Fn(Vec<u8>),
-    source: &SocketAddrV4,
-    cookie: u32,
-    packet: &[u8],
-    fullstat: bool,
-    callback: &dyn Fn(&SocketAddrV4, QueryResponse),
-) {
+// what a server can send us, after the cookie has been checked
+#[derive(Debug)]
+pub enum QueryPacket {
+    Challenge(i32),
+    Response(QueryResponse),
+}
+
+// the pure part of handling a packet, doesn't send or log anything
+pub fn parse_packet(packet: &[u8], cookie: u32, fullstat: bool) -> Result<QueryPacket, UdpError> {
     // check if packet can contains enough data
     if packet.len() < 5 {
-        return;
+        return Err(UdpError::Malformed);
     }
 
-    let id = cookie & 0x0F0F0F0F;
     // make sure ID is correct
-    if id.to_be_bytes() != packet[1..=4] {
-        println!(
-            "{:?} != {}",
-            id.to_be_bytes(),
-            packet[1..=4]
-                .iter()
-                .map(|c| format!("{:X}", c))
-                .collect::<Vec<_>>()
-                .join(" ")
-        );
-        return;
+    if session_id(cookie).to_be_bytes() != packet[1..=4] {
+        return Err(UdpError::Cookie);
     }
 
     match packet[0] {
         0x09 => {
             // challenge
             if packet.len() < 6 {
-                return;
+                return Err(UdpError::Malformed);
             }
 
-            let mut token: u32 = 0;
-            for i in 5..packet.len() {
-                if i == packet.len() - 1 && packet[i] == 0 {
-                    break; // we are done
-                }
-                let digit_char = packet[i] as char;
-                let digit: u32 = match digit_char.to_string().parse() {
-                    Ok(val) => val,
-                    Err(_) => return,
-                };
-                token = token * 10 + digit;
-            }
-
-            // send response packet back
-            let mut packet = vec![];
-            packet.extend_from_slice(&[0xFE, 0xFD]); // magic
-            packet.extend_from_slice(&[0x00]); // intention = handshake
-            packet.extend_from_slice(&id.to_be_bytes()); // session ID
-            packet.extend_from_slice(&token.to_be_bytes()); // challenge token
-            if fullstat {
-                packet.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]); // padding
-            }
-
-            send_back(packet);
+            // the token is a null terminated string of a (possibly negative) 32 bit number
+            let token = packet[5..].strip_suffix(&[0]).unwrap_or(&packet[5..]);
+            let token: i32 = std::str::from_utf8(token)
+                .ok()
+                .and_then(|token| token.parse().ok())
+                .ok_or(UdpError::Malformed)?;
+            Ok(QueryPacket::Challenge(token))
         }
         0x00 => {
             // response
-            if let Ok(response) = QueryResponse::parse_response(packet, fullstat) {
-                (callback)(source, response);
-            }
+            let response =
+                QueryResponse::parse_response(packet, fullstat).map_err(|_| UdpError::Malformed)?;
+            Ok(QueryPacket::Response(response))
         }
-        _ => {
-            println!("Unknown packet ID {:X}! (data: {packet:?})", packet[0])
+        id => Err(UdpError::UnknownPacket(id)),
+    }
+}
+
+// the stat request that answers a challenge
+pub fn challenge_response(cookie: u32, token: i32, fullstat: bool) -> Vec<u8> {
+    let mut packet = vec![];
+    packet.extend_from_slice(&[0xFE, 0xFD]); // magic
+    packet.extend_from_slice(&[0x00]); // intention = handshake
+    packet.extend_from_slice(&session_id(cookie).to_be_bytes()); // session ID
+    packet.extend_from_slice(&token.to_be_bytes()); // challenge token
+    if fullstat {
+        packet.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]); // padding
+    }
+    packet
+}
+
+pub fn handle_packet(
+    send_back: &dyn Fn(Vec<u8>),
+    source: &SocketAddrV4,
+    cookie: u32,
+    packet: &[u8],
+    fullstat: bool,
+    callback: &dyn Fn(&SocketAddrV4, QueryResponse),
+) -> Result<(), UdpError> {
+    match parse_packet(packet, cookie, fullstat) {
+        Ok(QueryPacket::Challenge(token)) => {
+            send_back(challenge_response(cookie, token, fullstat));
+            Ok(())
+        }
+        Ok(QueryPacket::Response(response)) => {
+            (callback)(source, response);
+            Ok(())
         }
+        Err(UdpError::Cookie) => {
+            warn_limited!(
+                %source,
+                expected = ?session_id(cookie).to_be_bytes(),
+                got = ?&packet[1..=4],
+                "wrong session ID"
+            );
+            Err(UdpError::Cookie)
+        }
+        Err(UdpError::UnknownPacket(id)) => {
+            warn_limited!(%source, id, data = ?packet, "unknown packet ID");
+            Err(UdpError::UnknownPacket(id))
+        }
+        Err(err) => Err(err),
     }
 }
 
-fn read_string(stream: &mut (dyn Read)) -> io::Result<String> {
+// servers only keep the lower 4 bits of every byte of the session ID
+fn session_id(cookie: u32) -> u32 {
+    cookie & 0x0F0F0F0F
+}
+
+fn read_string(stream: &mut dyn Read) -> io::Result<String> {
     let mut string = String::new();
     let mut buf = [0];
     loop {
-        stream.read(&mut buf)?;
+        stream.read_exact(&mut buf)?;
         if buf[0] == 0 {
             break;
         } else {
@@ -235,3 +261,30 @@ fn read_string(stream: &mut (dyn Read)) -> io::Result<String> {
     }
     Ok(string)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn answer_challenge() {
+        let cookie = 0x1A2B3C4D;
+        let mut packet = vec![0x09];
+        packet.extend_from_slice(&session_id(cookie).to_be_bytes());
+        packet.extend_from_slice(b"-1497233281\0");
+
+        let Ok(QueryPacket::Challenge(token)) = parse_packet(&packet, cookie, true) else {
+            panic!("not a challenge");
+        };
+        assert_eq!(token, -1497233281);
+        assert_eq!(
+            challenge_response(cookie, token, true),
+            [0xFE, 0xFD, 0x00, 0x0A, 0x0B, 0x0C, 0x0D, 0xA6, 0xC2, 0x08, 0x7F, 0, 0, 0, 0]
+        );
+
+        assert!(matches!(
+            parse_packet(&packet, cookie + 1, true),
+            Err(UdpError::Cookie)
+        ));
+    }
+}
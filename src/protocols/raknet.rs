@@ -4,12 +4,17 @@ use std::{
 };
 
 use byteorder::{BigEndian, ReadBytesExt};
+use serde_derive::{Deserialize, Serialize};
+use tracing::debug;
+
+use super::UdpError;
+use crate::warn_limited;
 
 const MAGIC: [u8; 16] = [
     0x00, 0xff, 0xff, 0x00, 0xfe, 0xfe, 0xfe, 0xfe, 0xfd, 0xfd, 0xfd, 0xfd, 0x12, 0x34, 0x56, 0x78,
 ];
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RaknetReponse {
     pub source: String,
     pub edition: String,
@@ -70,7 +75,7 @@ impl RaknetReponse {
         // validate that the GUID is identical
         if let Some(str_guid) = parts.next() {
             if str_guid != format!("{guid}") {
-                println!("GUID doesn't match, {guid} != {str_guid}");
+                debug!(guid, str_guid, "GUID doesn't match");
                 return Err(());
             }
         } else {
@@ -142,7 +147,10 @@ impl RaknetReponse {
     }
 }
 
-pub fn initial_packet(_addr: &std::net::SocketAddrV4, cookie: u32) -> Vec<u8> {
+// the initial packet is the same for every target except for the cookie in the timestamp
+pub const COOKIE_OFFSETS: &[usize] = &[1, 5];
+
+pub fn initial_packet(_addr: &SocketAddrV4, cookie: u32) -> Vec<u8> {
     let mut packet = vec![];
     packet.extend_from_slice(&[0x01]); // packet ID
     packet.extend_from_slice(&cookie.to_be_bytes()); // for some reason the server sends our timestamp back lol
@@ -153,87 +161,105 @@ pub fn initial_packet(_addr: &std::net::SocketAddrV4, cookie: u32) -> Vec<u8> {
     packet
 }
 
-pub fn handle_packet(
-    _send_back: &dyn Fn(Vec<u8>),
-    source: &std::net::SocketAddrV4,
-    cookie: u32,
-    packet: &[u8],
-    callback: &dyn Fn(&SocketAddrV4, RaknetReponse),
-) {
+// the pure part of handling a packet, parses an unconnected pong and checks the cookie in it
+pub fn parse_pong(packet: &[u8], cookie: u32) -> Result<RaknetReponse, UdpError> {
     // remember, we can't use .unwrap() here since then possible attackers could crash our scanner
 
     // size check
-    // 1 (packet ID) + 8 (timestamp) + 8 (server GUID) + MAGIC + 2 (short to the string) = 19
+    // 1 (packet ID) + 8 (timestamp) + 8 (server GUID) + 2 (short to the string) = 19, plus the 16 bytes of MAGIC
+    // and at least one byte of the string itself
     if packet.len() <= 19 + MAGIC.len() {
-        return;
+        return Err(UdpError::Malformed);
     }
 
     let mut stream = Cursor::new(packet);
 
     // packet ID
     let Ok(packet_id) = stream.read_u8() else {
-        return;
+        return Err(UdpError::Malformed);
     };
     if packet_id != 0x1c {
-        return;
+        return Err(UdpError::UnknownPacket(packet_id));
     }
 
     // client timestamp, we store the cookie here
     let Ok(timestamp) = stream.read_u64::<BigEndian>() else {
-        return;
+        return Err(UdpError::Malformed);
     };
 
     if (timestamp & u32::MAX as u64) as u32 != cookie
         || ((timestamp >> 32) & u32::MAX as u64) as u32 != cookie
     // fun fact: the >> operation shifts by bits, not bytes
     {
-        println!(
-            "wrong cookie!, expected 2x {cookie:X}, got {:X} and {:X}",
-            timestamp & u32::MAX as u64,
-            (timestamp >> 32) & u32::MAX as u64
-        );
-        return;
+        return Err(UdpError::Cookie);
     }
 
     // server GUID
     let Ok(guid) = stream.read_u64::<BigEndian>() else {
-        return;
+        return Err(UdpError::Malformed);
     };
 
     // magic
     let Ok(magic) = read_bytes(&mut stream, MAGIC.len()) else {
-        return;
+        return Err(UdpError::Malformed);
     };
     if magic != MAGIC {
-        println!("bad magic");
-        return;
+        debug!("bad magic");
+        return Err(UdpError::Malformed);
     }
 
     // server id
     let Ok(server_id_len) = stream.read_u16::<BigEndian>() else {
-        return;
+        return Err(UdpError::Malformed);
     };
 
     let Ok(server_id) = read_bytes(&mut stream, server_id_len as usize) else {
-        return;
+        return Err(UdpError::Malformed);
     };
 
     let Ok(server_id) = String::from_utf8(server_id) else {
-        return;
+        return Err(UdpError::Malformed);
     };
 
-    let Ok(response) = RaknetReponse::new(guid, server_id) else {
-        return;
-    };
+    RaknetReponse::new(guid, server_id).map_err(|_| UdpError::Malformed)
+}
 
-    callback(source, response);
+pub fn handle_packet(
+    _send_back: &dyn Fn(Vec<u8>),
+    source: &SocketAddrV4,
+    cookie: u32,
+    packet: &[u8],
+    callback: &dyn Fn(&SocketAddrV4, RaknetReponse),
+) -> Result<(), UdpError> {
+    match parse_pong(packet, cookie) {
+        Ok(response) => {
+            callback(source, response);
+            Ok(())
+        }
+        Err(UdpError::Cookie) => {
+            // parse_pong only returns this once the timestamp has been read
+            let timestamp = u64::from_be_bytes(packet[1..9].try_into().unwrap());
+            warn_limited!(
+                %source,
+                expected = format_args!("2x {cookie:X}"),
+                got = format_args!(
+                    "{:X} and {:X}",
+                    timestamp & u32::MAX as u64,
+                    (timestamp >> 32) & u32::MAX as u64
+                ),
+                "wrong cookie"
+            );
+            Err(UdpError::Cookie)
+        }
+        Err(err) => Err(err),
+    }
 }
 
-fn read_bytes(stream: &mut (dyn Read), length: usize) -> io::Result<Vec<u8>> {
+fn read_bytes(stream: &mut dyn Read, length: usize) -> io::Result<Vec<u8>> {
     let mut bytes = vec![];
     for _ in 0..length {
         let mut buf = [0];
-        stream.read(&mut buf)?;
+        stream.read_exact(&mut buf)?;
         bytes.push(buf[0]);
     }
 
@@ -1,26 +1,129 @@
+use std::net::SocketAddrV4;
+
 use byteorder::{BigEndian, WriteBytesExt};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+use tracing::debug;
 
 use crate::{tcpscanner::TcpState, utils};
 
-use super::TcpProtocol;
+use super::{ProtocolKind, SlpCallback, TcpError, TcpProtocol};
+
+// the longest packet a server is allowed to send, a 3 byte VarInt
+const MAX_FRAME_LEN: usize = 2097151;
 
 pub struct MinecraftSlpProtocol {
     hello_packet: Vec<u8>,
+    callback: SlpCallback,
 }
 
 #[derive(Debug, Default)]
 pub struct SlpState {}
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SlpResponse {
+    // the status JSON as the server sent it, servers (and mods) put all kinds of things in there
+    pub status: Value,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SlpError {
+    #[error(transparent)]
+    VarInt(#[from] utils::VarIntTooLong),
+    #[error("packet length {0} is out of range")]
+    BadLength(i32),
+    #[error("unexpected packet ID {0:#X}")]
+    UnknownPacket(i32),
+    #[error("the packet is malformed")]
+    Malformed,
+    #[error("the status isn't valid JSON")]
+    Json,
+}
+
 impl MinecraftSlpProtocol {
-    pub fn new() -> Self {
+    pub fn new(callback: SlpCallback) -> Self {
         Self {
             hello_packet: generate_hello_packet("test", 3, 47),
+            callback,
+        }
+    }
+}
+
+impl SlpResponse {
+    pub fn version(&self) -> Option<&str> {
+        self.status["version"]["name"].as_str()
+    }
+
+    pub fn protocol(&self) -> Option<i64> {
+        self.status["version"]["protocol"].as_i64()
+    }
+
+    pub fn players(&self) -> (Option<i64>, Option<i64>) {
+        (
+            self.status["players"]["online"].as_i64(),
+            self.status["players"]["max"].as_i64(),
+        )
+    }
+
+    // the MOTD without any formatting
+    pub fn description(&self) -> String {
+        let mut text = String::new();
+        chat_text(&self.status["description"], &mut text);
+        text
+    }
+}
+
+// the description is either a plain string or a chat component with children
+fn chat_text(component: &Value, text: &mut String) {
+    match component {
+        Value::String(string) => text.push_str(string),
+        Value::Array(components) => components.iter().for_each(|c| chat_text(c, text)),
+        Value::Object(object) => {
+            if let Some(Value::String(string)) = object.get("text") {
+                text.push_str(string);
+            }
+            if let Some(extra) = object.get("extra") {
+                chat_text(extra, text);
+            }
         }
+        _ => {}
     }
 }
 
+// parses a status response frame from the start of `data`
+// returns the response and the amount of bytes it took, or None when the frame isn't complete yet
+pub fn parse_frame(data: &[u8]) -> Result<Option<(SlpResponse, usize)>, SlpError> {
+    let Some((length, length_size)) = utils::read_varint(data)? else {
+        return Ok(None);
+    };
+    if length < 0 || length as usize > MAX_FRAME_LEN {
+        return Err(SlpError::BadLength(length));
+    }
+    let end = length_size + length as usize;
+    if data.len() < end {
+        return Ok(None);
+    }
+    let frame = &data[length_size..end];
+
+    let (id, id_size) = utils::read_varint(frame)?.ok_or(SlpError::Malformed)?;
+    if id != 0x00 {
+        return Err(SlpError::UnknownPacket(id));
+    }
+    let body = &frame[id_size..];
+
+    let (json_length, json_length_size) = utils::read_varint(body)?.ok_or(SlpError::Malformed)?;
+    let json = usize::try_from(json_length)
+        .ok()
+        .and_then(|json_length| body.get(json_length_size..json_length_size + json_length))
+        .ok_or(SlpError::Malformed)?;
+    let status = serde_json::from_slice(json).map_err(|_| SlpError::Json)?;
+
+    Ok(Some((SlpResponse { status }, end)))
+}
+
 impl TcpProtocol<SlpState> for MinecraftSlpProtocol {
-    fn initial_packet(&self, _dest: &std::net::SocketAddrV4) -> Option<Vec<u8>> {
+    fn initial_packet(&self, _dest: &SocketAddrV4) -> Option<Vec<u8>> {
         Some(self.hello_packet.clone())
     }
 
@@ -28,16 +131,30 @@ impl TcpProtocol<SlpState> for MinecraftSlpProtocol {
         "SLP".to_string()
     }
 
+    fn kind(&self) -> ProtocolKind {
+        ProtocolKind::Slp
+    }
+
     fn default_port(&self) -> u16 {
         25565
     }
 
     fn handle_data(
         &self,
-        _source: &std::net::SocketAddrV4,
-        _state: &mut TcpState<SlpState>,
-    ) -> Result<usize, super::TcpError> {
-        todo!("actually handle the data")
+        source: &SocketAddrV4,
+        state: &mut TcpState<SlpState>,
+    ) -> Result<usize, TcpError> {
+        match parse_frame(&state.data) {
+            Ok(Some((response, length))) => {
+                (self.callback)(source, response);
+                Ok(length)
+            }
+            Ok(None) => Err(TcpError::Incomplete),
+            Err(err) => {
+                debug!(%source, %err, "invalid status response");
+                Err(TcpError::Malformed)
+            }
+        }
     }
 }
 
@@ -67,3 +184,38 @@ fn generate_hello_packet(hostname: &str, port: u16, protocol: i32) -> Vec<u8> {
 
     full_packet
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_status() {
+        let json = r#"{"version":{"name":"1.20.4","protocol":765},"players":{"max":20,"online":3},"description":{"text":"A ","extra":[{"text":"Minecraft Server"}]}}"#;
+        let mut body = vec![0x00];
+        utils::write_varint(&mut body, json.len() as i32);
+        body.extend_from_slice(json.as_bytes());
+        let mut frame = vec![];
+        utils::write_varint(&mut frame, body.len() as i32);
+        frame.extend_from_slice(&body);
+
+        // everything but the last byte isn't enough
+        assert!(parse_frame(&frame[..frame.len() - 1]).unwrap().is_none());
+
+        let (response, length) = parse_frame(&frame).unwrap().unwrap();
+        assert_eq!(length, frame.len());
+        assert_eq!(response.version(), Some("1.20.4"));
+        assert_eq!(response.protocol(), Some(765));
+        assert_eq!(response.players(), (Some(3), Some(20)));
+        assert_eq!(response.description(), "A Minecraft Server");
+
+        assert_eq!(
+            parse_frame(&[0x02, 0x01, 0x00]).err(),
+            Some(SlpError::UnknownPacket(1))
+        );
+        assert_eq!(
+            parse_frame(&[0xFF; 6]).err(),
+            Some(SlpError::VarInt(utils::VarIntTooLong))
+        );
+    }
+}
@@ -0,0 +1,293 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use thiserror::Error;
+use tracing::{error, info, warn};
+
+use crate::{
+    backend::{Backend, FrameReceiver},
+    packet::{self, LinkType},
+    scanner::Shutdown,
+    stats::Stats,
+    warn_limited,
+};
+
+// this many errors in a row that we don't know what to do with and we reopen the channel anyway
+const MAX_OTHER_ERRORS: u32 = 10;
+const MAX_REOPEN_ATTEMPTS: u32 = 8;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+// how many frames can wait for a worker, the capture threads block (and the kernel starts dropping) after that
+const WORKER_QUEUE_SIZE: usize = 4096;
+// how long a worker waits for a frame before it gets to clean up anyway
+const WORKER_IDLE: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvErrorKind {
+    // the read timeout (or a signal), nothing went wrong
+    Timeout,
+    // the kernel dropped packets because we didn't read them fast enough
+    Overrun,
+    // the interface went down or disappeared, the channel has to be reopened
+    InterfaceDown,
+    Other,
+}
+
+impl RecvErrorKind {
+    pub fn classify(err: &io::Error) -> Self {
+        if matches!(
+            err.kind(),
+            io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+        ) {
+            return Self::Timeout;
+        }
+        match err.raw_os_error() {
+            Some(libc::ENOBUFS) => Self::Overrun,
+            Some(libc::ENETDOWN | libc::ENODEV | libc::ENXIO) => Self::InterfaceDown,
+            _ => Self::Other,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("could not reopen the datalink channel after {attempts} attempts: {source}")]
+pub struct Fatal {
+    attempts: u32,
+    source: io::Error,
+}
+
+// keeps the receive side of the datalink channel going, by reopening it when needed
+// when that doesn't work either, the scan gets stopped instead of silently losing every reply from then on
+pub struct RecvErrorHandler {
+    backend: Arc<dyn Backend>,
+    stats: Arc<Stats>,
+    running: Arc<AtomicBool>,
+    shutdown: Shutdown,
+    other_errors: u32,
+}
+
+impl RecvErrorHandler {
+    pub fn new(
+        backend: Arc<dyn Backend>,
+        stats: Arc<Stats>,
+        running: Arc<AtomicBool>,
+        shutdown: Shutdown,
+    ) -> Self {
+        Self {
+            backend,
+            stats,
+            running,
+            shutdown,
+            other_errors: 0,
+        }
+    }
+
+    // whether the receive thread should keep going
+    pub fn running(&self) -> bool {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    pub fn received(&mut self) {
+        self.other_errors = 0;
+    }
+
+    // returns false when the receive thread should give up
+    pub fn handle(&mut self, err: io::Error, rx: &mut Box<dyn FrameReceiver>) -> bool {
+        let result = match RecvErrorKind::classify(&err) {
+            RecvErrorKind::Timeout => Ok(()),
+            RecvErrorKind::Overrun => {
+                Stats::inc(&self.stats.recv_overruns);
+                warn_limited!(%err, "receive buffer overrun, replies were dropped");
+                Ok(())
+            }
+            RecvErrorKind::InterfaceDown => {
+                Stats::inc(&self.stats.recv_interface_down);
+                warn!(%err, "interface went down, reopening the datalink channel");
+                self.reopen(rx)
+            }
+            RecvErrorKind::Other => {
+                Stats::inc(&self.stats.recv_other_errors);
+                warn_limited!(%err, "could not receive packet");
+                self.other_errors += 1;
+                if self.other_errors >= MAX_OTHER_ERRORS {
+                    warn!(
+                        errors = self.other_errors,
+                        "too many receive errors in a row, reopening the datalink channel"
+                    );
+                    self.reopen(rx)
+                } else {
+                    Ok(())
+                }
+            }
+        };
+
+        match result {
+            Ok(()) => true,
+            Err(err) => {
+                error!(%err, "lost the datalink channel, stopping the scan");
+                self.shutdown.fail(err.to_string());
+                false
+            }
+        }
+    }
+
+    fn reopen(&mut self, rx: &mut Box<dyn FrameReceiver>) -> Result<(), Fatal> {
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 1.. {
+            // no use in retrying when the scan is being stopped anyway
+            if !self.running() {
+                return Ok(());
+            }
+            thread::sleep(backoff);
+
+            match self.backend.open_receiver() {
+                Ok(new_rx) => {
+                    *rx = new_rx;
+                    self.other_errors = 0;
+                    Stats::inc(&self.stats.recv_reopens);
+                    info!(attempt, "reopened the datalink channel");
+                    return Ok(());
+                }
+                Err(source) if attempt >= MAX_REOPEN_ATTEMPTS => {
+                    return Err(Fatal {
+                        attempts: attempt,
+                        source,
+                    })
+                }
+                Err(err) => {
+                    warn!(%err, attempt, "could not reopen the datalink channel, retrying in {backoff:?}");
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+        unreachable!()
+    }
+}
+
+// what the workers do with a frame, everything from the link layer up to the protocol
+pub trait FrameHandler: Send + 'static {
+    fn handle_frame(&mut self, frame: &[u8]);
+
+    // called when no frame arrived for WORKER_IDLE, for cleaning up without having to wait for more traffic
+    fn idle(&mut self) {}
+}
+
+// the receive side of a scanner: capture threads that only read the frames off the backend, and workers that parse
+// them and run the protocols
+// frames are handed to the workers by flow, so every connection is handled by one worker in order and the workers
+// can keep the state of their connections to themselves
+pub struct ReceivePool {
+    capture_threads: Vec<JoinHandle<()>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ReceivePool {
+    // every receiver gets a capture thread, and every handler a worker
+    pub fn spawn<H: FrameHandler>(
+        receivers: Vec<Box<dyn FrameReceiver>>,
+        handlers: Vec<H>,
+        link_type: LinkType,
+        errors: impl Fn() -> RecvErrorHandler,
+    ) -> Self {
+        let (queues, workers): (Vec<_>, Vec<_>) = handlers
+            .into_iter()
+            .map(|handler| {
+                let (queue, frames) = mpsc::sync_channel(WORKER_QUEUE_SIZE);
+                (queue, thread::spawn(move || Self::worker(frames, handler)))
+            })
+            .unzip();
+        let capture_threads = receivers
+            .into_iter()
+            .map(|rx| {
+                let queues = queues.clone();
+                let errors = errors();
+                thread::spawn(move || Self::capture_thread(rx, queues, link_type, errors))
+            })
+            .collect();
+
+        Self {
+            capture_threads,
+            workers,
+        }
+    }
+
+    // waits for the threads after the receive side was stopped, the workers first finish what was queued
+    pub fn join(self) {
+        for capture_thread in self.capture_threads {
+            if capture_thread.join().is_err() {
+                error!("Receive thread panicked");
+            }
+        }
+        // the capture threads had the only senders, so the queues are closed now
+        for worker in self.workers {
+            if worker.join().is_err() {
+                error!("Receive worker panicked");
+            }
+        }
+    }
+
+    fn capture_thread(
+        mut rx: Box<dyn FrameReceiver>,
+        queues: Vec<SyncSender<Vec<u8>>>,
+        link_type: LinkType,
+        mut errors: RecvErrorHandler,
+    ) {
+        while errors.running() {
+            match rx.recv() {
+                Ok(frame) => {
+                    errors.received();
+                    let worker = packet::flow_hash(frame, link_type) as usize % queues.len();
+                    if queues[worker].send(frame.to_vec()).is_err() {
+                        break;
+                    }
+                }
+                Err(err) => {
+                    if !errors.handle(err, &mut rx) {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    fn worker(frames: Receiver<Vec<u8>>, mut handler: impl FrameHandler) {
+        loop {
+            match frames.recv_timeout(WORKER_IDLE) {
+                Ok(frame) => handler.handle_frame(&frame),
+                Err(RecvTimeoutError::Timeout) => handler.idle(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn classify_errors() {
+        let timeout = io::Error::new(io::ErrorKind::TimedOut, "Timed out");
+        assert_eq!(RecvErrorKind::classify(&timeout), RecvErrorKind::Timeout);
+        assert_eq!(
+            RecvErrorKind::classify(&io::Error::from_raw_os_error(libc::ENOBUFS)),
+            RecvErrorKind::Overrun
+        );
+        assert_eq!(
+            RecvErrorKind::classify(&io::Error::from_raw_os_error(libc::ENETDOWN)),
+            RecvErrorKind::InterfaceDown
+        );
+        assert_eq!(
+            RecvErrorKind::classify(&io::Error::from_raw_os_error(libc::EINVAL)),
+            RecvErrorKind::Other
+        );
+    }
+}
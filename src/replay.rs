@@ -0,0 +1,221 @@
+use std::{
+    collections::HashMap,
+    io::Read,
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, warn};
+
+use crate::{
+    cookie::Cookies,
+    exchanges::Exchanges,
+    fingerprint::Fingerprint,
+    packet::LinkType,
+    pcap::{self, PcapReader},
+    protocols::Protocol,
+    receiver::FrameHandler,
+    stats::Stats,
+    tcpscanner::TcpReceiver,
+    udpscanner::UdpReceiver,
+};
+
+// feeds the frames of a capture through the same code the receive workers use, without root or a network
+// the cookies have to be made with the seed and start time of the scan that was captured, or every reply gets rejected
+
+enum Receiver<T>
+where
+    T: Default,
+{
+    Udp(UdpReceiver),
+    Tcp(TcpReceiver<T>),
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Summary {
+    pub frames: u64,
+    // packets the scanner would have sent back, like ACKs and query stat requests
+    pub answers: u64,
+    // the capture ended in the middle of a packet, which happens when the capturing process gets killed
+    pub truncated: bool,
+}
+
+pub fn replay<R, T>(
+    capture: &mut PcapReader<R>,
+    protocol: Protocol<T>,
+    fingerprint: &Fingerprint,
+    cookies: Cookies,
+    stats: Arc<Stats>,
+) -> Result<Summary, pcap::Error>
+where
+    R: Read,
+    T: Default + Send + 'static,
+{
+    // the answers get drained after every frame, and a single frame never gets more than a few
+    let (packet_send, answers) = mpsc::sync_channel(64);
+    // nothing ever waits on these, they're only needed by the receivers
+    let exchanges = Exchanges::new(Duration::from_secs(5)).table();
+
+    // the capture could have been made anywhere, so don't filter on our own MAC
+    let mut receiver = match protocol {
+        Protocol::Udp(protocol) => Receiver::Udp(UdpReceiver {
+            mac: None,
+            link_type: LinkType::Ethernet,
+            protocol,
+            packet_send,
+            cookies,
+            stats: stats.clone(),
+            exchanges,
+            capture: None,
+        }),
+        Protocol::Tcp(protocol) => Receiver::Tcp(TcpReceiver {
+            mac: None,
+            link_type: LinkType::Ethernet,
+            protocol,
+            packet_send,
+            cookies,
+            fingerprint: fingerprint.clone(),
+            connection_states: HashMap::new(),
+            stats: stats.clone(),
+            exchanges,
+            capture: None,
+            last_expire: Instant::now(),
+        }),
+    };
+
+    let mut summary = Summary::default();
+    loop {
+        let (link_type, frame) = match capture.next_frame() {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(pcap::Error::Truncated) => {
+                warn!(frames = summary.frames, "the capture is truncated");
+                summary.truncated = true;
+                break;
+            }
+            Err(err) => return Err(err),
+        };
+        summary.frames += 1;
+        // a pcapng file can mix interfaces with and without Ethernet
+        match &mut receiver {
+            Receiver::Udp(receiver) => {
+                receiver.link_type = link_type;
+                receiver.handle_frame(frame)
+            }
+            Receiver::Tcp(receiver) => {
+                receiver.link_type = link_type;
+                receiver.handle_frame(frame)
+            }
+        }
+
+        for answer in answers.try_iter() {
+            Stats::dec(&stats.queue_depth);
+            debug!(?answer, "not sending answer");
+            summary.answers += 1;
+        }
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::SocketAddrV4, sync::Mutex};
+
+    use chrono::Utc;
+    use pnet::packet::{
+        ip::IpNextHeaderProtocols,
+        ipv4::{self, MutableIpv4Packet},
+    };
+
+    use super::*;
+    use crate::{config::PortRange, protocols::UdpProtocol, utils};
+
+    // an Ethernet frame around a UDP datagram, as a pcap record
+    fn record(payload: Vec<u8>, source: &SocketAddrV4, destination: &SocketAddrV4) -> Vec<u8> {
+        let udp = utils::wrap_udp(payload, source, destination);
+        let mut ip = vec![0; 20 + udp.len()];
+        let mut packet = MutableIpv4Packet::new(&mut ip).unwrap();
+        packet.set_version(4);
+        packet.set_header_length(5);
+        packet.set_total_length(20 + udp.len() as u16);
+        packet.set_ttl(64);
+        packet.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        packet.set_source(*source.ip());
+        packet.set_destination(*destination.ip());
+        packet.set_payload(&udp);
+        let checksum = ipv4::checksum(&packet.to_immutable());
+        packet.set_checksum(checksum);
+
+        let mut frame = vec![0x02, 0, 0, 0, 0, 1, 0x02, 0, 0, 0, 0, 2, 0x08, 0x00];
+        frame.extend_from_slice(&ip);
+        let mut record = vec![0; 8];
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        record.extend_from_slice(&frame);
+        record
+    }
+
+    #[test]
+    fn replay_raknet_pong() {
+        let cookies = Cookies {
+            seed: 1234,
+            start_time: Utc::now(),
+            source_ports: PortRange::default(),
+        };
+        let server: SocketAddrV4 = "10.0.0.2:19132".parse().unwrap();
+        let cookie = cookies.cookie(&server);
+        let us = SocketAddrV4::new("10.0.0.1".parse().unwrap(), cookies.source_port(cookie));
+
+        let guid: u64 = 1234567890;
+        let server_id = format!(
+            "MCPE;Dedicated Server;594;1.20.30;0;10;{guid};Bedrock level;Survival;1;19132;19133;"
+        );
+        let mut pong = vec![0x1c];
+        pong.extend_from_slice(&cookie.to_be_bytes());
+        pong.extend_from_slice(&cookie.to_be_bytes());
+        pong.extend_from_slice(&guid.to_be_bytes());
+        pong.extend_from_slice(&[
+            0x00, 0xff, 0xff, 0x00, 0xfe, 0xfe, 0xfe, 0xfe, 0xfd, 0xfd, 0xfd, 0xfd, 0x12, 0x34,
+            0x56, 0x78,
+        ]);
+        pong.extend_from_slice(&(server_id.len() as u16).to_be_bytes());
+        pong.extend_from_slice(server_id.as_bytes());
+
+        let mut file = vec![0xD4, 0xC3, 0xB2, 0xA1, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        file.extend_from_slice(&[0xFF, 0xFF, 0, 0, 1, 0, 0, 0]);
+        file.extend_from_slice(&record(pong.clone(), &server, &us));
+        // the same pong, but from a server we never sent a probe with this cookie to
+        let other = "10.0.0.3:19132".parse().unwrap();
+        let other_us = SocketAddrV4::new(*us.ip(), cookies.source_port(cookies.cookie(&other)));
+        file.extend_from_slice(&record(pong, &other, &other_us));
+
+        let results = Arc::new(Mutex::new(vec![]));
+        let protocol = {
+            let results = results.clone();
+            Protocol::<()>::Udp(Arc::new(UdpProtocol::Raknet {
+                callback: Box::new(move |addr, response| {
+                    results.lock().unwrap().push((*addr, response.motd))
+                }),
+            }))
+        };
+        let stats = Arc::new(Stats::default());
+        let mut capture = PcapReader::new(&file[..]).unwrap();
+        let summary = replay(
+            &mut capture,
+            protocol,
+            &Fingerprint::nintendo_3ds(),
+            cookies,
+            stats.clone(),
+        )
+        .unwrap();
+
+        assert_eq!(summary.frames, 2);
+        assert!(!summary.truncated);
+        assert_eq!(
+            *results.lock().unwrap(),
+            [(server, "Dedicated Server".to_string())]
+        );
+        assert_eq!(Stats::get(&stats.cookie_failures), 1);
+    }
+}
@@ -0,0 +1,464 @@
+use std::{
+    io,
+    net::SocketAddrV4,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use thiserror::Error;
+use tracing::info;
+
+use crate::{
+    backend::{datalink::PnetBackend, Backend},
+    capture::Capture,
+    config::PortRange,
+    cookie::Cookies,
+    exchanges::Exchanges,
+    fingerprint::Fingerprint,
+    frame,
+    interface::MyInterface,
+    output::Output,
+    protocols::Protocol,
+    sender::{Pacer, SendOptions, Senders},
+    stats::Stats,
+    targets::TargetIter,
+    tcpscanner::TcpScanner,
+    udpscanner::UdpScanner,
+};
+
+// how long to keep handling replies after the last probe was sent
+// without this slow responses get lost (or attributed to the next scan)
+#[derive(Debug, Clone, Copy)]
+pub struct Cooldown {
+    // always wait this long, for the replies to the last probes
+    pub min: Duration,
+    // after that, stop once no replies arrived for this long and no exchanges are outstanding
+    pub idle: Duration,
+    // but never wait longer than this
+    pub max: Duration,
+    // give up on a multi-step exchange when the server didn't answer for this long
+    pub exchange_timeout: Duration,
+}
+
+impl Default for Cooldown {
+    fn default() -> Self {
+        Self {
+            min: Duration::from_secs(2),
+            idle: Duration::from_secs(2),
+            max: Duration::from_secs(10),
+            exchange_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+// the way to set up a scanner when using badscan as a library, everything is passed in as values
+//
+// let scanner = ScannerBuilder::new(protocol)
+//     .seed(1234)
+//     .rate(1000)
+//     .build()?;
+pub struct ScannerBuilder<T> {
+    protocol: Protocol<T>,
+    interface: Option<MyInterface>,
+    backend: Option<Arc<dyn Backend>>,
+    fingerprint: Fingerprint,
+    seed: i64,
+    start_time: Option<DateTime<Utc>>,
+    source_ports: PortRange,
+    rate: Option<u64>,
+    senders: usize,
+    send_cpus: Vec<usize>,
+    queue_size: usize,
+    workers: usize,
+    cooldown: Cooldown,
+    stats: Option<Arc<Stats>>,
+    output: Option<Output>,
+    capture: Option<Capture>,
+    shutdown: Shutdown,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("source port range {0} is empty")]
+    EmptyPortRange(PortRange),
+    #[error("rate limit can't be 0")]
+    ZeroRate,
+    #[error("need at least one send thread")]
+    NoSenders,
+    #[error("send queue size can't be 0")]
+    ZeroQueueSize,
+    #[error("need at least one receive worker")]
+    NoWorkers,
+    #[error("could not get the default interface: {0}")]
+    NoInterface(String),
+    #[error("could not open the backend: {0}")]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Frame(#[from] frame::Error),
+}
+
+impl<T> ScannerBuilder<T>
+where
+    T: Default + Send + 'static,
+{
+    pub fn new(protocol: Protocol<T>) -> Self {
+        Self {
+            protocol,
+            interface: None,
+            backend: None,
+            fingerprint: Fingerprint::nintendo_3ds(),
+            seed: 0,
+            start_time: None,
+            source_ports: PortRange::default(),
+            rate: None,
+            senders: 1,
+            send_cpus: Vec::new(),
+            queue_size: 65536,
+            workers: 1,
+            cooldown: Cooldown::default(),
+            stats: None,
+            output: None,
+            capture: None,
+            shutdown: Shutdown::default(),
+        }
+    }
+
+    // defaults to the interface of the default route
+    pub fn interface(mut self, interface: MyInterface) -> Self {
+        self.interface = Some(interface);
+        self
+    }
+
+    // how frames get sent and received, defaults to a pnet datalink channel on the interface
+    pub fn backend(mut self, backend: Arc<dyn Backend>) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    // only used for TCP protocols
+    pub fn fingerprint(mut self, fingerprint: Fingerprint) -> Self {
+        self.fingerprint = fingerprint;
+        self
+    }
+
+    pub fn seed(mut self, seed: i64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    // defaults to now, set this to the start time of an earlier run to keep accepting its replies
+    pub fn start_time(mut self, start_time: DateTime<Utc>) -> Self {
+        self.start_time = Some(start_time);
+        self
+    }
+
+    pub fn source_ports(mut self, source_ports: PortRange) -> Self {
+        self.source_ports = source_ports;
+        self
+    }
+
+    // maximum amount of probes per second, unlimited by default
+    pub fn rate(mut self, rate: u64) -> Self {
+        self.rate = Some(rate);
+        self
+    }
+
+    // how many threads send the probes of `scan_all`, each one to its own share of the targets
+    pub fn senders(mut self, senders: usize) -> Self {
+        self.senders = senders;
+        self
+    }
+
+    // pin the send threads to these CPUs, they're handed out in turn
+    pub fn send_cpus(mut self, send_cpus: Vec<usize>) -> Self {
+        self.send_cpus = send_cpus;
+        self
+    }
+
+    // how many frames can be waiting for the send thread of the answers, the receivers block when it's full
+    pub fn queue_size(mut self, queue_size: usize) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    // how many threads parse the replies and run the protocol, every one handles its own share of the servers
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    pub fn cooldown(mut self, cooldown: Cooldown) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+
+    // share the counters with something else, like the metrics server
+    pub fn stats(mut self, stats: Arc<Stats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    // gets flushed when the scanner finishes
+    pub fn output(mut self, output: Output) -> Self {
+        self.output = Some(output);
+        self
+    }
+
+    // write the frames we send and the replies we handle to pcapng files
+    pub fn capture(mut self, capture: Capture) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    pub fn shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub fn build(self) -> Result<Scanner<T>, Error> {
+        if self.source_ports.is_empty() {
+            return Err(Error::EmptyPortRange(self.source_ports));
+        }
+        if self.rate == Some(0) {
+            return Err(Error::ZeroRate);
+        }
+        if self.senders == 0 {
+            return Err(Error::NoSenders);
+        }
+        if self.workers == 0 {
+            return Err(Error::NoWorkers);
+        }
+        // the receive workers would wait for the send thread on every answer
+        if self.queue_size == 0 {
+            return Err(Error::ZeroQueueSize);
+        }
+
+        let interface = match self.interface {
+            Some(interface) => interface,
+            None => MyInterface::get_default().map_err(Error::NoInterface)?,
+        };
+        let backend = self
+            .backend
+            .unwrap_or_else(|| Arc::new(PnetBackend::new(interface.network_interface.clone())));
+        let cookies = Cookies {
+            seed: self.seed,
+            start_time: self.start_time.unwrap_or_else(Utc::now),
+            source_ports: self.source_ports,
+        };
+        let stats = self.stats.unwrap_or_default();
+        let exchanges = Exchanges::new(self.cooldown.exchange_timeout);
+
+        let inner = match self.protocol {
+            Protocol::Udp(protocol) => Inner::Udp(UdpScanner::new(
+                &interface,
+                &backend,
+                protocol,
+                &self.fingerprint,
+                cookies,
+                stats.clone(),
+                exchanges.table(),
+                self.shutdown.clone(),
+                self.capture.clone(),
+                self.queue_size,
+                self.workers,
+            )?),
+            Protocol::Tcp(protocol) => Inner::Tcp(TcpScanner::new(
+                &interface,
+                &backend,
+                protocol,
+                &self.fingerprint,
+                cookies,
+                stats.clone(),
+                exchanges.table(),
+                self.shutdown.clone(),
+                self.capture.clone(),
+                self.queue_size,
+                self.workers,
+            )?),
+        };
+
+        Ok(Scanner {
+            inner,
+            backend,
+            pacer: self.rate.map(|rate| Pacer::new(rate as f64)),
+            rate: self.rate,
+            senders: self.senders,
+            send_cpus: self.send_cpus,
+            cookies,
+            cooldown: self.cooldown,
+            stats,
+            exchanges,
+            output: self.output,
+            capture: self.capture,
+            shutdown: self.shutdown,
+        })
+    }
+}
+
+// a running scanner, either UDP or TCP depending on the protocol
+pub struct Scanner<T>
+where
+    T: Default,
+{
+    inner: Inner<T>,
+    backend: Arc<dyn Backend>,
+    // only for `scan`, the send threads of `scan_all` have their own
+    pacer: Option<Pacer>,
+    rate: Option<u64>,
+    senders: usize,
+    send_cpus: Vec<usize>,
+    cookies: Cookies,
+    cooldown: Cooldown,
+    stats: Arc<Stats>,
+    exchanges: Arc<Exchanges>,
+    output: Option<Output>,
+    capture: Option<Capture>,
+    shutdown: Shutdown,
+}
+
+enum Inner<T>
+where
+    T: Default,
+{
+    Udp(UdpScanner),
+    Tcp(TcpScanner<T>),
+}
+
+impl<T> Scanner<T>
+where
+    T: Default + Send + 'static,
+{
+    // sends the probe for one target, blocks when we're going faster than the rate limit
+    pub fn scan(&mut self, addr: SocketAddrV4) {
+        if let Some(pacer) = &mut self.pacer {
+            pacer.wait();
+        }
+        match &mut self.inner {
+            Inner::Udp(scanner) => scanner.scan(addr),
+            Inner::Tcp(scanner) => scanner.scan(addr),
+        }
+    }
+
+    // sends the probes for all targets from the send threads, and returns right away
+    // probes sent with `scan` in the meantime are paced separately, the rate limit applies to both on their own
+    pub fn scan_all(&self, targets: TargetIter) -> io::Result<Senders> {
+        let template = match &self.inner {
+            Inner::Udp(scanner) => scanner.template(),
+            Inner::Tcp(scanner) => scanner.template(),
+        };
+        Senders::spawn(
+            targets,
+            template,
+            &self.backend,
+            SendOptions {
+                threads: self.senders,
+                cpus: self.send_cpus.clone(),
+                rate: self.rate,
+                cookies: self.cookies,
+                stats: self.stats.clone(),
+                shutdown: self.shutdown.clone(),
+                capture: self.capture.clone(),
+            },
+        )
+    }
+
+    pub fn start_time(&self) -> DateTime<Utc> {
+        self.cookies.start_time
+    }
+
+    pub fn stats(&self) -> &Arc<Stats> {
+        &self.stats
+    }
+
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    // stops sending, keeps handling replies during the cooldown, and then stops the threads and flushes the output
+    pub fn finish(self) {
+        info!(
+            outstanding = self.exchanges.outstanding(),
+            "Waiting for the last replies..."
+        );
+        let start = Instant::now();
+        let replies_before = self.stats.total_replies();
+        let mut replies = replies_before;
+        let mut last_reply = start;
+        loop {
+            let now = Instant::now();
+            let total = self.stats.total_replies();
+            if total != replies {
+                replies = total;
+                last_reply = now;
+            }
+
+            if self.shutdown.skip_cooldown() || now - start >= self.cooldown.max {
+                break;
+            }
+            // replies that are still coming in extend the wait, as do servers that owe us another one
+            if now - start >= self.cooldown.min
+                && now - last_reply >= self.cooldown.idle
+                && self.exchanges.outstanding() == 0
+            {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        info!(
+            replies = replies - replies_before,
+            outstanding = self.exchanges.outstanding(),
+            "Cooldown done after {:.1}s",
+            start.elapsed().as_secs_f64()
+        );
+
+        match self.inner {
+            Inner::Udp(scanner) => scanner.finish(),
+            Inner::Tcp(scanner) => scanner.finish(),
+        }
+        if let Some(output) = &self.output {
+            output.flush();
+        }
+        if let Some(capture) = &self.capture {
+            capture.flush();
+        }
+    }
+}
+
+// shared flag to end a scan early, e.g. from a Ctrl-C handler
+// the first stop ends the sending (the replies still get a chance to arrive), the second one also skips the cooldown
+#[derive(Clone, Default, Debug)]
+pub struct Shutdown {
+    stops: Arc<AtomicU8>,
+    // why the scanner stopped itself
+    error: Arc<Mutex<Option<String>>>,
+}
+
+impl Shutdown {
+    // returns how many times this has been stopped now
+    pub fn stop(&self) -> u8 {
+        self.stops.fetch_add(1, Ordering::Relaxed).saturating_add(1)
+    }
+
+    // for when the scanner can't go on, there's no point in a cooldown then either
+    pub fn fail(&self, reason: String) {
+        self.error.lock().unwrap().get_or_insert(reason);
+        self.stops.fetch_max(2, Ordering::Relaxed);
+    }
+
+    pub fn error(&self) -> Option<String> {
+        self.error.lock().unwrap().clone()
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stops.load(Ordering::Relaxed) >= 1
+    }
+
+    fn skip_cooldown(&self) -> bool {
+        self.stops.load(Ordering::Relaxed) >= 2
+    }
+}
@@ -0,0 +1,306 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use tracing::{error, warn};
+
+use crate::{
+    backend::{Backend, FrameSender},
+    capture::Capture,
+    cookie::Cookies,
+    frame::FrameTemplate,
+    scanner::Shutdown,
+    stats::Stats,
+    targets::TargetIter,
+    warn_limited,
+};
+
+// the threads sending the probes of a scan, every one of them goes through its own share of the permutation
+// a single thread building and sending every frame is as fast as a scan can go otherwise
+// the answers to servers don't go through here, those are still queued for the scanner's send thread
+pub struct Senders {
+    threads: Vec<JoinHandle<()>>,
+    // where every thread is in the permutation
+    positions: Vec<Arc<AtomicU64>>,
+}
+
+// everything the send threads share
+pub struct SendOptions {
+    pub threads: usize,
+    // the threads get pinned to these CPUs in turn, not pinned at all when it's empty
+    pub cpus: Vec<usize>,
+    // for all threads together
+    pub rate: Option<u64>,
+    pub cookies: Cookies,
+    pub stats: Arc<Stats>,
+    pub shutdown: Shutdown,
+    pub capture: Option<Capture>,
+}
+
+impl Senders {
+    pub fn spawn(
+        targets: TargetIter,
+        template: &FrameTemplate,
+        backend: &Arc<dyn Backend>,
+        options: SendOptions,
+    ) -> io::Result<Self> {
+        let threads = options.threads.max(1);
+        let mut senders = Self {
+            threads: Vec::with_capacity(threads),
+            positions: Vec::with_capacity(threads),
+        };
+        // every sender is opened before the first thread starts, so none of them is already sending when one fails
+        let network_txs = (0..threads)
+            .map(|_| backend.open_sender())
+            .collect::<io::Result<Vec<_>>>()?;
+        let parts = targets.split(threads as u64).into_iter().zip(network_txs);
+        for (index, (targets, network_tx)) in parts.enumerate() {
+            let position = Arc::new(AtomicU64::new(targets.position()));
+            let sender = ProbeSender {
+                targets,
+                template: template.clone(),
+                network_tx,
+                pacer: options
+                    .rate
+                    .map(|rate| Pacer::new(rate as f64 / threads as f64)),
+                cookies: options.cookies,
+                stats: options.stats.clone(),
+                shutdown: options.shutdown.clone(),
+                capture: options.capture.clone(),
+                position: position.clone(),
+            };
+            let cpu = (!options.cpus.is_empty()).then(|| options.cpus[index % options.cpus.len()]);
+            let thread = thread::Builder::new()
+                .name(format!("sender-{index}"))
+                .spawn(move || {
+                    if let Some(cpu) = cpu {
+                        if let Err(err) = pin(cpu) {
+                            warn!("Could not pin send thread {index} to CPU {cpu}: {err}");
+                        }
+                    }
+                    sender.run()
+                });
+            match thread {
+                Ok(thread) => senders.threads.push(thread),
+                // the threads that did start would keep sending their share otherwise
+                Err(err) => {
+                    options
+                        .shutdown
+                        .fail(format!("could not start send thread: {err}"));
+                    senders.join();
+                    return Err(err);
+                }
+            }
+            senders.positions.push(position);
+        }
+        Ok(senders)
+    }
+
+    // every target before this position in the permutation got its probe, so a resume continues here
+    // the threads that got further ahead send some of their probes twice then
+    pub fn position(&self) -> u64 {
+        self.positions
+            .iter()
+            .map(|position| position.load(Ordering::Relaxed))
+            .min()
+            .unwrap_or_default()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.threads.iter().all(|thread| thread.is_finished())
+    }
+
+    // waits for every probe to be sent (or the scan to be stopped), and returns the final position
+    pub fn join(mut self) -> u64 {
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                error!("Send thread panicked");
+            }
+        }
+        self.position()
+    }
+}
+
+struct ProbeSender {
+    targets: TargetIter,
+    template: FrameTemplate,
+    network_tx: Box<dyn FrameSender>,
+    pacer: Option<Pacer>,
+    cookies: Cookies,
+    stats: Arc<Stats>,
+    shutdown: Shutdown,
+    capture: Option<Capture>,
+    position: Arc<AtomicU64>,
+}
+
+impl ProbeSender {
+    fn run(mut self) {
+        // where the first probe that couldn't be sent was, a resume has to start there again
+        let mut first_failure = None;
+        while !self.shutdown.is_stopped() {
+            let before = self.targets.position();
+            let Some(target) = self.targets.next() else {
+                break;
+            };
+            if let Some(pacer) = &mut self.pacer {
+                pacer.wait();
+            }
+
+            let cookie = self.cookies.cookie(&target);
+            let frame = self
+                .template
+                .probe(&target, self.cookies.source_port(cookie), cookie);
+            if let Some(capture) = &self.capture {
+                capture.write(frame);
+            }
+            match self.network_tx.send(frame) {
+                Ok(()) => {
+                    Stats::inc(&self.stats.probes_sent);
+                    Stats::inc(&self.stats.packets_sent);
+                    Stats::inc(&self.stats.targets_done);
+                }
+                Err(err) => {
+                    warn_limited!(%err, "could not send packet");
+                    Stats::inc(&self.stats.send_errors);
+                    first_failure.get_or_insert(before);
+                }
+            }
+            self.position.store(
+                first_failure.unwrap_or(self.targets.position()),
+                Ordering::Relaxed,
+            );
+        }
+        // whatever the backend batched up still has to go out
+        if let Err(err) = self.network_tx.flush() {
+            warn_limited!(%err, "could not send packets");
+        }
+    }
+}
+
+// spaces the probes out evenly, instead of sending bursts every second
+pub struct Pacer {
+    rate: f64,
+    start: Instant,
+    sent: u64,
+}
+
+impl Pacer {
+    pub fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            start: Instant::now(),
+            sent: 0,
+        }
+    }
+
+    pub fn wait(&mut self) {
+        let due = self.start + Duration::from_secs_f64(self.sent as f64 / self.rate);
+        if let Some(wait) = due.checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+        self.sent += 1;
+    }
+}
+
+// keeps the calling thread on one CPU, so it doesn't fight the receive threads (or the NIC's interrupts) for one
+#[cfg(target_os = "linux")]
+fn pin(cpu: usize) -> io::Result<()> {
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no such CPU"));
+    }
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin(_cpu: usize) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "pinning threads is only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+    use pnet::{datalink::NetworkInterface, ipnetwork::IpNetwork, util::MacAddr};
+
+    use super::*;
+    use crate::{
+        config::PortRange, interface::MyInterface, neighbours::Neighbours, packet::LinkType,
+        targets::TargetSpace,
+    };
+
+    // fails every send after the first `ok` ones
+    struct FlakySender {
+        ok: usize,
+    }
+
+    impl FrameSender for FlakySender {
+        fn send(&mut self, _frame: &[u8]) -> io::Result<()> {
+            if self.ok == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.ok -= 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_sends_are_not_skipped() {
+        let interface = MyInterface {
+            network_interface: NetworkInterface {
+                name: "test".to_string(),
+                description: String::new(),
+                index: 1,
+                mac: Some(MacAddr(2, 0, 0, 0, 0, 1)),
+                ips: vec![IpNetwork::V4("10.0.0.2/24".parse().unwrap())],
+                flags: 0,
+            },
+            gateway_mac: Some(MacAddr(2, 0, 0, 0, 0, 2)),
+            gateway_ip: None,
+            neighbours: Neighbours::default(),
+        };
+        let space = TargetSpace::new(vec!["10.0.1.0/28".parse().unwrap()], vec![25565]).unwrap();
+        let stats = Arc::new(Stats::default());
+        let position = Arc::new(AtomicU64::new(0));
+        let sender = ProbeSender {
+            targets: TargetIter::new(space, 1),
+            template: FrameTemplate::udp(&interface, LinkType::Ethernet, 64, &[0; 8], &[]).unwrap(),
+            network_tx: Box::new(FlakySender { ok: 5 }),
+            pacer: None,
+            cookies: Cookies {
+                seed: 1,
+                start_time: Utc::now(),
+                source_ports: PortRange {
+                    start: 61000,
+                    end: 61999,
+                },
+            },
+            stats: stats.clone(),
+            shutdown: Shutdown::default(),
+            capture: None,
+            position: position.clone(),
+        };
+        sender.run();
+
+        assert_eq!(Stats::get(&stats.probes_sent), 5);
+        assert_eq!(Stats::get(&stats.targets_done), 5);
+        assert_eq!(Stats::get(&stats.packets_sent), 5);
+        assert_eq!(Stats::get(&stats.send_errors), 11);
+        // a resume starts with the first target that didn't go out
+        assert_eq!(position.load(Ordering::Relaxed), 5);
+    }
+}
@@ -0,0 +1,175 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use tracing::{error, info};
+
+use crate::{packet::DropReason, protocols::ProtocolKind};
+
+// counters shared between the scanner threads, everything is relaxed since we only need rough numbers
+#[derive(Default, Debug)]
+pub struct Stats {
+    // probes to new targets
+    pub probes_sent: AtomicU64,
+    // every packet we put on the wire, including ACKs, RSTs and follow-up packets
+    pub packets_sent: AtomicU64,
+    // packets the backend refused to send, these aren't in packets_sent
+    pub send_errors: AtomicU64,
+    // packets that passed cookie validation, indexed by ProtocolKind
+    replies: [AtomicU64; ProtocolKind::ALL.len()],
+    pub results: AtomicU64,
+    pub cookie_failures: AtomicU64,
+    pub parse_failures: AtomicU64,
+    pub rsts: AtomicU64,
+    // RSTs the kernel sent from our source ports, anything but 0 means the firewall rule isn't doing its job
+    pub kernel_rsts: AtomicU64,
+    // receive errors, the datalink channel gets reopened when it breaks
+    pub recv_overruns: AtomicU64,
+    pub recv_interface_down: AtomicU64,
+    pub recv_other_errors: AtomicU64,
+    pub recv_reopens: AtomicU64,
+    // packets dropped before they got to the protocol, by reason
+    pub drops_truncated: AtomicU64,
+    pub drops_bad_header: AtomicU64,
+    pub drops_fragment: AtomicU64,
+    pub drops_checksum: AtomicU64,
+    pub tcp_connections: AtomicU64,
+    // packets waiting for the send thread
+    pub queue_depth: AtomicU64,
+    // progress through the targets
+    pub targets_done: AtomicU64,
+    pub targets_total: AtomicU64,
+}
+
+impl Stats {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(counter: &AtomicU64) {
+        counter.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped(&self, reason: DropReason) {
+        Self::inc(match reason {
+            DropReason::Truncated => &self.drops_truncated,
+            DropReason::BadHeader => &self.drops_bad_header,
+            DropReason::Fragment => &self.drops_fragment,
+            DropReason::BadChecksum => &self.drops_checksum,
+        });
+    }
+
+    pub fn reply(&self, protocol: ProtocolKind) {
+        Self::inc(&self.replies[protocol as usize]);
+    }
+
+    // only the protocols that got replies
+    pub fn replies(&self) -> BTreeMap<&'static str, u64> {
+        ProtocolKind::ALL
+            .into_iter()
+            .map(|protocol| (protocol.name(), Self::get(&self.replies[protocol as usize])))
+            .filter(|(_, count)| *count > 0)
+            .collect()
+    }
+
+    pub fn total_replies(&self) -> u64 {
+        self.replies.iter().map(Self::get).sum()
+    }
+}
+
+// prints a status line every `interval`
+pub struct StatusReporter {
+    stats: Arc<Stats>,
+    interval: Duration,
+}
+
+impl StatusReporter {
+    pub fn new(stats: Arc<Stats>, interval: Duration) -> Self {
+        Self { stats, interval }
+    }
+
+    pub fn spawn(self) -> JoinHandle<()> {
+        thread::spawn(move || self.run())
+    }
+
+    fn run(self) {
+        let start = Instant::now();
+        let mut last_probes = 0;
+        let mut last_time = start;
+        let mut last_kernel_rsts = 0;
+        loop {
+            thread::sleep(self.interval);
+
+            let now = Instant::now();
+            let probes = Stats::get(&self.stats.probes_sent);
+            let pps = (probes - last_probes) as f64 / (now - last_time).as_secs_f64();
+            last_probes = probes;
+            last_time = now;
+
+            info!("{}", self.status_line(now - start, pps));
+            // keep reminding, a single warning at the start of a long scan is easy to miss
+            let kernel_rsts = Stats::get(&self.stats.kernel_rsts);
+            if kernel_rsts > last_kernel_rsts {
+                error!("The kernel sent {kernel_rsts} RSTs from our scanning ports so far, TCP results are being lost!");
+            }
+            last_kernel_rsts = kernel_rsts;
+        }
+    }
+
+    fn status_line(&self, elapsed: Duration, pps: f64) -> String {
+        let stats = &self.stats;
+        let done = Stats::get(&stats.targets_done);
+        let total = Stats::get(&stats.targets_total);
+        let percentage = if total == 0 {
+            100.0
+        } else {
+            done as f64 / total as f64 * 100.0
+        };
+
+        let replies = stats
+            .replies()
+            .iter()
+            .map(|(protocol, count)| format!("{protocol} {count}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let eta = if done >= total {
+            "done".to_string()
+        } else if pps > 0.0 {
+            format_duration(Duration::from_secs_f64((total - done) as f64 / pps))
+        } else {
+            "?".to_string()
+        };
+
+        format!(
+            "[{}] {done}/{total} targets ({percentage:.1}%) | {pps:.0} pps | sent {} packets | replies: {} | results {} | cookie failures {} | parse failures {} | RSTs {} | queue {} | ETA {eta}",
+            format_duration(elapsed),
+            Stats::get(&stats.packets_sent),
+            if replies.is_empty() { "none".to_string() } else { replies },
+            Stats::get(&stats.results),
+            Stats::get(&stats.cookie_failures),
+            Stats::get(&stats.parse_failures),
+            Stats::get(&stats.rsts),
+            Stats::get(&stats.queue_depth),
+        )
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}",
+        secs / 3600,
+        (secs / 60) % 60,
+        secs % 60
+    )
+}
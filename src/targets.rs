@@ -0,0 +1,529 @@
+use std::{
+    collections::BTreeSet,
+    fmt::Display,
+    net::{Ipv4Addr, SocketAddrV4},
+    str::FromStr,
+};
+
+use pnet::ipnetwork::Ipv4Network;
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::utils::stable_hash;
+
+// a range of IPv4 addresses, written as either a single address or in CIDR notation
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct Ipv4Range {
+    pub start: Ipv4Addr,
+    pub len: u64,
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid IPv4 address `{0}`")]
+    InvalidAddress(String),
+    #[error("invalid prefix length `{0}`")]
+    InvalidPrefix(String),
+    #[error("no targets to scan")]
+    Empty,
+    #[error("invalid shard `{0}`, expected `i/n` with i < n")]
+    InvalidShard(String),
+}
+
+// the part of the target space this instance scans, written as `i/n` (zero-based)
+// all instances have to use the same seed, so they agree on the permutation
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Shard {
+    pub index: u64,
+    pub count: u64,
+}
+
+impl FromStr for Shard {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidShard(s.to_string());
+        let (index, count) = s.split_once('/').ok_or_else(invalid)?;
+        let index: u64 = index.trim().parse().map_err(|_| invalid())?;
+        let count: u64 = count.trim().parse().map_err(|_| invalid())?;
+        if index >= count {
+            return Err(invalid());
+        }
+
+        Ok(Self { index, count })
+    }
+}
+
+impl TryFrom<String> for Shard {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Shard> for String {
+    fn from(shard: Shard) -> Self {
+        shard.to_string()
+    }
+}
+
+impl Display for Shard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+impl FromStr for Ipv4Range {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: Ipv4Addr = addr
+            .trim()
+            .parse()
+            .map_err(|_| Error::InvalidAddress(addr.to_string()))?;
+        let prefix: u32 = match prefix {
+            Some(prefix) => match prefix.trim().parse() {
+                Ok(prefix) if prefix <= 32 => prefix,
+                _ => return Err(Error::InvalidPrefix(prefix.to_string())),
+            },
+            None => 32,
+        };
+
+        // mask off the host part, so 10.0.0.5/8 becomes 10.0.0.0/8
+        let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+        Ok(Self {
+            start: Ipv4Addr::from(u32::from(addr) & mask),
+            len: 1 << (32 - prefix),
+        })
+    }
+}
+
+impl TryFrom<String> for Ipv4Range {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Display for Ipv4Range {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let prefix = 32 - self.len.trailing_zeros();
+        write!(f, "{}/{prefix}", self.start)
+    }
+}
+
+// every (address, port) pair we want to scan, addressable by index
+#[derive(Debug, Clone)]
+pub struct TargetSpace {
+    targets: Targets,
+}
+
+#[derive(Debug, Clone)]
+enum Targets {
+    // every port on every address in the ranges
+    Ranges {
+        ranges: Vec<Ipv4Range>,
+        ports: Vec<u16>,
+        addresses: u64,
+    },
+    // an explicit list, for example servers found by an earlier scan
+    List(Vec<SocketAddrV4>),
+}
+
+impl TargetSpace {
+    pub fn new(ranges: Vec<Ipv4Range>, ports: Vec<u16>) -> Result<Self, Error> {
+        let addresses = ranges.iter().map(|range| range.len).sum();
+        if addresses == 0 || ports.is_empty() {
+            return Err(Error::Empty);
+        }
+
+        Ok(Self {
+            targets: Targets::Ranges {
+                ranges,
+                ports,
+                addresses,
+            },
+        })
+    }
+
+    pub fn from_list(addrs: Vec<SocketAddrV4>) -> Result<Self, Error> {
+        if addrs.is_empty() {
+            return Err(Error::Empty);
+        }
+
+        Ok(Self {
+            targets: Targets::List(addrs),
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        match &self.targets {
+            Targets::Ranges {
+                ports, addresses, ..
+            } => addresses * ports.len() as u64,
+            Targets::List(addrs) => addrs.len() as u64,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // stable hashes of the addresses and of the ports scanned on every one of them, checkpoints keep these to notice
+    // other targets that happen to have the same count
+    // the ports of an explicit list are part of its addresses
+    pub fn address_hash(&self) -> u64 {
+        let mut data = Vec::new();
+        match &self.targets {
+            Targets::Ranges { ranges, .. } => {
+                for range in ranges {
+                    data.extend_from_slice(&range.start.octets());
+                    data.extend_from_slice(&range.len.to_le_bytes());
+                }
+            }
+            Targets::List(addrs) => {
+                for addr in addrs {
+                    data.extend_from_slice(&addr.ip().octets());
+                    data.extend_from_slice(&addr.port().to_le_bytes());
+                }
+            }
+        }
+        stable_hash(&data)
+    }
+
+    pub fn port_hash(&self) -> u64 {
+        let ports = match &self.targets {
+            Targets::Ranges { ports, .. } => ports.as_slice(),
+            Targets::List(_) => &[],
+        };
+        let data: Vec<u8> = ports.iter().flat_map(|port| port.to_le_bytes()).collect();
+        stable_hash(&data)
+    }
+
+    pub fn get(&self, index: u64) -> SocketAddrV4 {
+        let (ranges, ports) = match &self.targets {
+            Targets::Ranges { ranges, ports, .. } => (ranges, ports),
+            Targets::List(addrs) => return addrs[index as usize],
+        };
+
+        let port = ports[(index % ports.len() as u64) as usize];
+        let mut index = index / ports.len() as u64;
+        for range in ranges {
+            if index < range.len {
+                return SocketAddrV4::new(
+                    Ipv4Addr::from(u32::from(range.start) + index as u32),
+                    port,
+                );
+            }
+            index -= range.len;
+        }
+        unreachable!("target index out of bounds")
+    }
+
+    // every address we'd scan in `network`, without the ports
+    // None when there are more than `limit`, asking all of those for their MACs would take longer than it's worth
+    pub fn addresses_within(&self, network: Ipv4Network, limit: usize) -> Option<Vec<Ipv4Addr>> {
+        let first = u32::from(network.network()) as u64;
+        let last = u32::from(network.broadcast()) as u64;
+        let mut addresses = BTreeSet::new();
+        match &self.targets {
+            Targets::Ranges { ranges, .. } => {
+                for range in ranges {
+                    let start = (u32::from(range.start) as u64).max(first);
+                    let end = (u32::from(range.start) as u64 + range.len).min(last + 1);
+                    if end.saturating_sub(start) > limit as u64 {
+                        return None;
+                    }
+                    addresses.extend((start..end).map(|ip| Ipv4Addr::from(ip as u32)));
+                    if addresses.len() > limit {
+                        return None;
+                    }
+                }
+            }
+            Targets::List(addrs) => {
+                addresses.extend(
+                    addrs
+                        .iter()
+                        .map(|addr| *addr.ip())
+                        .filter(|ip| network.contains(*ip)),
+                );
+                if addresses.len() > limit {
+                    return None;
+                }
+            }
+        }
+        Some(addresses.into_iter().collect())
+    }
+}
+
+// maps every index in 0..n onto a unique other index in 0..n, so we don't hammer a single network at once
+// it's a simple affine permutation (a * i + b) mod n, where a is coprime with n
+#[derive(Debug, Clone, Copy)]
+pub struct Permutation {
+    n: u64,
+    a: u64,
+    b: u64,
+}
+
+impl Permutation {
+    pub fn new(n: u64, seed: i64) -> Self {
+        let mut data = [0; 16];
+        data[0..8].copy_from_slice(&seed.to_le_bytes());
+        data[8..16].copy_from_slice(&n.to_le_bytes());
+        let hash = stable_hash(&data);
+
+        let n = n.max(1);
+        let mut a = (hash % n) | 1;
+        while gcd(a, n) != 1 {
+            a += 1;
+        }
+        let b = hash.rotate_left(32) % n;
+
+        Self { n, a: a % n, b }
+    }
+
+    pub fn get(&self, index: u64) -> u64 {
+        ((self.a as u128 * index as u128 + self.b as u128) % self.n as u128) as u64
+    }
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+// iterates over the permuted target space, remembering how far it got so a scan can be resumed
+#[derive(Clone)]
+pub struct TargetIter {
+    space: TargetSpace,
+    permutation: Permutation,
+    start: u64,
+    position: u64,
+    end: u64,
+    // how far apart the targets this iterator yields are, more than 1 after a `split`
+    stride: u64,
+}
+
+impl TargetIter {
+    pub fn new(space: TargetSpace, seed: i64) -> Self {
+        let permutation = Permutation::new(space.len(), seed);
+        let end = space.len();
+        Self {
+            space,
+            permutation,
+            start: 0,
+            position: 0,
+            end,
+            stride: 1,
+        }
+    }
+
+    // only scan a contiguous slice of the permutation, the shards together cover everything exactly once
+    pub fn shard(mut self, shard: Shard) -> Self {
+        let total = self.space.len() as u128;
+        self.start = (total * shard.index as u128 / shard.count as u128) as u64;
+        self.end = (total * (shard.index + 1) as u128 / shard.count as u128) as u64;
+        self.position = self.start;
+        self
+    }
+
+    // continue from a position returned by `position()` earlier
+    pub fn resume(mut self, position: u64) -> Self {
+        self.position = position.clamp(self.start, self.end);
+        self
+    }
+
+    // split what's left into `parts` interleaved iterators, the first one yields the positions p, p + parts, ...,
+    // the second one p + 1, p + 1 + parts, ... and so on, so they all move through the permutation at the same pace
+    // everything before the lowest `position()` of them has been yielded, that's where a resume has to continue
+    pub fn split(self, parts: u64) -> Vec<Self> {
+        (0..parts)
+            .map(|part| Self {
+                position: (self.position + part * self.stride).min(self.end),
+                stride: self.stride * parts,
+                ..self.clone()
+            })
+            .collect()
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    // how many of the targets of this iterator were already yielded (or skipped by a resume)
+    pub fn done(&self) -> u64 {
+        self.position - self.start
+    }
+
+    // the amount of targets this iterator yields in total, not counting a resume
+    pub fn total(&self) -> u64 {
+        self.end - self.start
+    }
+}
+
+impl Iterator for TargetIter {
+    type Item = SocketAddrV4;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.position >= self.end {
+            return None;
+        }
+        let target = self.space.get(self.permutation.get(self.position));
+        self.position = (self.position + self.stride).min(self.end);
+        Some(target)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn parse_ranges() {
+        let range: Ipv4Range = "10.0.0.5/24".parse().unwrap();
+        assert_eq!(range.start, Ipv4Addr::new(10, 0, 0, 0));
+        assert_eq!(range.len, 256);
+
+        let single: Ipv4Range = "192.168.2.120".parse().unwrap();
+        assert_eq!(single.len, 1);
+
+        let everything: Ipv4Range = "0.0.0.0/0".parse().unwrap();
+        assert_eq!(everything.len, 1 << 32);
+
+        assert!("10.0.0.0/33".parse::<Ipv4Range>().is_err());
+        assert!("10.0.0/8".parse::<Ipv4Range>().is_err());
+    }
+
+    #[test]
+    fn permutation_covers_everything() {
+        let space = TargetSpace::new(
+            vec![
+                "10.0.0.0/24".parse().unwrap(),
+                "10.1.0.0/30".parse().unwrap(),
+            ],
+            vec![25565, 25566, 25567],
+        )
+        .unwrap();
+        let total = space.len();
+        let targets: HashSet<_> = TargetIter::new(space, 1234).collect();
+        assert_eq!(targets.len() as u64, total);
+    }
+
+    #[test]
+    fn resume_continues_iteration() {
+        let space = TargetSpace::new(vec!["10.0.0.0/24".parse().unwrap()], vec![25565]).unwrap();
+        let mut first = TargetIter::new(space.clone(), 42);
+        let head: Vec<_> = first.by_ref().take(100).collect();
+        let rest: Vec<_> = TargetIter::new(space.clone(), 42)
+            .resume(first.position())
+            .collect();
+
+        let all: Vec<_> = TargetIter::new(space, 42).collect();
+        assert_eq!([head, rest].concat(), all);
+    }
+
+    #[test]
+    fn shards_are_disjoint() {
+        let space = TargetSpace::new(vec!["10.0.0.0/22".parse().unwrap()], vec![25565]).unwrap();
+        let all: Vec<_> = TargetIter::new(space.clone(), 7).collect();
+        let sharded: Vec<_> = (0..3)
+            .flat_map(|index| TargetIter::new(space.clone(), 7).shard(Shard { index, count: 3 }))
+            .collect();
+        assert_eq!(sharded, all);
+
+        assert!("3/3".parse::<Shard>().is_err());
+        assert_eq!(
+            "1/4".parse::<Shard>().unwrap(),
+            Shard { index: 1, count: 4 }
+        );
+    }
+
+    // shards on other machines only line up when they all walk this exact permutation, whatever they were built with
+    // if this changes, so does CHECKPOINT_VERSION
+    #[test]
+    fn permutation_is_stable() {
+        let permutation = Permutation::new(10, 7);
+        let order: Vec<_> = (0..10).map(|index| permutation.get(index)).collect();
+        assert_eq!(order, vec![4, 1, 8, 5, 2, 9, 6, 3, 0, 7]);
+
+        let permutation = Permutation::new(1 << 32, 1234);
+        let order: Vec<_> = (0..3).map(|index| permutation.get(index)).collect();
+        assert_eq!(order, vec![3565385246, 1547140211, 3823862472]);
+    }
+
+    #[test]
+    fn splits_cover_everything_once() {
+        let space = TargetSpace::new(vec!["10.0.0.0/24".parse().unwrap()], vec![25565]).unwrap();
+        let mut first = TargetIter::new(space.clone(), 9).shard(Shard { index: 1, count: 2 });
+        let head: Vec<_> = first.by_ref().take(10).collect();
+
+        let mut parts = first.split(3);
+        let mut split: Vec<_> = parts[0].by_ref().take(5).collect();
+        split.extend(parts[1].by_ref().take(20));
+        // everything the slowest part hasn't got to yet has to be scanned again after a resume
+        let position = parts.iter().map(|part| part.position()).min().unwrap();
+        assert_eq!(position, 128 + 10 + 2);
+        for part in parts {
+            split.extend(part);
+        }
+
+        let all: Vec<_> = TargetIter::new(space, 9)
+            .shard(Shard { index: 1, count: 2 })
+            .collect();
+        assert_eq!(split.len() + head.len(), all.len());
+        let split: HashSet<_> = split.into_iter().chain(head).collect();
+        assert_eq!(split, all.into_iter().collect());
+    }
+
+    #[test]
+    fn addresses_on_link() {
+        let network = "10.0.0.0/24".parse().unwrap();
+        let space = TargetSpace::new(
+            vec![
+                "10.0.0.8/30".parse().unwrap(),
+                "10.0.0.10".parse().unwrap(),
+                "10.0.0.0/8".parse().unwrap(),
+            ],
+            vec![25565, 25566],
+        )
+        .unwrap();
+        // the /8 only counts for as much as is in our /24
+        assert_eq!(space.addresses_within(network, 256).unwrap().len(), 256);
+        assert_eq!(space.addresses_within(network, 255), None);
+
+        let space = TargetSpace::new(vec!["10.0.0.8/30".parse().unwrap()], vec![25565]).unwrap();
+        assert_eq!(
+            space.addresses_within(network, 256).unwrap(),
+            (8..12)
+                .map(|i| Ipv4Addr::new(10, 0, 0, i))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            space.addresses_within("192.168.0.0/16".parse().unwrap(), 256),
+            Some(vec![])
+        );
+
+        let space = TargetSpace::from_list(vec![
+            "10.0.0.3:25565".parse().unwrap(),
+            "10.0.0.3:25566".parse().unwrap(),
+            "1.1.1.1:25565".parse().unwrap(),
+        ])
+        .unwrap();
+        assert_eq!(
+            space.addresses_within(network, 256),
+            Some(vec![Ipv4Addr::new(10, 0, 0, 3)])
+        );
+    }
+}
@@ -14,7 +14,7 @@ pub struct TcpTemplate {
 impl TcpTemplate {
     pub fn new(flags: u8, window: u16, options: Vec<TcpOption>) -> Self {
         let options_length_bytes: usize = options.iter().map(TcpOptionPacket::packet_size).sum();
-        let options_length_words: usize = (options_length_bytes + 3) / 4;
+        let options_length_words: usize = options_length_bytes.div_ceil(4);
         let tcp_len = 20 + options_length_words * 4;
         let mut buf = vec![0u8; tcp_len];
         let mut packet = MutableTcpPacket::new(&mut buf).unwrap();
@@ -34,6 +34,11 @@ impl TcpTemplate {
         }
     }
 
+    // the header without ports, sequence and acknowledgement numbers or checksum
+    pub fn header(&self) -> &[u8] {
+        &self.packet
+    }
+
     pub fn create(
         &self,
         source: &SocketAddrV4,
@@ -54,8 +59,8 @@ impl TcpTemplate {
         packet.set_payload(payload);
         packet.set_checksum(tcp::ipv4_checksum(
             &TcpPacket::new(packet.packet()).unwrap(),
-            &source.ip(),
-            &dest.ip(),
+            source.ip(),
+            dest.ip(),
         ));
 
         packet.packet().to_vec()
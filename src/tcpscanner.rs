@@ -1,28 +1,36 @@
 use std::{
-    collections::{hash_map::DefaultHasher, HashMap},
-    hash::{Hash, Hasher},
-    net::{IpAddr, Ipv4Addr, SocketAddrV4},
+    collections::HashMap,
+    marker::PhantomData,
+    net::SocketAddrV4,
     sync::{
-        mpsc::{self, Receiver, Sender},
-        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+        Arc,
     },
     thread::{self, JoinHandle},
+    time::Instant,
 };
 
 use chrono::{DateTime, Utc};
-use pnet::{
-    datalink::{self, Channel, DataLinkReceiver, DataLinkSender},
-    packet::{
-        ethernet::{EtherTypes, EthernetPacket},
-        ip::IpNextHeaderProtocols,
-        ipv4::{self, Ipv4Flags, Ipv4Packet, MutableIpv4Packet},
-        tcp::{TcpFlags, TcpPacket},
-        Packet,
-    },
-};
+use pnet::{packet::tcp::TcpFlags, util::MacAddr};
+
+use tracing::{debug, error, trace};
 
 use crate::{
-    config::CONFIG, fingerprint::Fingerprint, interface::MyInterface, protocols::TcpProtocol,
+    backend::{self, Backend, FrameSender},
+    capture::Capture,
+    config::PortRange,
+    cookie::Cookies,
+    exchanges::ExchangeTable,
+    fingerprint::Fingerprint,
+    frame::{FrameTemplate, Outgoing},
+    interface::MyInterface,
+    packet::{self, LinkType, Transport},
+    protocols::{TcpError, TcpProtocol},
+    receiver::{FrameHandler, ReceivePool, RecvErrorHandler},
+    scanner::{self, Shutdown},
+    stats::Stats,
+    warn_limited,
 };
 
 pub struct TcpScanner<T>
@@ -30,16 +38,21 @@ where
     T: Default,
 {
     _interface: MyInterface,
-    _send_thread: JoinHandle<()>,
-    _recv_thread: JoinHandle<()>,
-    packet_send: Sender<(SocketAddrV4, Vec<u8>)>,
+    send_thread: JoinHandle<()>,
+    receive: ReceivePool,
+    // cleared to stop the receive threads
+    running: Arc<AtomicBool>,
+    packet_send: SyncSender<Outgoing>,
+    // a copy for the send threads of `Scanner::scan_all`
+    template: FrameTemplate,
     pub start_time: DateTime<Utc>,
-    source_ip: Ipv4Addr,
-    fingerprint: Fingerprint,
-    _state: Arc<Mutex<HashMap<SocketAddrV4, TcpState<T>>>>,
+    cookies: Cookies,
+    stats: Arc<Stats>,
+    // the connections are only known to the workers
+    _state: PhantomData<T>,
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
 pub struct TcpState<T>
 where
     T: Default,
@@ -48,287 +61,487 @@ where
     pub data: Vec<u8>,
     // the insternal state that the protocol parsed
     pub internal: T,
+    // when the server last sent us something, connections that stay quiet for the exchange timeout are dropped
+    pub last_activity: Instant,
+}
+
+impl<T> Default for TcpState<T>
+where
+    T: Default,
+{
+    fn default() -> Self {
+        Self {
+            data: Vec::new(),
+            internal: T::default(),
+            last_activity: Instant::now(),
+        }
+    }
 }
 
-const IPV4_HEADER_SIZE: usize = 20;
+// the most data we buffer for a single connection
+const MAX_BUFFERED: usize = 256 * 1024;
 
 impl<'a, T> TcpScanner<T>
 where
     T: Default + Send + 'static,
 {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         interface: &'a MyInterface,
+        backend: &Arc<dyn Backend>,
         protocol: Arc<dyn TcpProtocol<T>>,
         fingerprint: &Fingerprint,
-    ) -> TcpScanner<T> {
+        cookies: Cookies,
+        stats: Arc<Stats>,
+        exchanges: ExchangeTable,
+        shutdown: Shutdown,
+        capture: Option<Capture>,
+        queue_size: usize,
+        workers: usize,
+    ) -> Result<TcpScanner<T>, scanner::Error> {
         let interface = interface.clone();
-        let start_time = Utc::now();
-        let IpAddr::V4(source_ip) = interface.get_source_ip() else {
-            panic!("No ipv4 source address!")
-        };
-        let connection_states = Arc::new(Mutex::new(HashMap::new()));
 
-        let (network_tx, network_rx) =
-            match datalink::channel(&interface.network_interface, Default::default())
-                .expect("Could not get channel")
-            {
-                Channel::Ethernet(tx, rx) => (tx, rx),
-                _ => panic!("idk what weird type of connection you have mate"),
-            };
+        let (network_tx, network_rx) = backend.open()?;
+        let mut network_rxs = vec![network_rx];
+        for _ in 1..backend.receivers() {
+            network_rxs.push(backend.open_receiver()?);
+        }
+        let link_type = backend.link_type();
+        let template = FrameTemplate::tcp(
+            &interface,
+            link_type,
+            fingerprint.ittl,
+            &fingerprint.get_syn(),
+        )?;
 
-        // packet queue
-        let (packet_send_tx, packet_send_rx) = mpsc::channel();
+        // packet queue, bounded so the receivers slow down instead of piling up answers when the network can't keep up
+        let (packet_send_tx, packet_send_rx) = mpsc::sync_channel(queue_size);
+        let running = Arc::new(AtomicBool::new(true));
 
         // we first start reading, and only then we start allowing packets to be sent
         // in this case it doesn't matter since it's impossible to send packets at this point, but it's in case an idiot (me) messes with the code
-        let recv_thread = {
-            let interface = interface.clone();
-            let protocol = protocol.clone();
-            let packet_send = packet_send_tx.clone();
-            let start_time = start_time.clone();
-            let fingerprint = fingerprint.clone();
-            let connection_states = connection_states.clone();
-            thread::spawn(move || {
-                // receive packets
-                Self::recv_thread(
-                    interface,
-                    network_rx,
-                    protocol,
-                    packet_send,
-                    start_time,
-                    &fingerprint,
-                    connection_states,
+        let receive = {
+            let receiver = TcpReceiver {
+                mac: interface.network_interface.mac,
+                link_type,
+                protocol,
+                packet_send: packet_send_tx.clone(),
+                cookies,
+                fingerprint: fingerprint.clone(),
+                connection_states: HashMap::new(),
+                stats: stats.clone(),
+                exchanges,
+                capture: capture.clone(),
+                last_expire: Instant::now(),
+            };
+            // every receive thread gets its share of the replies from the backend, and every worker its share of the flows
+            ReceivePool::spawn(network_rxs, vec![receiver; workers], link_type, || {
+                RecvErrorHandler::new(
+                    backend.clone(),
+                    stats.clone(),
+                    running.clone(),
+                    shutdown.clone(),
                 )
             })
         };
 
         let send_thread = {
-            let interface = interface.clone();
-            let source_ip = source_ip.clone();
-            let fingerprint = fingerprint.clone();
+            let template = template.clone();
+            let stats = stats.clone();
             thread::spawn(move || {
-                Self::send_thread(
-                    interface,
-                    fingerprint,
-                    source_ip,
-                    packet_send_rx,
-                    network_tx,
-                )
+                Self::send_thread(template, packet_send_rx, network_tx, stats, capture)
             })
         };
 
-        Self {
+        Ok(Self {
             _interface: interface,
-            _send_thread: send_thread,
-            _recv_thread: recv_thread,
+            send_thread,
+            receive,
+            running,
             packet_send: packet_send_tx,
-            start_time,
-            source_ip,
-            fingerprint: fingerprint.clone(),
-            _state: connection_states.clone(),
-        }
+            template,
+            start_time: cookies.start_time,
+            cookies,
+            stats,
+            _state: PhantomData,
+        })
     }
 
     pub fn scan(&'a mut self, addr: SocketAddrV4) {
         // send initial packet
-        let cookie = Self::cookie(&addr, &self.start_time);
-        let source = SocketAddrV4::new(self.source_ip, 61000);
-        let packet = self
-            .fingerprint
-            .get_syn()
-            .create(&source, &addr, cookie, 0, &[]);
-
-        self.send_to(addr, packet);
+        let cookie = self.cookies.cookie(&addr);
+        self.send_to(Outgoing::Probe {
+            dest: addr,
+            source_port: self.cookies.source_port(cookie),
+            cookie,
+        });
+        Stats::inc(&self.stats.probes_sent);
+    }
+
+    pub fn template(&self) -> &FrameTemplate {
+        &self.template
+    }
+
+    // stops the threads, replies that arrive after this are lost
+    pub fn finish(self) {
+        self.running.store(false, Ordering::Relaxed);
+        self.receive.join();
+        // the workers had a sender too, so only now the queue closes and the send thread stops
+        drop(self.packet_send);
+        if self.send_thread.join().is_err() {
+            error!("Send thread panicked");
+        }
     }
 
-    fn send_to(&'a mut self, addr: SocketAddrV4, packet: Vec<u8>) {
+    fn send_to(&'a mut self, outgoing: Outgoing) {
+        Stats::inc(&self.stats.queue_depth);
         self.packet_send
-            .send((addr, packet))
+            .send(outgoing)
             .expect("Could not send packet");
     }
 
-    fn cookie(addr: &SocketAddrV4, start_time: &DateTime<Utc>) -> u32 {
-        let mut hasher = DefaultHasher::new();
-        (
-            *addr.ip(),
-            addr.port(),
-            CONFIG.scan.seed,
-            start_time.timestamp_millis(),
-        )
-            .hash(&mut hasher);
-        hasher.finish() as u32
+    fn send_thread(
+        mut template: FrameTemplate,
+        rx: Receiver<Outgoing>,
+        mut network_tx: Box<dyn FrameSender>,
+        stats: Arc<Stats>,
+        capture: Option<Capture>,
+    ) {
+        // receive packets form a queue and send them
+        while let Some(outgoing) = backend::next_queued(&rx, network_tx.as_mut()) {
+            Stats::dec(&stats.queue_depth);
+            let frame = match &outgoing {
+                Outgoing::Probe {
+                    dest,
+                    source_port,
+                    cookie,
+                } => template.probe(dest, *source_port, *cookie),
+                Outgoing::Segment(dest, segment) => template.segment(dest, segment),
+            };
+            if let Some(capture) = &capture {
+                capture.write(frame);
+            }
+            match network_tx.send(frame) {
+                Ok(()) => Stats::inc(&stats.packets_sent),
+                Err(err) => {
+                    warn_limited!(%err, "could not send packet");
+                    Stats::inc(&stats.send_errors);
+                }
+            }
+        }
     }
+}
 
-    fn recv_thread(
-        interface: MyInterface,
-        mut rx: Box<dyn DataLinkReceiver>,
-        protocol: Arc<dyn TcpProtocol<T>>,
-        packet_send: Sender<(SocketAddrV4, Vec<u8>)>,
-        start_time: DateTime<Utc>,
-        fingerprint: &Fingerprint,
-        connection_states: Arc<Mutex<HashMap<SocketAddrV4, TcpState<T>>>>,
-    ) {
-        loop {
-            match rx.next() {
-                Ok(packet) => {
-                    let packet = {
-                        let packet = EthernetPacket::new(packet).unwrap();
-                        if packet.get_destination() != interface.mac() {
-                            continue;
-                        } // make sure it's meant for us
-
-                        packet.payload().to_vec()
-                    };
-
-                    let Some(packet) = Ipv4Packet::new(&packet) else {
-                        continue;
-                    };
-
-                    if packet.get_next_level_protocol() != IpNextHeaderProtocols::Tcp {
-                        println!(
-                            "Invalid next level protocol while scanning TCP: {}",
-                            packet.get_next_level_protocol()
-                        );
-                        continue;
-                    }
+// everything that happens to a received frame, from the Ethernet header up to the protocol
+// this is what the receive workers run, separate from them so captures can be replayed through it too
+pub struct TcpReceiver<T>
+where
+    T: Default,
+{
+    // only frames sent to this MAC are handled, None to handle all of them
+    pub mac: Option<MacAddr>,
+    pub link_type: LinkType,
+    pub protocol: Arc<dyn TcpProtocol<T>>,
+    // where the ACKs, RSTs and data we answer with go
+    pub packet_send: SyncSender<Outgoing>,
+    pub cookies: Cookies,
+    pub fingerprint: Fingerprint,
+    // only the connections of the flows handed to this worker
+    pub connection_states: HashMap<SocketAddrV4, TcpState<T>>,
+    pub stats: Arc<Stats>,
+    pub exchanges: ExchangeTable,
+    // every frame we handle gets written here
+    pub capture: Option<Capture>,
+    // when the connections of servers that went quiet were last dropped
+    pub last_expire: Instant,
+}
 
-                    let tcp_packet = TcpPacket::new(packet.payload()).unwrap();
-                    let source = SocketAddrV4::new(packet.get_source(), tcp_packet.get_source());
-                    let dest =
-                        SocketAddrV4::new(packet.get_destination(), tcp_packet.get_destination());
-                    let cookie = Self::cookie(&source, &start_time);
-                    println!(
-                        "Got TCP packet from {}:{}, flags = {:b}",
-                        packet.get_source(),
-                        tcp_packet.get_source(),
-                        tcp_packet.get_flags()
-                    );
-
-                    // SYN-ACK
-                    if tcp_packet.get_flags() & TcpFlags::SYN != 0
-                        && tcp_packet.get_flags() & TcpFlags::ACK != 0
-                    {
-                        // validate cookie
-                        if tcp_packet.get_acknowledgement() != cookie + 1 {
-                            println!(
-                                "Invalid cookie! expected {} but got {}",
-                                cookie + 1,
-                                tcp_packet.get_acknowledgement()
-                            );
-                            // send RST back
-                            println!("Sending RST back");
-                            let rst = fingerprint.get_rst().create(
-                                &dest,
-                                &source,
-                                tcp_packet.get_acknowledgement(),
-                                tcp_packet.get_sequence() + 1,
-                                &[],
+// every worker has its own, and starts without connections since it gets different flows than the others
+impl<T> Clone for TcpReceiver<T>
+where
+    T: Default,
+{
+    fn clone(&self) -> Self {
+        Self {
+            mac: self.mac,
+            link_type: self.link_type,
+            protocol: self.protocol.clone(),
+            packet_send: self.packet_send.clone(),
+            cookies: self.cookies,
+            fingerprint: self.fingerprint.clone(),
+            connection_states: HashMap::new(),
+            stats: self.stats.clone(),
+            exchanges: self.exchanges.clone(),
+            capture: self.capture.clone(),
+            last_expire: Instant::now(),
+        }
+    }
+}
+
+impl<T> FrameHandler for TcpReceiver<T>
+where
+    T: Default + Send + 'static,
+{
+    fn handle_frame(&mut self, frame: &[u8]) {
+        self.expire_connections();
+        let stats = &self.stats;
+        let fingerprint = &self.fingerprint;
+        let packet = match self.link_type {
+            LinkType::Ethernet => {
+                let ethernet = match packet::parse_ethernet(frame) {
+                    Ok(ethernet) => ethernet,
+                    Err(reason) => {
+                        stats.dropped(reason);
+                        return;
+                    }
+                };
+                let Some(packet) = ethernet.ipv4 else {
+                    return;
+                };
+                if let Some(mac) = self.mac {
+                    if ethernet.source == mac {
+                        // outgoing packet, check if the kernel is resetting our connections behind our back
+                        // this is how a missing (or wrong) firewall rule shows up, the first one is reported right away
+                        if Self::is_kernel_rst(packet, &self.cookies.source_ports)
+                            && stats.kernel_rsts.fetch_add(1, Ordering::Relaxed) == 0
+                        {
+                            error!(
+                                ports = %self.cookies.source_ports,
+                                "the kernel is sending RSTs from our scanning ports, firewall them or connections will break!"
                             );
-                            packet_send.send((source, rst)).unwrap();
-                            continue;
                         }
+                        return;
+                    }
+                    if ethernet.destination != mac {
+                        return;
+                    } // make sure it's meant for us
+                }
+                packet
+            }
+            // a TUN device only gives us what was routed to it, and our own packets never come back
+            LinkType::Ip => match packet::raw_ip(frame) {
+                Ok(Some(packet)) => packet,
+                Ok(None) => return,
+                Err(reason) => {
+                    stats.dropped(reason);
+                    return;
+                }
+            },
+        };
 
-                        // sending ACK
-                        // apparently the sequence and the acknowledgement need to be swapped, no clue why
-                        let ack = fingerprint.get_ack().create(
-                            &dest,
-                            &source,
-                            tcp_packet.get_acknowledgement(),
-                            tcp_packet.get_sequence() + 1,
-                            &[],
-                        );
+        let segment = match packet::parse_ipv4(packet, true) {
+            Ok(Some(segment)) => segment,
+            Ok(None) => return,
+            Err(reason) => {
+                trace!(%reason, "dropping packet");
+                stats.dropped(reason);
+                return;
+            }
+        };
+        let Transport::Tcp(tcp_packet) = segment.transport else {
+            trace!(source = %segment.source, "ignoring non-TCP packet");
+            return;
+        };
+
+        // only look at packets sent to our scanning ports
+        if !self
+            .cookies
+            .source_ports
+            .contains(segment.destination.port())
+        {
+            return;
+        }
+        if let Some(capture) = &self.capture {
+            capture.write(frame);
+        }
 
-                        packet_send.send((source, ack)).unwrap();
+        let source = segment.source;
+        let dest = segment.destination;
+        let cookie = self.cookies.cookie(&source);
+        let queue = |packet: Vec<u8>| {
+            Stats::inc(&stats.queue_depth);
+            self.packet_send
+                .send(Outgoing::Segment(source, packet))
+                .unwrap();
+        };
+        trace!(
+            %source,
+            flags = format_args!("{:b}", tcp_packet.flags),
+            "got TCP packet"
+        );
 
-                        if let Some(data) = protocol.initial_packet(&source) {
-                            // send data
-                            let packet = fingerprint.get_psh().create(
-                                &dest,
-                                &source,
-                                tcp_packet.get_acknowledgement(),
-                                tcp_packet.get_sequence() + 1,
-                                &data,
-                            );
-                            packet_send.send((source, packet)).unwrap();
-                        }
+        // SYN-ACK
+        if tcp_packet.flags & TcpFlags::SYN != 0 && tcp_packet.flags & TcpFlags::ACK != 0 {
+            // validate cookie, the port we sent the SYN from is derived from it too
+            if tcp_packet.acknowledgement != cookie.wrapping_add(1)
+                || self.cookies.source_port(cookie) != dest.port()
+            {
+                warn_limited!(
+                    %source,
+                    expected_ack = cookie.wrapping_add(1),
+                    expected_port = self.cookies.source_port(cookie),
+                    ack = tcp_packet.acknowledgement,
+                    port = dest.port(),
+                    "invalid cookie, sending RST back"
+                );
+                // send RST back
+                let rst = fingerprint.get_rst().create(
+                    &dest,
+                    &source,
+                    tcp_packet.acknowledgement,
+                    tcp_packet.sequence.wrapping_add(1),
+                    &[],
+                );
+                queue(rst);
+                Stats::inc(&stats.cookie_failures);
+                return;
+            }
 
-                        connection_states
-                            .lock()
-                            .unwrap()
-                            .insert(source, Default::default());
-                    } else if !tcp_packet.payload().is_empty() {
-                        println!("data: {:?}", tcp_packet.payload());
-
-                        if let Some(state) = connection_states.lock().unwrap().get_mut(&source) {
-                            // ack this data
-                            let ack = fingerprint.get_ack().create(
-                                &dest,
-                                &source,
-                                tcp_packet.get_acknowledgement(),
-                                tcp_packet.get_sequence() + tcp_packet.payload().len() as u32,
-                                &[],
-                            );
+            // sending ACK
+            // apparently the sequence and the acknowledgement need to be swapped, no clue why
+            let ack = fingerprint.get_ack().create(
+                &dest,
+                &source,
+                tcp_packet.acknowledgement,
+                tcp_packet.sequence.wrapping_add(1),
+                &[],
+            );
 
-                            packet_send.send((source, ack)).unwrap();
+            queue(ack);
+            stats.reply(self.protocol.kind());
 
-                            // handle the data
-                            state.data.extend_from_slice(tcp_packet.payload());
-                            println!("state currently contains {} bytes", state.data.len());
-                            protocol
-                                .handle_data(&source, state)
-                                .expect("TODO: handle this");
-                        }
-                    } else if tcp_packet.get_flags() & TcpFlags::RST != 0 {
-                        println!("RST :(");
-                    } else {
-                        println!("Unknown flags: {:b}", tcp_packet.get_flags());
+            if let Some(data) = self.protocol.initial_packet(&source) {
+                // send data
+                let packet = fingerprint.get_psh().create(
+                    &dest,
+                    &source,
+                    tcp_packet.acknowledgement,
+                    tcp_packet.sequence.wrapping_add(1),
+                    &data,
+                );
+                queue(packet);
+            }
+
+            self.exchanges.start(source);
+            // a retransmitted SYN-ACK starts the connection over
+            if self
+                .connection_states
+                .insert(source, Default::default())
+                .is_none()
+            {
+                Stats::inc(&stats.tcp_connections);
+            }
+        } else if !tcp_packet.payload.is_empty() {
+            trace!(%source, data = ?tcp_packet.payload, "got data");
+
+            if let Some(state) = self.connection_states.get_mut(&source) {
+                state.last_activity = Instant::now();
+                // ack this data
+                let ack = fingerprint.get_ack().create(
+                    &dest,
+                    &source,
+                    tcp_packet.acknowledgement,
+                    tcp_packet
+                        .sequence
+                        .wrapping_add(tcp_packet.payload.len() as u32),
+                    &[],
+                );
+
+                queue(ack);
+
+                // handle the data
+                state.data.extend_from_slice(tcp_packet.payload);
+                debug!(%source, buffered = state.data.len(), "received data");
+                // don't let a server make us buffer data forever
+                let result = if state.data.len() > MAX_BUFFERED {
+                    Err(TcpError::Malformed)
+                } else {
+                    self.protocol.handle_data(&source, state)
+                };
+                match result {
+                    Ok(_) => {}
+                    Err(TcpError::Incomplete) => {
+                        self.exchanges.start(source);
+                        return;
+                    }
+                    Err(TcpError::Malformed) => {
+                        warn_limited!(%source, buffered = state.data.len(), "malformed data, closing the connection");
+                        Stats::inc(&stats.parse_failures);
+                        let rst = fingerprint.get_rst().create(
+                            &dest,
+                            &source,
+                            tcp_packet.acknowledgement,
+                            tcp_packet
+                                .sequence
+                                .wrapping_add(tcp_packet.payload.len() as u32),
+                            &[],
+                        );
+                        queue(rst);
                     }
                 }
-                Err(_) => todo!(),
+                self.exchanges.end(&source);
+                self.connection_states.remove(&source);
+                Stats::dec(&stats.tcp_connections);
+            }
+        } else if tcp_packet.flags & TcpFlags::RST != 0 {
+            debug!(%source, "got RST");
+            Stats::inc(&stats.rsts);
+            self.exchanges.end(&source);
+            if self.connection_states.remove(&source).is_some() {
+                Stats::dec(&stats.tcp_connections);
             }
+        } else {
+            debug!(
+                %source,
+                flags = format_args!("{:b}", tcp_packet.flags),
+                "unknown flags"
+            );
         }
     }
 
-    fn send_thread(
-        interface: MyInterface,
-        fingerprint: Fingerprint,
-        source_ip: Ipv4Addr,
-        rx: Receiver<(SocketAddrV4, Vec<u8>)>,
-        mut network_tx: Box<dyn DataLinkSender>,
-    ) {
-        // receive packets form a queue and send them
-        while let Ok((dest, packet)) = rx.recv() {
-            let dest: SocketAddrV4 = dest;
-            let packet: Vec<u8> = packet;
-            // create packet
-
-            // wrap in Ipv4
-            let mut ipv4_buf = vec![0u8; packet.len() + IPV4_HEADER_SIZE];
-            let mut ipv4_packet = MutableIpv4Packet::new(&mut ipv4_buf).unwrap();
-            // -- START STEALING FROM MATSCAN --
-            ipv4_packet.set_version(4); // ipv4 lol
-            ipv4_packet.set_dscp(0); // precedence and delay, don't care so 0
-            ipv4_packet.set_ecn(0); // reserved
-            ipv4_packet.set_identification(1); // https://github.com/torvalds/linux/blob/master/net/ipv4/ip_output.c#L165
-            ipv4_packet.set_fragment_offset(0); // fragmentation is disabled so 0
-            ipv4_packet.set_options(&[]);
-            // -- STOP STEALING FROM MATSCAN --
-            ipv4_packet.set_flags(Ipv4Flags::DontFragment); // please, it would make it so much easier
-            ipv4_packet.set_header_length(IPV4_HEADER_SIZE as u8 / 4);
-            ipv4_packet.set_ttl(fingerprint.ittl);
-            ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
-            ipv4_packet.set_total_length(
-                (packet.len() + 4 * ipv4_packet.get_header_length() as usize) as u16,
-            );
-            ipv4_packet.set_payload(&packet);
-            ipv4_packet.set_destination(dest.ip().to_owned());
-            ipv4_packet.set_source(source_ip);
-            ipv4_packet.set_checksum(ipv4::checksum(
-                &Ipv4Packet::new(ipv4_packet.packet()).unwrap(),
-            ));
-
-            // send packet
-            interface.send_packet(&mut network_tx, ipv4_packet.packet(), EtherTypes::Ipv4);
+    fn idle(&mut self) {
+        self.expire_connections();
+    }
+}
+
+impl<T> TcpReceiver<T>
+where
+    T: Default,
+{
+    // the exchanges of servers that never send the rest of their data time out, and so do their connections
+    // otherwise every one of them would keep its buffered data for the rest of the scan
+    fn expire_connections(&mut self) {
+        let timeout = self.exchanges.timeout();
+        let now = Instant::now();
+        if now - self.last_expire < timeout {
+            return;
         }
+        self.last_expire = now;
+
+        let connections = self.connection_states.len();
+        self.connection_states
+            .retain(|_, state| now - state.last_activity < timeout);
+        let expired = connections - self.connection_states.len();
+        if expired > 0 {
+            debug!(expired, "dropped connections that went quiet");
+            self.stats
+                .tcp_connections
+                .fetch_sub(expired as u64, Ordering::Relaxed);
+        }
+    }
+
+    // our own packets always have identification 1 (see TcpScanner::send_thread), the kernel uses something else
+    // the checksums of outgoing packets are often only filled in by the network card, so don't check those
+    fn is_kernel_rst(packet: &[u8], source_ports: &PortRange) -> bool {
+        let Ok(Some(segment)) = packet::parse_ipv4(packet, false) else {
+            return false;
+        };
+        let Transport::Tcp(tcp_packet) = segment.transport else {
+            return false;
+        };
+
+        segment.identification != 1
+            && tcp_packet.flags & TcpFlags::RST != 0
+            && source_ports.contains(segment.source.port())
     }
 }
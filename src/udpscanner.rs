@@ -1,221 +1,302 @@
 use std::{
-    collections::hash_map::DefaultHasher,
-    hash::{Hash, Hasher},
-    net::{IpAddr, Ipv4Addr, SocketAddrV4},
+    cell::Cell,
+    net::{Ipv4Addr, SocketAddrV4},
     sync::{
-        mpsc::{self, Receiver, Sender},
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, SyncSender},
         Arc,
     },
     thread::{self, JoinHandle},
 };
 
 use chrono::{DateTime, Utc};
-use pnet::{
-    datalink::{self, Channel, DataLinkReceiver, DataLinkSender},
-    packet::{
-        ethernet::{EtherTypes, EthernetPacket},
-        ip::IpNextHeaderProtocols,
-        ipv4::{self, Ipv4Flags, Ipv4Packet, MutableIpv4Packet},
-        udp::UdpPacket,
-        Packet,
-    },
-};
+use pnet::util::MacAddr;
+use tracing::{error, trace};
 
 use crate::{
-    config::CONFIG, fingerprint::Fingerprint, interface::MyInterface, protocols::UdpProtocol, utils,
+    backend::{self, Backend, FrameSender},
+    capture::Capture,
+    cookie::Cookies,
+    exchanges::ExchangeTable,
+    fingerprint::Fingerprint,
+    frame::{FrameTemplate, Outgoing},
+    interface::MyInterface,
+    packet::{self, LinkType, Transport},
+    protocols::{UdpError, UdpProtocol},
+    receiver::{FrameHandler, ReceivePool, RecvErrorHandler},
+    scanner::{self, Shutdown},
+    stats::Stats,
+    utils, warn_limited,
 };
 
 pub struct UdpScanner {
     _interface: MyInterface,
-    protocol: Arc<UdpProtocol>,
-    _send_thread: JoinHandle<()>,
-    _recv_thread: JoinHandle<()>,
-    packet_send: Sender<(SocketAddrV4, Vec<u8>)>,
+    send_thread: JoinHandle<()>,
+    receive: ReceivePool,
+    // cleared to stop the receive threads
+    running: Arc<AtomicBool>,
+    packet_send: SyncSender<Outgoing>,
+    // a copy for the send threads of `Scanner::scan_all`
+    template: FrameTemplate,
     pub start_time: DateTime<Utc>,
-    source_ip: Ipv4Addr,
+    cookies: Cookies,
+    stats: Arc<Stats>,
 }
 
-const IPV4_HEADER_SIZE: usize = 20;
-
 impl<'a> UdpScanner {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         interface: &'a MyInterface,
+        backend: &Arc<dyn Backend>,
         protocol: Arc<UdpProtocol>,
         fingerprint: &Fingerprint,
-    ) -> UdpScanner {
+        cookies: Cookies,
+        stats: Arc<Stats>,
+        exchanges: ExchangeTable,
+        shutdown: Shutdown,
+        capture: Option<Capture>,
+        queue_size: usize,
+        workers: usize,
+    ) -> Result<UdpScanner, scanner::Error> {
         let interface = interface.clone();
-        let start_time = Utc::now();
-        let IpAddr::V4(source_ip) = interface.get_source_ip() else {
-            panic!("No ipv4 source address!")
-        };
 
-        let (network_tx, network_rx) =
-            match datalink::channel(&interface.network_interface, Default::default())
-                .expect("Could not get channel")
-            {
-                Channel::Ethernet(tx, rx) => (tx, rx),
-                _ => panic!("idk what weird type of connection you have mate"),
-            };
+        let (network_tx, network_rx) = backend.open()?;
+        let mut network_rxs = vec![network_rx];
+        for _ in 1..backend.receivers() {
+            network_rxs.push(backend.open_receiver()?);
+        }
+        let link_type = backend.link_type();
+        // every probe is the same apart from the cookie
+        let template = FrameTemplate::udp(
+            &interface,
+            link_type,
+            fingerprint.ittl,
+            &protocol.initial_packet(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0), 0),
+            protocol.cookie_offsets(),
+        )?;
 
-        // packet queue
-        let (packet_send_tx, packet_send_rx) = mpsc::channel();
+        // packet queue, bounded so the receivers slow down instead of piling up answers when the network can't keep up
+        let (packet_send_tx, packet_send_rx) = mpsc::sync_channel(queue_size);
+        let running = Arc::new(AtomicBool::new(true));
 
         // we first start reading, and only then we start allowing packets to be sent
         // in this case it doesn't matter since it's impossible to send packets at this point, but it's in case an idiot (me) messes with the code
-        let recv_thread = {
-            let interface = interface.clone();
-            let protocol = protocol.clone();
-            let packet_send = packet_send_tx.clone();
-            let start_time = start_time.clone();
-            thread::spawn(move || {
-                // receive packets
-                Self::recv_thread(interface, network_rx, protocol, packet_send, start_time)
+        let receive = {
+            let receiver = UdpReceiver {
+                mac: interface.network_interface.mac,
+                link_type,
+                protocol: protocol.clone(),
+                packet_send: packet_send_tx.clone(),
+                cookies,
+                stats: stats.clone(),
+                exchanges,
+                capture: capture.clone(),
+            };
+            // every receive thread gets its share of the replies from the backend, and every worker its share of the flows
+            ReceivePool::spawn(network_rxs, vec![receiver; workers], link_type, || {
+                RecvErrorHandler::new(
+                    backend.clone(),
+                    stats.clone(),
+                    running.clone(),
+                    shutdown.clone(),
+                )
             })
         };
 
         let send_thread = {
-            let interface = interface.clone();
-            let source_ip = source_ip.clone();
-            let fingerprint = fingerprint.clone();
+            let template = template.clone();
+            let stats = stats.clone();
             thread::spawn(move || {
-                Self::send_thread(
-                    interface,
-                    fingerprint,
-                    source_ip,
-                    packet_send_rx,
-                    network_tx,
-                )
+                Self::send_thread(template, packet_send_rx, network_tx, stats, capture)
             })
         };
 
-        Self {
+        Ok(Self {
             _interface: interface,
-            protocol,
-            _send_thread: send_thread,
-            _recv_thread: recv_thread,
+            send_thread,
+            receive,
+            running,
             packet_send: packet_send_tx,
-            start_time,
-            source_ip,
-        }
+            template,
+            start_time: cookies.start_time,
+            cookies,
+            stats,
+        })
     }
 
     pub fn scan(&'a mut self, addr: SocketAddrV4) {
         // send initial packet
-        let cookie = Self::cookie(&addr, &self.start_time);
-        let source = SocketAddrV4::new(self.source_ip, 61000);
-        let packet = utils::wrap_udp(self.protocol.initial_packet(&addr, cookie), &source, &addr);
+        let cookie = self.cookies.cookie(&addr);
+        self.send_to(Outgoing::Probe {
+            dest: addr,
+            source_port: self.cookies.source_port(cookie),
+            cookie,
+        });
+        Stats::inc(&self.stats.probes_sent);
+    }
 
-        self.send_to(addr, packet);
+    pub fn template(&self) -> &FrameTemplate {
+        &self.template
     }
 
-    fn send_to(&'a mut self, addr: SocketAddrV4, packet: Vec<u8>) {
-        self.packet_send
-            .send((addr, packet))
-            .expect("Could not send packet");
+    // stops the threads, replies that arrive after this are lost
+    pub fn finish(self) {
+        self.running.store(false, Ordering::Relaxed);
+        self.receive.join();
+        // the workers had a sender too, so only now the queue closes and the send thread stops
+        drop(self.packet_send);
+        if self.send_thread.join().is_err() {
+            error!("Send thread panicked");
+        }
     }
 
-    fn cookie(addr: &SocketAddrV4, start_time: &DateTime<Utc>) -> u32 {
-        let mut hasher = DefaultHasher::new();
-        (
-            *addr.ip(),
-            addr.port(),
-            CONFIG.scan.seed,
-            start_time.timestamp_millis(),
-        )
-            .hash(&mut hasher);
-        hasher.finish() as u32
+    fn send_to(&'a mut self, outgoing: Outgoing) {
+        Stats::inc(&self.stats.queue_depth);
+        self.packet_send
+            .send(outgoing)
+            .expect("Could not send packet");
     }
 
-    fn recv_thread(
-        interface: MyInterface,
-        mut rx: Box<dyn DataLinkReceiver>,
-        protocol: Arc<UdpProtocol>,
-        packet_send: Sender<(SocketAddrV4, Vec<u8>)>,
-        start_time: DateTime<Utc>,
+    fn send_thread(
+        mut template: FrameTemplate,
+        rx: Receiver<Outgoing>,
+        mut network_tx: Box<dyn FrameSender>,
+        stats: Arc<Stats>,
+        capture: Option<Capture>,
     ) {
-        loop {
-            match rx.next() {
-                Ok(packet) => {
-                    let packet = {
-                        let packet = EthernetPacket::new(packet).unwrap();
-                        if packet.get_destination() != interface.mac() {
-                            continue;
-                        } // make sure it's meant for us
-
-                        packet.payload().to_vec()
-                    };
-
-                    let Some(packet) = Ipv4Packet::new(&packet) else {
-                        continue;
-                    };
-
-                    // nothing wrong with using &* :D
-                    if packet.get_next_level_protocol() != IpNextHeaderProtocols::Udp {
-                        println!(
-                            "Invalid next level protocol while scanning UDP: {}",
-                            packet.get_next_level_protocol()
-                        );
-                        continue;
-                    }
-
-                    let udp = UdpPacket::new(packet.payload()).unwrap();
-
-                    let source = SocketAddrV4::new(packet.get_source(), udp.get_source());
-
-                    let cookie = Self::cookie(&source, &start_time);
-
-                    protocol.handle_packet(
-                        &|packet: Vec<u8>| packet_send.send((source, packet)).unwrap(),
-                        &source,
-                        cookie,
-                        udp.payload(),
-                    );
+        // receive packets form a queue and send them
+        while let Some(outgoing) = backend::next_queued(&rx, network_tx.as_mut()) {
+            Stats::dec(&stats.queue_depth);
+            let frame = match &outgoing {
+                Outgoing::Probe {
+                    dest,
+                    source_port,
+                    cookie,
+                } => template.probe(dest, *source_port, *cookie),
+                Outgoing::Segment(dest, segment) => template.segment(dest, segment),
+            };
+            if let Some(capture) = &capture {
+                capture.write(frame);
+            }
+            match network_tx.send(frame) {
+                Ok(()) => Stats::inc(&stats.packets_sent),
+                Err(err) => {
+                    warn_limited!(%err, "could not send packet");
+                    Stats::inc(&stats.send_errors);
                 }
-                Err(_) => todo!(),
             }
         }
     }
+}
 
-    fn send_thread(
-        interface: MyInterface,
-        fingerprint: Fingerprint,
-        source_ip: Ipv4Addr,
-        rx: Receiver<(SocketAddrV4, Vec<u8>)>,
-        mut network_tx: Box<dyn DataLinkSender>,
-    ) {
-        // receive packets form a queue and send them
-        while let Ok((dest, packet)) = rx.recv() {
-            let dest: SocketAddrV4 = dest;
-            let packet: Vec<u8> = packet;
-            // create packet
-
-            // wrap in Ipv4
-            let mut ipv4_buf = vec![0u8; packet.len() + IPV4_HEADER_SIZE];
-            let mut ipv4_packet = MutableIpv4Packet::new(&mut ipv4_buf).unwrap();
-            // -- START STEALING FROM MATSCAN --
-            ipv4_packet.set_version(4); // ipv4 lol
-            ipv4_packet.set_dscp(0); // precedence and delay, don't care so 0
-            ipv4_packet.set_ecn(0); // reserved
-            ipv4_packet.set_identification(1); // https://github.com/torvalds/linux/blob/master/net/ipv4/ip_output.c#L165
-            ipv4_packet.set_fragment_offset(0); // fragmentation is disabled so 0
-            ipv4_packet.set_options(&[]);
-            // -- STOP STEALING FROM MATSCAN --
-            ipv4_packet.set_flags(Ipv4Flags::DontFragment); // please, it would make it so much easier
-            ipv4_packet.set_header_length(IPV4_HEADER_SIZE as u8 / 4);
-            ipv4_packet.set_ttl(fingerprint.ittl);
-            ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Udp);
-            ipv4_packet.set_total_length(
-                (packet.len() + 4 * ipv4_packet.get_header_length() as usize) as u16,
+// everything that happens to a received frame, from the Ethernet header up to the protocol
+// this is what the receive workers run, separate from them so captures can be replayed through it too
+#[derive(Clone)]
+pub struct UdpReceiver {
+    // only frames sent to this MAC are handled, None to handle all of them
+    pub mac: Option<MacAddr>,
+    pub link_type: LinkType,
+    pub protocol: Arc<UdpProtocol>,
+    // where the answers of the protocol go
+    pub packet_send: SyncSender<Outgoing>,
+    pub cookies: Cookies,
+    pub stats: Arc<Stats>,
+    pub exchanges: ExchangeTable,
+    // every frame we handle gets written here
+    pub capture: Option<Capture>,
+}
+
+impl FrameHandler for UdpReceiver {
+    fn handle_frame(&mut self, frame: &[u8]) {
+        let stats = &self.stats;
+        let packet = match self.link_type {
+            LinkType::Ethernet => packet::parse_ethernet(frame).map(|ethernet| {
+                // make sure it's meant for us
+                ethernet
+                    .ipv4
+                    .filter(|_| self.mac.is_none_or(|mac| ethernet.destination == mac))
+            }),
+            // a TUN device only gives us what was routed to it
+            LinkType::Ip => packet::raw_ip(frame),
+        };
+        let segment = match packet {
+            Ok(Some(packet)) => packet::parse_ipv4(packet, true),
+            Ok(None) => return,
+            Err(reason) => Err(reason),
+        };
+        let segment = match segment {
+            Ok(Some(segment)) => segment,
+            Ok(None) => return,
+            Err(reason) => {
+                trace!(%reason, "dropping packet");
+                stats.dropped(reason);
+                return;
+            }
+        };
+        let Transport::Udp { payload } = segment.transport else {
+            trace!(source = %segment.source, "ignoring non-UDP packet");
+            return;
+        };
+
+        // only look at packets sent to our scanning ports
+        if !self
+            .cookies
+            .source_ports
+            .contains(segment.destination.port())
+        {
+            return;
+        }
+        if let Some(capture) = &self.capture {
+            capture.write(frame);
+        }
+
+        let source = segment.source;
+        let dest = segment.destination;
+
+        let cookie = self.cookies.cookie(&source);
+
+        // the port we sent the probe from is part of the cookie
+        if self.cookies.source_port(cookie) != dest.port() {
+            warn_limited!(
+                %source,
+                expected = self.cookies.source_port(cookie),
+                got = dest.port(),
+                "reply on the wrong source port"
             );
-            ipv4_packet.set_payload(&packet);
-            ipv4_packet.set_destination(dest.ip().to_owned());
-            ipv4_packet.set_source(source_ip);
-            ipv4_packet.set_checksum(ipv4::checksum(
-                &Ipv4Packet::new(ipv4_packet.packet()).unwrap(),
-            ));
-
-            // send packet
-            interface.send_packet(&mut network_tx, ipv4_packet.packet(), EtherTypes::Ipv4);
+            Stats::inc(&stats.cookie_failures);
+            return;
+        }
+
+        // if we answer, the server owes us another reply
+        let answered = Cell::new(false);
+        let result = self.protocol.handle_packet(
+            &|packet: Vec<u8>| {
+                answered.set(true);
+                Stats::inc(&stats.queue_depth);
+                self.packet_send
+                    .send(Outgoing::Segment(
+                        source,
+                        utils::wrap_udp(packet, &dest, &source),
+                    ))
+                    .unwrap()
+            },
+            &source,
+            cookie,
+            payload,
+        );
+        match result {
+            Ok(()) => {
+                if answered.get() {
+                    self.exchanges.start(source);
+                } else {
+                    self.exchanges.end(&source);
+                }
+                stats.reply(self.protocol.kind())
+            }
+            Err(UdpError::Cookie) => Stats::inc(&stats.cookie_failures),
+            Err(UdpError::Malformed | UdpError::UnknownPacket(_)) => {
+                Stats::inc(&stats.parse_failures)
+            }
         }
     }
 }
@@ -1,4 +1,8 @@
-use std::{io::Write, net::SocketAddrV4};
+use std::{hash::Hasher, io::Write, net::SocketAddrV4};
+
+use siphasher::sip::SipHasher13;
+
+use thiserror::Error;
 
 use pnet::packet::{
     udp::{self, MutableUdpPacket, UdpPacket},
@@ -8,6 +12,14 @@ use pnet::packet::{
 // constants
 pub const UDP_HEADER_LEN: usize = 8;
 
+// SipHash-1-3 with fixed keys, unlike std's DefaultHasher this gives the same hash with every Rust version (and on every
+// machine), which resumed checkpoints and shards scanned on other boxes depend on
+pub fn stable_hash(data: &[u8]) -> u64 {
+    let mut hasher = SipHasher13::new_with_keys(0, 0);
+    hasher.write(data);
+    hasher.finish()
+}
+
 pub fn wrap_udp(packet: Vec<u8>, source: &SocketAddrV4, dest: &SocketAddrV4) -> Vec<u8> {
     let length = packet.len() + UDP_HEADER_LEN;
     let mut buf = vec![0u8; length];
@@ -18,7 +30,7 @@ pub fn wrap_udp(packet: Vec<u8>, source: &SocketAddrV4, dest: &SocketAddrV4) ->
     udp_packet.set_payload(&packet);
     udp_packet.set_checksum(udp::ipv4_checksum(
         &UdpPacket::new(udp_packet.packet()).unwrap(),
-        &source.ip(),
+        source.ip(),
         dest.ip(),
     ));
 
@@ -32,10 +44,29 @@ pub fn write_varint(writer: &mut impl Write, mut value: i32) {
     }
     while value != 0 {
         buffer[0] = (value & 0b0111_1111) as u8;
-        value = (value >> 7) & (i32::max_value() >> 6);
+        value = (value >> 7) & (i32::MAX >> 6);
         if value != 0 {
             buffer[0] |= 0b1000_0000;
         }
         writer.write_all(&buffer).unwrap();
     }
 }
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("VarInt is longer than 5 bytes")]
+pub struct VarIntTooLong;
+
+// returns the value and how many bytes it took, or None when the data ends before the VarInt does
+pub fn read_varint(data: &[u8]) -> Result<Option<(i32, usize)>, VarIntTooLong> {
+    let mut value: u32 = 0;
+    for (i, byte) in data.iter().enumerate() {
+        if i >= 5 {
+            return Err(VarIntTooLong);
+        }
+        value |= ((byte & 0b0111_1111) as u32) << (7 * i);
+        if byte & 0b1000_0000 == 0 {
+            return Ok(Some((value as i32, i + 1)));
+        }
+    }
+    Ok(None)
+}
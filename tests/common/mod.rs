@@ -0,0 +1,551 @@
+// a simulated network of Minecraft servers (and worse) behind the in-memory backend
+// every test binary uses a different part of this
+#![allow(dead_code)]
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use badscan::{
+    backend::{
+        memory::{MemoryBackend, Peer},
+        Backend,
+    },
+    interface::MyInterface,
+    neighbours::Neighbours,
+    output::Response,
+    packet::{self, LinkType, TcpSegment, Transport},
+    protocols::{
+        slp::{MinecraftSlpProtocol, SlpState},
+        Protocol, UdpProtocol,
+    },
+    scanner::{Cooldown, Scanner, ScannerBuilder},
+    tcp::template::TcpTemplate,
+    utils,
+};
+use pnet::{
+    datalink::NetworkInterface,
+    ipnetwork::IpNetwork,
+    packet::{
+        ethernet::{EtherTypes, MutableEthernetPacket},
+        ip::{IpNextHeaderProtocol, IpNextHeaderProtocols},
+        ipv4::{self, MutableIpv4Packet},
+        tcp::TcpFlags,
+    },
+    util::MacAddr,
+};
+use serde_json::{json, Value};
+
+pub const SCANNER_MAC: MacAddr = MacAddr(0x02, 0, 0, 0, 0, 1);
+pub const GATEWAY_MAC: MacAddr = MacAddr(0x02, 0, 0, 0, 0, 2);
+pub const SCANNER_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const RAKNET_MAGIC: [u8; 16] = [
+    0x00, 0xff, 0xff, 0x00, 0xfe, 0xfe, 0xfe, 0xfe, 0xfd, 0xfd, 0xfd, 0xfd, 0x12, 0x34, 0x56, 0x78,
+];
+const QUERY_TOKEN: i32 = 9513307;
+
+pub fn interface() -> MyInterface {
+    MyInterface {
+        network_interface: NetworkInterface {
+            name: "mem0".to_string(),
+            description: String::new(),
+            index: 0,
+            mac: Some(SCANNER_MAC),
+            ips: vec![IpNetwork::V4("10.0.0.1/8".parse().unwrap())],
+            flags: 0,
+        },
+        gateway_mac: Some(GATEWAY_MAC),
+        gateway_ip: None,
+        neighbours: Neighbours::default(),
+    }
+}
+
+// like a TUN device, without any MACs
+pub fn tun_interface() -> MyInterface {
+    let mut interface = interface();
+    interface.network_interface.name = "tun0".to_string();
+    interface.network_interface.mac = None;
+    interface.gateway_mac = None;
+    interface
+}
+
+pub fn cooldown() -> Cooldown {
+    Cooldown {
+        min: Duration::from_millis(200),
+        idle: Duration::from_millis(200),
+        max: Duration::from_secs(5),
+        exchange_timeout: Duration::from_secs(1),
+    }
+}
+
+pub type Results = Arc<Mutex<Vec<(SocketAddrV4, Response)>>>;
+
+pub fn raknet(results: &Results) -> Protocol<SlpState> {
+    let results = results.clone();
+    Protocol::Udp(Arc::new(UdpProtocol::Raknet {
+        callback: Box::new(move |addr, response| {
+            results
+                .lock()
+                .unwrap()
+                .push((*addr, Response::Raknet(response)))
+        }),
+    }))
+}
+
+pub fn query(results: &Results, fullstat: bool) -> Protocol<SlpState> {
+    let results = results.clone();
+    Protocol::Udp(Arc::new(UdpProtocol::McQuery {
+        callback: Box::new(move |addr, response| {
+            results
+                .lock()
+                .unwrap()
+                .push((*addr, Response::Query(response)))
+        }),
+        fullstat,
+    }))
+}
+
+pub fn slp(results: &Results) -> Protocol<SlpState> {
+    let results = results.clone();
+    Protocol::Tcp(Arc::new(MinecraftSlpProtocol::new(Box::new(
+        move |addr, response| {
+            results
+                .lock()
+                .unwrap()
+                .push((*addr, Response::Slp(response)))
+        },
+    ))))
+}
+
+pub fn scanner(protocol: Protocol<SlpState>, backend: MemoryBackend) -> Scanner<SlpState> {
+    builder(protocol, backend).build().unwrap()
+}
+
+// for tests that need more than the defaults
+pub fn builder(protocol: Protocol<SlpState>, backend: MemoryBackend) -> ScannerBuilder<SlpState> {
+    let interface = match backend.link_type() {
+        LinkType::Ethernet => interface(),
+        LinkType::Ip => tun_interface(),
+    };
+    ScannerBuilder::new(protocol)
+        .interface(interface)
+        .backend(Arc::new(backend))
+        .seed(1234)
+        .cooldown(cooldown())
+}
+
+pub fn addrs(results: &Results) -> BTreeSet<SocketAddrV4> {
+    results
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(addr, _)| *addr)
+        .collect()
+}
+
+// an Ethernet frame from the gateway to the scanner
+pub fn frame(
+    protocol: IpNextHeaderProtocol,
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    transport: &[u8],
+) -> Vec<u8> {
+    let mut ip = vec![0; 20 + transport.len()];
+    let mut packet = MutableIpv4Packet::new(&mut ip).unwrap();
+    packet.set_version(4);
+    packet.set_header_length(5);
+    packet.set_total_length(20 + transport.len() as u16);
+    packet.set_ttl(64);
+    packet.set_next_level_protocol(protocol);
+    packet.set_source(source);
+    packet.set_destination(destination);
+    packet.set_payload(transport);
+    let checksum = ipv4::checksum(&packet.to_immutable());
+    packet.set_checksum(checksum);
+
+    let mut frame = vec![0; 14 + ip.len()];
+    let mut ethernet = MutableEthernetPacket::new(&mut frame).unwrap();
+    ethernet.set_source(GATEWAY_MAC);
+    ethernet.set_destination(SCANNER_MAC);
+    ethernet.set_ethertype(EtherTypes::Ipv4);
+    ethernet.set_payload(&ip);
+    frame
+}
+
+pub fn udp_frame(payload: Vec<u8>, source: &SocketAddrV4, destination: &SocketAddrV4) -> Vec<u8> {
+    let udp = utils::wrap_udp(payload, source, destination);
+    frame(
+        IpNextHeaderProtocols::Udp,
+        *source.ip(),
+        *destination.ip(),
+        &udp,
+    )
+}
+
+pub fn tcp_frame(
+    flags: u8,
+    source: &SocketAddrV4,
+    destination: &SocketAddrV4,
+    sequence: u32,
+    acknowledgement: u32,
+    payload: &[u8],
+) -> Vec<u8> {
+    let tcp = TcpTemplate::new(flags, 65535, vec![]).create(
+        source,
+        destination,
+        sequence,
+        acknowledgement,
+        payload,
+    );
+    frame(
+        IpNextHeaderProtocols::Tcp,
+        *source.ip(),
+        *destination.ip(),
+        &tcp,
+    )
+}
+
+pub enum Host {
+    // a Java server answering the server list ping, with its response split into segments of this size
+    Slp { status: Value, segment_size: usize },
+    // a Java server with enable-query on
+    Query { motd: String, players: Vec<String> },
+    // a Bedrock server
+    Raknet { motd: String },
+    // RSTs every connection, and ignores UDP
+    Closed,
+    // answers in the right protocol with the right cookie, but the rest is broken
+    Malformed,
+    // answers with a reply that doesn't carry our cookie
+    Spoofer,
+    // accepts connections, and then never says anything
+    Silent,
+}
+
+impl Host {
+    pub fn slp(description: &str) -> Self {
+        Self::Slp {
+            status: json!({
+                "version": {"name": "1.20.4", "protocol": 765},
+                "players": {"max": 20, "online": 1},
+                "description": {"text": description},
+            }),
+            segment_size: 1460,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    // frames the scanner sent
+    pub received: usize,
+    // frames lost on the way, in either direction
+    pub dropped: usize,
+    // the hosts whose (final) reply made it to the scanner
+    pub delivered: BTreeSet<SocketAddrV4>,
+}
+
+pub struct Network {
+    hosts: HashMap<SocketAddrV4, Host>,
+    // chance that a frame gets lost
+    loss: f64,
+    rng: u64,
+    link_type: LinkType,
+}
+
+impl Network {
+    pub fn new() -> Self {
+        Self {
+            hosts: HashMap::new(),
+            loss: 0.0,
+            rng: 0x9E3779B97F4A7C15,
+            link_type: LinkType::Ethernet,
+        }
+    }
+
+    pub fn host(mut self, addr: SocketAddrV4, host: Host) -> Self {
+        self.hosts.insert(addr, host);
+        self
+    }
+
+    // the same seed loses the same frames, as long as the scanner sends them in the same order
+    pub fn lossy(mut self, loss: f64, seed: u64) -> Self {
+        self.loss = loss;
+        self.rng = seed | 1;
+        self
+    }
+
+    // bare IP packets instead of Ethernet frames, like a TUN device
+    pub fn tun(mut self) -> Self {
+        self.link_type = LinkType::Ip;
+        self
+    }
+
+    // runs until the scanner is done, the report comes out of the handle
+    pub fn spawn(self) -> (MemoryBackend, JoinHandle<Report>) {
+        let (backend, peer) = MemoryBackend::with_link_type(self.link_type);
+        (backend, thread::spawn(move || self.run(peer)))
+    }
+
+    fn run(mut self, peer: Peer) -> Report {
+        let mut report = Report::default();
+        while let Some(frame) = peer.recv_timeout(Duration::from_secs(10)) {
+            report.received += 1;
+            if self.lose() {
+                report.dropped += 1;
+                continue;
+            }
+            for (reply, last) in self.handle(&frame) {
+                if self.lose() {
+                    report.dropped += 1;
+                    continue;
+                }
+                let reply = match self.link_type {
+                    LinkType::Ethernet => reply,
+                    LinkType::Ip => reply[14..].to_vec(),
+                };
+                if last {
+                    let segment = packet::parse_link(&reply, self.link_type).unwrap().unwrap();
+                    report.delivered.insert(segment.source);
+                }
+                if !peer.send(reply) {
+                    return report;
+                }
+            }
+        }
+        report
+    }
+
+    // xorshift, good enough to lose some frames
+    fn lose(&mut self) -> bool {
+        if self.loss == 0.0 {
+            return false;
+        }
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng as f64 / u64::MAX as f64) < self.loss
+    }
+
+    // the frames to send back, and whether they complete the exchange
+    fn handle(&self, frame: &[u8]) -> Vec<(Vec<u8>, bool)> {
+        if self.link_type == LinkType::Ethernet {
+            let ethernet = packet::parse_ethernet(frame).unwrap();
+            assert_eq!(ethernet.source, SCANNER_MAC);
+            assert_eq!(ethernet.destination, GATEWAY_MAC);
+        }
+        let segment = packet::parse_link(frame, self.link_type).unwrap().unwrap();
+        assert_eq!(*segment.source.ip(), SCANNER_IP);
+        let (server, client) = (segment.destination, segment.source);
+        let Some(host) = self.hosts.get(&server) else {
+            return vec![];
+        };
+
+        match segment.transport {
+            Transport::Udp { payload } => udp_reply(host, payload)
+                .map(|(reply, last)| vec![(udp_frame(reply, &server, &client), last)])
+                .unwrap_or_default(),
+            Transport::Tcp(tcp) => tcp_reply(host, &tcp)
+                .into_iter()
+                .map(|(flags, sequence, acknowledgement, payload, last)| {
+                    let frame =
+                        tcp_frame(flags, &server, &client, sequence, acknowledgement, &payload);
+                    (frame, last)
+                })
+                .collect(),
+        }
+    }
+}
+
+fn udp_reply(host: &Host, probe: &[u8]) -> Option<(Vec<u8>, bool)> {
+    match (host, probe) {
+        (Host::Raknet { motd }, [0x01, ping @ ..]) => Some((raknet_pong(motd, &ping[..8]), true)),
+        (Host::Malformed, [0x01, ping @ ..]) => {
+            let mut pong = raknet_pong("broken", &ping[..8]);
+            pong[17] ^= 0xFF; // the magic
+            Some((pong, true))
+        }
+        (Host::Spoofer, [0x01, ping @ ..]) => {
+            let mut timestamp = ping[..8].to_vec();
+            timestamp[7] ^= 1;
+            Some((raknet_pong("spoofed", &timestamp), true))
+        }
+
+        (Host::Query { motd, players }, [0xFE, 0xFD, intention, rest @ ..]) => {
+            let session = session(&rest[..4]);
+            match (intention, &rest[4..]) {
+                // handshake
+                (0x09, []) => {
+                    let mut challenge = vec![0x09];
+                    challenge.extend_from_slice(&session);
+                    challenge.extend_from_slice(format!("{QUERY_TOKEN}\0").as_bytes());
+                    Some((challenge, false))
+                }
+                (0x00, [token @ .., 0, 0, 0, 0]) if *token == QUERY_TOKEN.to_be_bytes() => {
+                    Some((full_stat(&session, motd, players), true))
+                }
+                (0x00, token) if *token == QUERY_TOKEN.to_be_bytes() => {
+                    Some((partial_stat(&session, motd, players.len()), true))
+                }
+                _ => None,
+            }
+        }
+        (Host::Malformed, [0xFE, 0xFD, 0x09, rest @ ..]) => {
+            let mut stat = vec![0x00];
+            stat.extend_from_slice(&session(&rest[..4]));
+            stat.extend_from_slice(b"cut off");
+            Some((stat, true))
+        }
+        (Host::Spoofer, [0xFE, 0xFD, 0x09, rest @ ..]) => {
+            let mut session = session(&rest[..4]);
+            session[0] ^= 1;
+            let mut challenge = vec![0x09];
+            challenge.extend_from_slice(&session);
+            challenge.extend_from_slice(b"1\0");
+            Some((challenge, true))
+        }
+        _ => None,
+    }
+}
+
+// servers only keep the lower 4 bits of every byte
+fn session(session: &[u8]) -> [u8; 4] {
+    let session: [u8; 4] = session.try_into().unwrap();
+    (u32::from_be_bytes(session) & 0x0F0F0F0F).to_be_bytes()
+}
+
+fn raknet_pong(motd: &str, timestamp: &[u8]) -> Vec<u8> {
+    let guid: u64 = 0xC0FFEE;
+    let server_id =
+        format!("MCPE;{motd};594;1.20.30;0;10;{guid};Bedrock level;Survival;1;19132;19133;");
+    let mut pong = vec![0x1c];
+    pong.extend_from_slice(timestamp);
+    pong.extend_from_slice(&guid.to_be_bytes());
+    pong.extend_from_slice(&RAKNET_MAGIC);
+    pong.extend_from_slice(&(server_id.len() as u16).to_be_bytes());
+    pong.extend_from_slice(server_id.as_bytes());
+    pong
+}
+
+fn partial_stat(session: &[u8], motd: &str, players: usize) -> Vec<u8> {
+    let mut stat = vec![0x00];
+    stat.extend_from_slice(session);
+    for field in [motd, "SMP", "world", &players.to_string(), "20"] {
+        stat.extend_from_slice(field.as_bytes());
+        stat.push(0);
+    }
+    stat.extend_from_slice(&25565u16.to_le_bytes());
+    stat.extend_from_slice(b"10.0.0.1\0");
+    stat
+}
+
+fn full_stat(session: &[u8], motd: &str, players: &[String]) -> Vec<u8> {
+    let mut stat = vec![0x00];
+    stat.extend_from_slice(session);
+    stat.extend_from_slice(b"splitnum\0\x80\0");
+    for (key, value) in [
+        ("hostname", motd),
+        ("gametype", "SMP"),
+        ("version", "1.20.4"),
+        ("numplayers", &players.len().to_string()),
+        ("maxplayers", "20"),
+    ] {
+        stat.extend_from_slice(key.as_bytes());
+        stat.push(0);
+        stat.extend_from_slice(value.as_bytes());
+        stat.push(0);
+    }
+    stat.push(0);
+    stat.extend_from_slice(b"\x01player_\0\0");
+    for player in players {
+        stat.extend_from_slice(player.as_bytes());
+        stat.push(0);
+    }
+    stat.push(0);
+    stat
+}
+
+// the sequence number the simulated servers start their side of a connection with
+const SERVER_ISN: u32 = 0x10000;
+
+// flags, sequence, acknowledgement, payload, and whether it completes the exchange
+type TcpReply = (u8, u32, u32, Vec<u8>, bool);
+
+fn tcp_reply(host: &Host, segment: &TcpSegment) -> Vec<TcpReply> {
+    let syn = segment.flags & TcpFlags::SYN != 0 && segment.flags & TcpFlags::ACK == 0;
+    let next = segment.sequence.wrapping_add(segment.payload.len() as u32);
+
+    match host {
+        // UDP only
+        Host::Query { .. } | Host::Raknet { .. } => vec![],
+        Host::Closed if syn => vec![(
+            TcpFlags::RST | TcpFlags::ACK,
+            0,
+            segment.sequence.wrapping_add(1),
+            vec![],
+            true,
+        )],
+        Host::Spoofer if syn => vec![(
+            TcpFlags::SYN | TcpFlags::ACK,
+            SERVER_ISN,
+            segment.sequence.wrapping_add(2),
+            vec![],
+            true,
+        )],
+        _ if syn => vec![(
+            TcpFlags::SYN | TcpFlags::ACK,
+            SERVER_ISN,
+            segment.sequence.wrapping_add(1),
+            vec![],
+            false,
+        )],
+        // the handshake and status request, everything arrives at once on this network
+        Host::Slp {
+            status,
+            segment_size,
+        } if !segment.payload.is_empty() => {
+            let response = status_response(&status.to_string());
+            let chunks = response.chunks(*segment_size).count();
+            response
+                .chunks(*segment_size)
+                .enumerate()
+                .map(|(i, chunk)| {
+                    let sequence = SERVER_ISN + 1 + (i * segment_size) as u32;
+                    let last = i == chunks - 1;
+                    (
+                        TcpFlags::PSH | TcpFlags::ACK,
+                        sequence,
+                        next,
+                        chunk.to_vec(),
+                        last,
+                    )
+                })
+                .collect()
+        }
+        Host::Malformed if !segment.payload.is_empty() => {
+            let response = status_response("{\"version\": ");
+            vec![(
+                TcpFlags::PSH | TcpFlags::ACK,
+                SERVER_ISN + 1,
+                next,
+                response,
+                true,
+            )]
+        }
+        _ => vec![],
+    }
+}
+
+fn status_response(json: &str) -> Vec<u8> {
+    let mut body = vec![0x00];
+    utils::write_varint(&mut body, json.len() as i32);
+    body.extend_from_slice(json.as_bytes());
+    let mut frame = vec![];
+    utils::write_varint(&mut frame, body.len() as i32);
+    frame.extend_from_slice(&body);
+    frame
+}
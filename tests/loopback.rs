@@ -0,0 +1,40 @@
+// the scanner over the in-memory backend, against a single simulated server
+
+mod common;
+
+use std::{net::SocketAddrV4, sync::Arc};
+
+use badscan::output::Response;
+
+use common::{Host, Network, Results};
+
+#[test]
+fn raknet_scan() {
+    let online: SocketAddrV4 = "10.0.1.1:19132".parse().unwrap();
+    let offline: SocketAddrV4 = "10.0.1.2:19132".parse().unwrap();
+    let (backend, network) = Network::new()
+        .host(
+            online,
+            Host::Raknet {
+                motd: "Dedicated Server".to_string(),
+            },
+        )
+        .spawn();
+
+    let results = Results::default();
+    let mut scanner = common::scanner(common::raknet(&results), backend);
+    let stats = Arc::clone(scanner.stats());
+    scanner.scan(online);
+    scanner.scan(offline);
+    scanner.finish();
+
+    let results = results.lock().unwrap();
+    assert_eq!(results.len(), 1);
+    let (addr, Response::Raknet(response)) = &results[0] else {
+        panic!("not a Raknet response");
+    };
+    assert_eq!(*addr, online);
+    assert_eq!(response.motd, "Dedicated Server");
+    assert_eq!(stats.total_replies(), 1);
+    assert_eq!(network.join().unwrap().received, 2);
+}
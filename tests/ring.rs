@@ -0,0 +1,83 @@
+// the packet ring backend on the loopback interface
+// opening the rings needs CAP_NET_RAW, run with `cargo test --test ring -- --ignored` as root
+#![cfg(target_os = "linux")]
+
+use std::{
+    collections::HashSet,
+    io,
+    net::SocketAddrV4,
+    time::{Duration, Instant},
+};
+
+use badscan::{
+    backend::{
+        ring::{RingBackend, RingConfig},
+        Backend,
+    },
+    utils::wrap_udp,
+};
+use pnet::datalink;
+
+// an Ethernet frame with a UDP datagram between two addresses on the loopback interface
+fn frame(source_port: u16) -> Vec<u8> {
+    let source = SocketAddrV4::new([127, 0, 0, 1].into(), source_port);
+    let dest = SocketAddrV4::new([127, 0, 0, 1].into(), 9);
+    let udp = wrap_udp(b"badscan ring test".to_vec(), &source, &dest);
+
+    let mut frame = vec![0; 12];
+    frame.extend_from_slice(&[0x08, 0x00]);
+    let total_length = (20 + udp.len()) as u16;
+    frame.extend_from_slice(&[0x45, 0]);
+    frame.extend_from_slice(&total_length.to_be_bytes());
+    frame.extend_from_slice(&[0, 1, 0x40, 0, 64, 17, 0, 0]);
+    frame.extend_from_slice(&source.ip().octets());
+    frame.extend_from_slice(&dest.ip().octets());
+    frame.extend_from_slice(&udp);
+    frame
+}
+
+#[test]
+#[ignore = "needs CAP_NET_RAW"]
+fn loopback_batch() {
+    let lo = datalink::interfaces()
+        .into_iter()
+        .find(|interface| interface.is_loopback())
+        .expect("no loopback interface");
+    let config = RingConfig {
+        enabled: true,
+        block_size: 64,
+        blocks: 4,
+        tx_frames: 64,
+        batch: 16,
+        receivers: 2,
+    };
+    let backend = RingBackend::new(lo, &config).unwrap();
+
+    // both receivers join the same fanout group, every frame goes to one of them
+    let mut receivers: Vec<_> = (0..backend.receivers())
+        .map(|_| backend.open_receiver().unwrap())
+        .collect();
+    assert_eq!(receivers.len(), 2);
+    let mut sender = backend.open_sender().unwrap();
+
+    // exactly one batch, the last frame makes the sender hand all of them to the kernel at once
+    let frames: Vec<_> = (0..config.batch as u16).map(|i| frame(40000 + i)).collect();
+    for frame in &frames {
+        sender.send(frame).unwrap();
+    }
+
+    let mut missing: HashSet<_> = frames.into_iter().collect();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !missing.is_empty() && Instant::now() < deadline {
+        for rx in &mut receivers {
+            match rx.recv() {
+                Ok(frame) => {
+                    missing.remove(frame);
+                }
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => {}
+                Err(err) => panic!("could not receive: {err}"),
+            }
+        }
+    }
+    assert!(missing.is_empty(), "{} frames never arrived", missing.len());
+}
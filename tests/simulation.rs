@@ -0,0 +1,313 @@
+// whole scans against a simulated network, with working servers as well as everything that can go wrong
+
+mod common;
+
+use std::{
+    collections::BTreeSet,
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::{atomic::Ordering, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use badscan::{
+    output::Response,
+    protocols::query::QueryResponse,
+    stats::Stats,
+    targets::{TargetIter, TargetSpace},
+};
+
+use common::{Host, Network, Results};
+
+fn addr(host: u8, port: u16) -> SocketAddrV4 {
+    SocketAddrV4::new(Ipv4Addr::new(10, 0, 1, host), port)
+}
+
+#[test]
+fn slp_scan() {
+    let (backend, network) = Network::new()
+        .host(addr(1, 25565), Host::slp("A Minecraft Server"))
+        .host(addr(2, 25565), Host::slp("Another one"))
+        // the response has to be put back together from several segments
+        .host(
+            addr(3, 25565),
+            Host::Slp {
+                status: serde_json::json!({
+                    "version": {"name": "Paper 1.20.4", "protocol": 765},
+                    "players": {"max": 100, "online": 42},
+                    "description": "x".repeat(500),
+                }),
+                segment_size: 64,
+            },
+        )
+        .host(addr(4, 25565), Host::Closed)
+        .host(addr(5, 25565), Host::Closed)
+        .host(addr(6, 25565), Host::Malformed)
+        .host(addr(7, 25565), Host::Spoofer)
+        .spawn();
+
+    let results = Results::default();
+    let mut scanner = common::scanner(common::slp(&results), backend);
+    let stats = Arc::clone(scanner.stats());
+    // 8 doesn't exist at all
+    for host in 1..=8 {
+        scanner.scan(addr(host, 25565));
+    }
+    scanner.finish();
+
+    assert_eq!(
+        common::addrs(&results),
+        BTreeSet::from([addr(1, 25565), addr(2, 25565), addr(3, 25565)])
+    );
+    for (server, response) in results.lock().unwrap().iter() {
+        let Response::Slp(response) = response else {
+            panic!("not an SLP response");
+        };
+        if server.ip().octets()[3] == 3 {
+            assert_eq!(response.players(), (Some(42), Some(100)));
+            assert_eq!(response.description().len(), 500);
+        } else {
+            assert_eq!(response.version(), Some("1.20.4"));
+        }
+    }
+    assert_eq!(Stats::get(&stats.rsts), 2);
+    assert_eq!(Stats::get(&stats.parse_failures), 1);
+    assert_eq!(Stats::get(&stats.cookie_failures), 1);
+    assert_eq!(stats.tcp_connections.load(Ordering::Relaxed), 0);
+    network.join().unwrap();
+}
+
+// a server that accepts the connection and then goes quiet shouldn't keep it open for the rest of the scan
+#[test]
+fn silent_servers_time_out() {
+    let (backend, network) = Network::new().host(addr(1, 25565), Host::Silent).spawn();
+
+    let results = Results::default();
+    let mut scanner = common::scanner(common::slp(&results), backend);
+    let stats = Arc::clone(scanner.stats());
+    scanner.scan(addr(1, 25565));
+
+    let connections = || stats.tcp_connections.load(Ordering::Relaxed);
+    let start = Instant::now();
+    while connections() == 0 && start.elapsed() < Duration::from_secs(2) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(connections(), 1);
+    // well before the cooldown would end the scan
+    while connections() == 1 && start.elapsed() < Duration::from_secs(4) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(connections(), 0);
+
+    scanner.finish();
+    assert!(results.lock().unwrap().is_empty());
+    network.join().unwrap();
+}
+
+#[test]
+fn slp_scan_over_tun() {
+    let (backend, network) = Network::new()
+        .tun()
+        .host(addr(1, 25565), Host::slp("A Minecraft Server"))
+        .host(addr(2, 25565), Host::Closed)
+        .host(addr(3, 25565), Host::Spoofer)
+        .spawn();
+
+    let results = Results::default();
+    let mut scanner = common::scanner(common::slp(&results), backend);
+    let stats = Arc::clone(scanner.stats());
+    for host in 1..=3 {
+        scanner.scan(addr(host, 25565));
+    }
+    scanner.finish();
+
+    assert_eq!(common::addrs(&results), BTreeSet::from([addr(1, 25565)]));
+    assert_eq!(Stats::get(&stats.rsts), 1);
+    assert_eq!(Stats::get(&stats.cookie_failures), 1);
+    network.join().unwrap();
+}
+
+fn query_network() -> Network {
+    Network::new()
+        .host(
+            addr(1, 25565),
+            Host::Query {
+                motd: "A Minecraft Server".to_string(),
+                players: vec!["Notch".to_string(), "jeb_".to_string()],
+            },
+        )
+        .host(
+            addr(2, 25565),
+            Host::Query {
+                motd: "Empty".to_string(),
+                players: vec![],
+            },
+        )
+        // doesn't do UDP
+        .host(addr(3, 25565), Host::Closed)
+        .host(addr(4, 25565), Host::Malformed)
+        .host(addr(5, 25565), Host::Spoofer)
+}
+
+fn query_scan(fullstat: bool) -> (Results, Arc<Stats>) {
+    let (backend, network) = query_network().spawn();
+    let results = Results::default();
+    let mut scanner = common::scanner(common::query(&results, fullstat), backend);
+    let stats = Arc::clone(scanner.stats());
+    for host in 1..=5 {
+        scanner.scan(addr(host, 25565));
+    }
+    scanner.finish();
+    network.join().unwrap();
+
+    assert_eq!(
+        common::addrs(&results),
+        BTreeSet::from([addr(1, 25565), addr(2, 25565)])
+    );
+    assert_eq!(Stats::get(&stats.parse_failures), 1);
+    assert_eq!(Stats::get(&stats.cookie_failures), 1);
+    (results, stats)
+}
+
+#[test]
+fn query_partial_scan() {
+    let (results, _) = query_scan(false);
+    for (server, response) in results.lock().unwrap().iter() {
+        let Response::Query(QueryResponse::Partial {
+            motd, numplayers, ..
+        }) = response
+        else {
+            panic!("not a partial stat");
+        };
+        if *server == addr(1, 25565) {
+            assert_eq!(motd, "A Minecraft Server");
+            assert_eq!(numplayers, "2");
+        }
+    }
+}
+
+#[test]
+fn query_full_scan() {
+    let (results, _) = query_scan(true);
+    for (server, response) in results.lock().unwrap().iter() {
+        let Response::Query(QueryResponse::Full {
+            kv_section,
+            players,
+        }) = response
+        else {
+            panic!("not a full stat");
+        };
+        if *server == addr(1, 25565) {
+            assert_eq!(kv_section["hostname"], "A Minecraft Server");
+            assert_eq!(players, &["Notch", "jeb_"]);
+        } else {
+            assert!(players.is_empty());
+        }
+    }
+}
+
+#[test]
+fn raknet_lossy_scan() {
+    let mut network = Network::new().lossy(0.3, 42);
+    for host in 1..=100 {
+        network = network.host(
+            addr(host, 19132),
+            Host::Raknet {
+                motd: format!("Server {host}"),
+            },
+        );
+    }
+    let (backend, network) = network.spawn();
+
+    let results = Results::default();
+    let mut scanner = common::scanner(common::raknet(&results), backend);
+    for host in 1..=100 {
+        scanner.scan(addr(host, 19132));
+    }
+    scanner.finish();
+    let report = network.join().unwrap();
+
+    // every pong that made it through is a result, and nothing else is
+    assert!(report.dropped > 0);
+    assert!(!report.delivered.is_empty());
+    assert_eq!(common::addrs(&results), report.delivered);
+    assert_eq!(results.lock().unwrap().len(), report.delivered.len());
+}
+
+#[test]
+fn parallel_senders() {
+    let mut network = Network::new();
+    for host in 1..=50 {
+        network = network.host(
+            addr(host, 25565),
+            Host::Query {
+                motd: format!("Server {host}"),
+                players: vec![],
+            },
+        );
+    }
+    let (backend, network) = network.spawn();
+
+    // a tiny answer queue, so the receivers have to wait for the send thread of the answers
+    let results = Results::default();
+    let scanner = common::builder(common::query(&results, false), backend)
+        .senders(4)
+        .queue_size(1)
+        .build()
+        .unwrap();
+    let stats = Arc::clone(scanner.stats());
+    let space = TargetSpace::new(vec!["10.0.1.0/26".parse().unwrap()], vec![25565]).unwrap();
+    let position = scanner
+        .scan_all(TargetIter::new(space, 1234))
+        .unwrap()
+        .join();
+    scanner.finish();
+    let report = network.join().unwrap();
+
+    // every target got exactly one probe, and every server a stat request after its challenge
+    assert_eq!(position, 64);
+    assert_eq!(Stats::get(&stats.probes_sent), 64);
+    assert_eq!(report.received, 64 + 50);
+    assert_eq!(
+        common::addrs(&results),
+        (1..=50).map(|host| addr(host, 25565)).collect()
+    );
+}
+
+#[test]
+fn slp_scan_with_workers() {
+    let mut network = Network::new();
+    for host in 1..=30 {
+        network = network.host(
+            addr(host, 25565),
+            Host::Slp {
+                status: serde_json::json!({
+                    "version": {"name": "1.20.4", "protocol": 765},
+                    "description": format!("Server {host} {}", "x".repeat(300)),
+                }),
+                // every connection needs several segments, which have to arrive at its worker in order
+                segment_size: 64,
+            },
+        );
+    }
+    let (backend, network) = network.spawn();
+
+    let results = Results::default();
+    let mut scanner = common::builder(common::slp(&results), backend)
+        .workers(4)
+        .build()
+        .unwrap();
+    let stats = Arc::clone(scanner.stats());
+    for host in 1..=30 {
+        scanner.scan(addr(host, 25565));
+    }
+    scanner.finish();
+    network.join().unwrap();
+
+    assert_eq!(
+        common::addrs(&results),
+        (1..=30).map(|host| addr(host, 25565)).collect()
+    );
+    assert_eq!(Stats::get(&stats.parse_failures), 0);
+    assert_eq!(stats.tcp_connections.load(Ordering::Relaxed), 0);
+}
@@ -0,0 +1,150 @@
+// the AF_XDP backend in copy mode on a veth pair
+// creating the pair and attaching the program needs CAP_NET_ADMIN and CAP_BPF,
+// run with `cargo test --features xdp --test xdp -- --ignored` as root
+#![cfg(all(feature = "xdp", target_os = "linux"))]
+
+use std::{
+    io,
+    net::SocketAddrV4,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use badscan::{
+    backend::{
+        xdp::{XdpBackend, XdpConfig},
+        Backend,
+    },
+    config::PortRange,
+    utils::wrap_udp,
+};
+use pnet::datalink::{self, Channel, NetworkInterface};
+
+const SCANNER: &str = "badscan-xdp0";
+const PEER: &str = "badscan-xdp1";
+
+// deletes the pair again, even when the test fails
+struct Veth;
+
+impl Veth {
+    fn create() -> Self {
+        let _ = ip(&["link", "del", SCANNER]);
+        ip(&["link", "add", SCANNER, "type", "veth", "peer", "name", PEER]).unwrap();
+        let veth = Veth;
+        ip(&["link", "set", SCANNER, "up"]).unwrap();
+        ip(&["link", "set", PEER, "up"]).unwrap();
+        veth
+    }
+}
+
+impl Drop for Veth {
+    fn drop(&mut self) {
+        let _ = ip(&["link", "del", SCANNER]);
+    }
+}
+
+fn ip(args: &[&str]) -> Result<(), String> {
+    let output = Command::new("ip")
+        .args(args)
+        .output()
+        .map_err(|err| err.to_string())?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).into_owned());
+    }
+    Ok(())
+}
+
+fn interface(name: &str) -> NetworkInterface {
+    datalink::interfaces()
+        .into_iter()
+        .find(|interface| interface.name == name)
+        .unwrap_or_else(|| panic!("no interface called {name}"))
+}
+
+// an Ethernet frame with a UDP datagram from the peer to the scanner's end
+fn frame(to: &NetworkInterface, from: &NetworkInterface, dest_port: u16) -> Vec<u8> {
+    let source = SocketAddrV4::new([10, 0, 0, 2].into(), 25565);
+    let dest = SocketAddrV4::new([10, 0, 0, 1].into(), dest_port);
+    let udp = wrap_udp(b"badscan xdp test".to_vec(), &source, &dest);
+
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&to.mac.unwrap().octets());
+    frame.extend_from_slice(&from.mac.unwrap().octets());
+    frame.extend_from_slice(&[0x08, 0x00]);
+    let total_length = (20 + udp.len()) as u16;
+    frame.extend_from_slice(&[0x45, 0]);
+    frame.extend_from_slice(&total_length.to_be_bytes());
+    frame.extend_from_slice(&[0, 1, 0x40, 0, 64, 17, 0, 0]);
+    frame.extend_from_slice(&source.ip().octets());
+    frame.extend_from_slice(&dest.ip().octets());
+    frame.extend_from_slice(&udp);
+    frame
+}
+
+#[test]
+#[ignore = "needs CAP_NET_ADMIN"]
+fn veth_copy_mode() {
+    let _veth = Veth::create();
+    let scanner = interface(SCANNER);
+    let peer = interface(PEER);
+
+    let config = XdpConfig {
+        enabled: true,
+        zero_copy: false,
+        ..Default::default()
+    };
+    let source_ports = PortRange {
+        start: 61000,
+        end: 61009,
+    };
+    let backend = XdpBackend::new(&scanner, &config, source_ports).unwrap();
+    assert!(!backend.zero_copy());
+    let (mut tx, mut rx) = backend.open().unwrap();
+
+    let (mut peer_tx, mut peer_rx) = match datalink::channel(
+        &peer,
+        datalink::Config {
+            read_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        },
+    )
+    .unwrap()
+    {
+        Channel::Ethernet(tx, rx) => (tx, rx),
+        _ => unreachable!(),
+    };
+
+    // a reply to one of our source ports ends up at the socket, others are left to the kernel
+    let reply = frame(&scanner, &peer, 61005);
+    let other = frame(&scanner, &peer, 8080);
+    peer_tx.send_to(&other, None).unwrap().unwrap();
+    peer_tx.send_to(&reply, None).unwrap().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        assert!(Instant::now() < deadline, "the reply never arrived");
+        match rx.recv() {
+            Ok(frame) => {
+                assert_eq!(frame, reply.as_slice());
+                break;
+            }
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => {}
+            Err(err) => panic!("could not receive: {err}"),
+        }
+    }
+
+    // and what we send comes out of the other end
+    let probe = frame(&peer, &scanner, 9);
+    for _ in 0..config.batch {
+        tx.send(&probe).unwrap();
+    }
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        assert!(Instant::now() < deadline, "the probe never arrived");
+        match peer_rx.next() {
+            Ok(frame) if frame == probe.as_slice() => break,
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => {}
+            Err(err) => panic!("could not receive: {err}"),
+        }
+    }
+}